@@ -4,56 +4,573 @@
 // Now: correct parsing, real dequant preview, no more sins against little-endian
 // The machines dream in -1 0 1. We merely translate.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(Parser)]
 #[command(name = "ternary-tools")]
 #[command(version = "1.2-gguf-ascended")]
-#[command(about = "The file(1) of the ternary age — now fully correct and ready for the singularity")]
+#[command(infer_subcommands = true)]
+#[command(
+    about = "The file(1) of the ternary age — now fully correct and ready for the singularity"
+)]
 struct Cli {
+    /// Keep stderr for errors only (no REPL prompt)
+    #[arg(long, global = true)]
+    quiet: bool,
+    /// Also note on stderr what a command is reading and writing
+    #[arg(long, global = true, conflicts_with = "quiet")]
+    verbose: bool,
+    /// Write results to a file instead of stdout; errors stay on stderr
+    #[arg(long = "output", global = true, value_name = "FILE")]
+    output_file: Option<String>,
+    /// Indent `--output-format json` output, one item per line
+    #[arg(long, global = true)]
+    pretty: bool,
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
+    /// Inspect, check and rewrite GGUF model files
+    #[command(alias = "g", infer_subcommands = true)]
     Gguf {
+        #[arg(long, global = true)]
+        lossy: bool,
+        /// Memory-map the file instead of reading it (needs the `mmap`
+        /// build feature)
+        #[arg(long, global = true)]
+        mmap: bool,
         #[command(subcommand)]
         op: GgufOp,
     },
-    #[command(hide = true)]
-    Calc { expr: Option<String> },
+    /// Evaluate base-3 integer expressions
+    ///
+    /// Numbers are read in base 3 (see --input-base). From loosest to
+    /// tightest: `|` (tritwise OR), `^` (XOR), `&` (AND), `+ -`, `* /`, then
+    /// unary `-` and `~` (flip every trit) and parentheses. `name = expr`
+    /// assigns, `;` separates statements, `$` is the previous result and
+    /// `$n` the n-th; `fact(n)`, `gcd(a, b, …)` and `lcm(a, b, …)` are the
+    /// functions. With no expression, lines are read from --input or stdin.
+    #[command(alias = "c")]
+    Calc {
+        expr: Option<String>,
+        #[arg(long)]
+        decimal: bool,
+        #[arg(long)]
+        group: bool,
+        #[arg(long)]
+        show_both_ternary: bool,
+        /// Also count each digit of the result (each trit, balanced too
+        /// with --show-both-ternary)
+        #[arg(long)]
+        count_trits: bool,
+        #[arg(long)]
+        explain: bool,
+        #[arg(long, conflicts_with = "expr")]
+        repl: bool,
+        #[arg(long, value_enum, default_value_t = DivMode::Trunc)]
+        div_mode: DivMode,
+        /// Read the expressions from a file (`-` for stdin, the default)
+        #[arg(long, conflicts_with_all = ["expr", "repl"])]
+        input: Option<String>,
+        /// Predefine fixed_one, pi_approx, e_approx, phi_approx and
+        /// sqrt2_approx, in fixed point with twelve trits after the point
+        /// (fixed_one is 3^12)
+        #[arg(long)]
+        allow_constants: bool,
+        /// Stop at the first bad line of a file or stdin instead of going on
+        #[arg(long, conflicts_with_all = ["expr", "repl"])]
+        strict: bool,
+        /// Give up on an expression (one line) after this many milliseconds
+        #[arg(long)]
+        timeout_ms: Option<u64>,
+        /// Read `2(11)`, `(1)2` and `(1)(2)` as multiplications
+        #[arg(long)]
+        implicit_mul: bool,
+        /// Work out + - * on balanced trits with a full adder, not with i64
+        #[arg(long)]
+        native_ternary: bool,
+        /// Base of the numbers typed in; results are still printed in ternary
+        #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(2..=10))]
+        input_base: u32,
+        /// Work modulo N (decimal), with every result in 0..N
+        #[arg(
+            long = "mod",
+            value_name = "N",
+            conflicts_with = "native_ternary",
+            value_parser = clap::value_parser!(i64).range(2..)
+        )]
+        modulus: Option<i64>,
+        /// Results as a JSON array, one object per result
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text, conflicts_with_all = ["repl", "explain"])]
+        output_format: OutputFormat,
+    },
+    /// Base-3 checksum of any file's bytes (`-` for stdin)
+    Checksum {
+        file: String,
+        #[arg(long, value_enum, default_value_t = ChecksumAlgo::Ternary)]
+        algo: ChecksumAlgo,
+    },
+    /// Convert numbers between bases, ternary by default
+    Convert {
+        #[arg(
+            allow_hyphen_values = true,
+            required_unless_present_any = [
+                "batch",
+                "gray",
+                "to_gray",
+                "from_gray",
+                "hamming",
+                "validate",
+                "ternary_sci",
+                "cf",
+                "from_rle"
+            ]
+        )]
+        value: Option<String>,
+        #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(2..=36))]
+        from_base: u32,
+        #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(2..=36))]
+        to_base: u32,
+        #[arg(long)]
+        group: bool,
+        #[arg(long, conflicts_with = "value")]
+        batch: bool,
+        #[arg(long, requires = "batch")]
+        input: Option<String>,
+        #[arg(long, requires = "batch")]
+        strict: bool,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output_format: OutputFormat,
+        /// Convert exactly at any length instead of stopping at 64 bits
+        #[arg(long)]
+        big: bool,
+        /// Print the n-digit reflected ternary Gray code sequence
+        #[arg(
+            long,
+            value_parser = clap::value_parser!(u32).range(1..=12),
+            conflicts_with_all = ["value", "batch", "to_gray", "from_gray"]
+        )]
+        gray: Option<u32>,
+        /// Gray codeword of a value given in --from-base
+        #[arg(
+            long,
+            allow_hyphen_values = true,
+            conflicts_with_all = ["value", "batch", "from_gray"]
+        )]
+        to_gray: Option<String>,
+        /// Value of a ternary Gray codeword, printed in --to-base
+        #[arg(long, conflicts_with_all = ["value", "batch"])]
+        from_gray: Option<String>,
+        /// Count the trit positions where two ternary strings differ
+        #[arg(
+            long,
+            num_args = 2,
+            value_names = ["A", "B"],
+            conflicts_with_all = ["value", "batch", "gray", "to_gray", "from_gray"]
+        )]
+        hamming: Option<Vec<String>>,
+        /// Exit 0 if the string is canonical ternary, 1 with the reason if not
+        #[arg(
+            long,
+            allow_hyphen_values = true,
+            conflicts_with_all = ["value", "batch", "gray", "to_gray", "from_gray", "hamming"]
+        )]
+        validate: Option<String>,
+        /// Let --validate accept leading zeros (`0012`)
+        #[arg(long, requires = "validate")]
+        allow_leading_zeros: bool,
+        /// Ternary scientific notation `[-]trits[.trits][e[+|-]decimal]`: a
+        /// base-3 mantissa times 3 to a decimal power, `1.2e2` = 1.2 (base 3)
+        /// × 3^2 = 120
+        #[arg(
+            long,
+            allow_hyphen_values = true,
+            conflicts_with_all = [
+                "value",
+                "batch",
+                "gray",
+                "to_gray",
+                "from_gray",
+                "hamming",
+                "validate"
+            ]
+        )]
+        ternary_sci: Option<String>,
+        /// Best rational approximation of a decimal by continued fractions:
+        /// the terms, every convergent p/q and the last one in ternary, its
+        /// repeating trits in parentheses
+        #[arg(
+            long = "cf",
+            allow_hyphen_values = true,
+            conflicts_with_all = [
+                "value",
+                "batch",
+                "gray",
+                "to_gray",
+                "from_gray",
+                "hamming",
+                "validate",
+                "ternary_sci"
+            ]
+        )]
+        cf: Option<String>,
+        /// How many partial quotients --cf expands, the integer part included
+        #[arg(
+            long,
+            default_value_t = 8,
+            value_parser = clap::value_parser!(u32).range(1..=60),
+            requires = "cf"
+        )]
+        terms: u32,
+        /// Write the ternary result run-length encoded, `00011` = `3(0)2(1)`
+        #[arg(long, conflicts_with_all = ["batch", "group", "glyphs", "from_glyphs"])]
+        rle: bool,
+        /// Expand a run-length encoding back to its trits, as written
+        #[arg(
+            long,
+            allow_hyphen_values = true,
+            conflicts_with_all = [
+                "value",
+                "batch",
+                "gray",
+                "to_gray",
+                "from_gray",
+                "hamming",
+                "validate",
+                "ternary_sci",
+                "cf"
+            ]
+        )]
+        from_rle: Option<String>,
+        /// Write balanced ternary with these glyphs for +1, 0, -1: `--glyphs=+0-`,
+        /// or `10T` for a bare `--glyphs`
+        #[arg(
+            long,
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "10T",
+            allow_hyphen_values = true,
+            conflicts_with_all = ["batch", "big", "to_base", "group"]
+        )]
+        glyphs: Option<String>,
+        /// Read the value as balanced ternary in these glyphs for +1, 0, -1 (`--from-glyphs=+0-`)
+        #[arg(
+            long,
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "10T",
+            allow_hyphen_values = true,
+            conflicts_with_all = ["batch", "big", "from_base"]
+        )]
+        from_glyphs: Option<String>,
+    },
+    /// Run the built-in round-trip checks; exits 1 if any fails
+    Selftest,
+    /// Reproducible random ternary numbers, one per line
+    Random {
+        /// Width in trits; every number is padded to it
+        #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..=39))]
+        digits: u32,
+        #[arg(long, default_value_t = 1)]
+        count: u64,
+        /// Same seed, same numbers (default: from the clock)
+        #[arg(long)]
+        seed: Option<u64>,
+        /// Balanced trits (- 0 +) centred on zero instead of 0..3^digits
+        #[arg(long)]
+        balanced: bool,
+        /// Largest magnitude, in decimal
+        #[arg(long)]
+        max: Option<u64>,
+    },
+    /// Balanced-ternary floating point
+    ///
+    /// An encoding is `EEEE:MMMMMMMM`, exponent then mantissa trits (- 0 +),
+    /// most significant first. The value is the mantissa read as
+    /// t0.t1t2… times 3^exponent; there is no sign trit, and t0 ≠ 0 unless
+    /// the value is 0 or below the smallest exponent.
+    Tfloat {
+        #[command(subcommand)]
+        op: TfloatOp,
+    },
+}
+
+#[derive(Subcommand)]
+enum TfloatOp {
+    /// Encode a number and show how far the round trip lands from it
+    Encode {
+        #[arg(allow_hyphen_values = true)]
+        value: f64,
+        /// Mantissa width in trits
+        #[arg(long, default_value_t = 12, value_parser = clap::value_parser!(u32).range(1..=39))]
+        mantissa: u32,
+        /// Exponent width in trits
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..=8))]
+        exp: u32,
+    },
+    /// Decode `exponent:mantissa` trits, e.g. `000+:+00-+-0-+-++`
+    Decode { code: String },
 }
 
 #[derive(Subcommand)]
 enum GgufOp {
+    /// One screen: architecture, parameters, tensors and checksum
     Summary {
         file: String,
         #[arg(long, default_value_t = true)]
         ternary: bool,
     },
+    /// Every metadata pair and the tensor table
     Info {
         file: String,
         #[arg(long)]
         ternary: bool,
+        #[arg(long, default_value_t = 16)]
+        max_values: usize,
+        #[arg(long)]
+        full: bool,
+        /// Print one field alone: magic, version, n_tensors, n_kv or meta:<key>
+        #[arg(long)]
+        only: Option<String>,
+        /// `--only FIELD --ternary` in one flag
+        #[arg(long, value_name = "FIELD", conflicts_with = "only")]
+        only_ternary: Option<String>,
+        /// Re-read the header every --interval-ms until interrupted, for a
+        /// file that is still being written
+        #[arg(long, conflicts_with_all = ["only", "only_ternary"])]
+        watch: bool,
+        #[arg(long, default_value_t = 500, requires = "watch")]
+        interval_ms: u64,
+        /// On a damaged file, print what was read before the error
+        #[arg(long, conflicts_with = "watch")]
+        lenient: bool,
     },
+    /// Decoded values of one tensor, or their statistics
     Show {
         file: String,
         tensor: String,
-        #[arg(long, default_value_t = 16)]
+        /// How many values to show
+        #[arg(long, alias = "limit", default_value_t = 16)]
         head: usize,
+        /// Index of the first value; a multiple of 32 for Q8_0 and Q4_0,
+        /// whose values come in blocks
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
         #[arg(long)]
         raw: bool,
         #[arg(long)]
         ternary: bool,
+        /// Min, max, mean and zero fraction over the whole tensor
+        #[arg(long, conflicts_with_all = ["offset", "raw"])]
+        stats: bool,
+    },
+    /// Hex, ASCII and ternary dump of the first bytes of one tensor
+    Hexdump {
+        file: String,
+        tensor: String,
+        /// How many bytes; never more than the tensor has
+        #[arg(long, default_value_t = 64)]
+        bytes: u64,
     },
+    /// Check the tensor layout and required metadata, and print the checksum
     Validate {
         file: String,
+        /// Also print a checksum per metadata key and a root over them
+        #[arg(long)]
+        tree: bool,
+        /// Require the metadata a llama-style runtime loads:
+        /// general.architecture (STRING) and the UINT32s {arch}.context_length,
+        /// embedding_length, feed_forward_length, block_count and
+        /// attention.head_count
+        #[arg(long)]
+        require_arch: bool,
+        /// Require one more key, as `key` or `key:TYPE`; `{arch}` in the key
+        /// stands for general.architecture
+        #[arg(long, value_name = "KEY[:TYPE]")]
+        require: Vec<String>,
+    },
+    /// Checksum of every tensor's raw data, and a root over them
+    Checksum {
+        file: String,
+        #[arg(long, value_enum, default_value_t = ChecksumAlgo::Ternary)]
+        algo: ChecksumAlgo,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output_format: OutputFormat,
+    },
+    /// The tensor table alone, sorted and filtered
+    Tensors {
+        file: String,
+        #[arg(long, value_enum, default_value_t = TensorSort::File)]
+        sort: TensorSort,
+        #[arg(long)]
+        filter: Option<String>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output_format: OutputFormat,
+    },
+    /// How many tensors use each type
+    Types {
+        file: String,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output_format: OutputFormat,
+    },
+    /// The tokenizer's tokens (tokenizer.ggml.tokens), one per line
+    Vocab {
+        file: String,
+        /// Only tokens a..b (end excluded); either side may be left out
+        #[arg(long)]
+        range: Option<String>,
+    },
+    /// Metadata and tensors that differ between two files
+    Diff {
+        a: String,
+        b: String,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output_format: OutputFormat,
+    },
+    /// Print the SafeTensors JSON header this file's tensors would have
+    ExportHeader {
+        file: String,
+        /// Write the header to this file instead, leaving the results of
+        /// the global --output alone
+        #[arg(long, value_name = "FILE")]
+        out: Option<String>,
+    },
+    /// How far one tensor drifted between two files: max and mean absolute
+    /// error and cosine similarity
+    CompareData {
+        original: String,
+        quantized: String,
+        #[arg(long)]
+        tensor: String,
+    },
+    /// Set one metadata pair
+    Set {
+        key: String,
+        #[arg(allow_hyphen_values = true)]
+        value: String,
+        input: String,
+        output: String,
+        #[arg(long = "type", value_enum)]
+        ty: Option<GgufValueType>,
+        /// Print what would change without writing the output
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Set many metadata pairs from a JSON template
+    Merge {
+        template: String,
+        input: String,
+        output: String,
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Rename one tensor
+    Rename {
+        old: String,
+        new: String,
+        input: String,
+        output: String,
+        #[arg(long)]
+        dry_run: bool,
     },
+    /// Rewrite the tensor infos and their data in another order
+    Reorder {
+        input: String,
+        output: String,
+        #[arg(long, value_enum)]
+        by: TensorSort,
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Remove metadata keys (the tokenizer arrays, say); tensors stay as they are
+    Strip {
+        input: String,
+        output: String,
+        /// Key to remove, as `meta:<key>` or plain `<key>`; repeat for more
+        #[arg(long = "drop", value_name = "KEY", required = true)]
+        drop: Vec<String>,
+        /// Skip --drop keys the file does not have instead of failing
+        #[arg(long)]
+        ignore_missing: bool,
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Rewrite the tensor data so every offset is a multiple of --alignment
+    Align {
+        input: String,
+        output: String,
+        #[arg(long, default_value_t = 32)]
+        alignment: u32,
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum TensorSort {
+    /// Order of the tensor info table
+    File,
+    /// Largest first; tensors of unknown type last
+    Size,
+    Name,
+    Offset,
+}
+
+/// How calc's `/` rounds a quotient that is not exact.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum DivMode {
+    /// Toward zero, like Rust and C: -7 / 2 = -3
+    Trunc,
+    /// Toward negative infinity, like Python: -7 / 2 = -4
+    Floor,
+    /// So the remainder is never negative: -7 / 2 = -4, 7 / -2 = -3
+    Euclid,
+}
+
+/// Checksum algorithms for `checksum --algo`; see the implementations
+/// of `TernaryChecksumAlgo` for the exact definitions.
+#[derive(Clone, Copy, ValueEnum)]
+enum ChecksumAlgo {
+    /// The multiply-by-3 hash `validate` and `summary` print
+    Ternary,
+    /// Sum of the trits of every byte, mod 3^9
+    DigitSum,
+    /// 12-trit CRC over GF(3)
+    Crc,
+    /// Fletcher-style pair of sums, mod 3^9 each
+    Fletcher,
+}
+
+/// Settable metadata value types; discriminants are the GGUF type ids.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum GgufValueType {
+    Uint8 = 0,
+    Int8 = 1,
+    Uint16 = 2,
+    Int16 = 3,
+    Uint32 = 4,
+    Int32 = 5,
+    Float32 = 6,
+    Bool = 7,
+    String = 8,
+    Uint64 = 10,
+    Int64 = 11,
+    Float64 = 12,
 }
 
 /*=====================================================================
@@ -68,7 +585,7 @@ struct GgufHeader {
     n_metadata_kv: u64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct GgufTensorInfo {
     name: String,
     dims: Vec<u64>,
@@ -76,6 +593,16 @@ struct GgufTensorInfo {
     offset: u64,
 }
 
+/// A metadata pair as stored on disk. `raw` is the encoded value, so pairs
+/// we don't touch are written back byte-for-byte.
+#[derive(Debug, Clone)]
+struct GgufKv {
+    key: String,
+    ty: u32,
+    value: GgufValue,
+    raw: Vec<u8>,
+}
+
 #[derive(Debug, Clone)]
 enum GgufValue {
     Uint8(u8),
@@ -134,26 +661,377 @@ impl std::fmt::Display for GgufValue {
     }
 }
 
+/*=====================================================================
+  Output
+=====================================================================*/
+
+/// `--output`: the file results go to instead of stdout. Set once in main,
+/// before anything is printed.
+static OUTPUT_FILE: OnceLock<File> = OnceLock::new();
+
+/// Results, as opposed to diagnostics: stdout unless `--output` was given.
+struct Output;
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match OUTPUT_FILE.get() {
+            Some(mut f) => f.write(buf),
+            None => io::stdout().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match OUTPUT_FILE.get() {
+            Some(mut f) => f.flush(),
+            None => io::stdout().flush(),
+        }
+    }
+}
+
+/// What stderr carries besides errors: nothing with `--quiet`, the REPL
+/// prompt by default, and notes on what a command is doing with
+/// `--verbose`. Set once in main like OUTPUT_FILE.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+/// The one check everything informational on stderr goes through.
+fn stderr_shows(level: Verbosity) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level as u8
+}
+
+/// A `--verbose` note on stderr; errors go through `exit_with` instead.
+macro_rules! note {
+    ($($arg:tt)*) => {
+        if stderr_shows(Verbosity::Verbose) {
+            eprintln!("ternary-tools: {}", format_args!($($arg)*));
+        }
+    };
+}
+
+/// `print!` for results, so `--output` catches them.
+macro_rules! out {
+    ($($arg:tt)*) => {
+        Output
+            .write_fmt(format_args!($($arg)*))
+            .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("output: {}", e)))
+    };
+}
+
+/// `println!` for results, so `--output` catches them.
+macro_rules! outln {
+    () => {
+        out!("\n")
+    };
+    ($($arg:tt)*) => {
+        out!("{}\n", format_args!($($arg)*))
+    };
+}
+
+/// `--pretty`, set once in main like OUTPUT_FILE.
+static PRETTY_JSON: AtomicBool = AtomicBool::new(false);
+
+/// Every `--output-format json` result goes out through here.
+fn print_json(value: &Json) {
+    outln!("{}", value.to_text(PRETTY_JSON.load(Ordering::Relaxed)));
+}
+
+/// A `Json::Object` from `"key": value` pairs; each value goes through
+/// `Json::from`, so strings, integers and `Option`s can be given as is.
+macro_rules! json_object {
+    ($($key:literal: $value:expr),* $(,)?) => {
+        Json::Object(vec![$(($key.to_string(), Json::from($value))),*])
+    };
+}
+
 /*=====================================================================
   Main
 =====================================================================*/
 
 fn main() {
     let cli = Cli::parse();
+    if let Some(path) = &cli.output_file {
+        let file = File::create(path)
+            .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+        OUTPUT_FILE.set(file).unwrap();
+    }
+    PRETTY_JSON.store(cli.pretty, Ordering::Relaxed);
+    let verbosity = match (cli.quiet, cli.verbose) {
+        (true, _) => Verbosity::Quiet,
+        (_, true) => Verbosity::Verbose,
+        _ => Verbosity::Normal,
+    };
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
     match cli.command {
-        Commands::Gguf { op } => match op {
-            GgufOp::Summary { file, ternary } => gguf_summary(&file, ternary),
-            GgufOp::Info { file, ternary } => gguf_info(&file, ternary),
-            GgufOp::Show {
-                file,
-                tensor,
-                head,
-                raw,
-                ternary,
-            } => gguf_show(&file, &tensor, head, raw, ternary),
-            GgufOp::Validate { file } => gguf_validate(&file),
+        Commands::Gguf { lossy, mmap, op } => {
+            LOSSY_UTF8.store(lossy, Ordering::Relaxed);
+            if mmap && !cfg!(feature = "mmap") {
+                exit_with(
+                    ExitCategory::Usage,
+                    "--mmap needs a build with `--features mmap`",
+                );
+            }
+            GGUF_MMAP.store(mmap, Ordering::Relaxed);
+            match op {
+                GgufOp::Summary { file, ternary } => gguf_summary(&file, ternary),
+                GgufOp::Info {
+                    file,
+                    ternary,
+                    max_values,
+                    full,
+                    only,
+                    only_ternary,
+                    watch,
+                    interval_ms,
+                    lenient,
+                } => {
+                    if watch {
+                        gguf_watch(&file, Duration::from_millis(interval_ms));
+                    }
+                    let (only, ternary) = match only_ternary {
+                        Some(field) => (Some(field), true),
+                        None => (only, ternary),
+                    };
+                    gguf_info(
+                        &file,
+                        ternary,
+                        (!full).then_some(max_values),
+                        only.as_deref(),
+                        lenient,
+                    )
+                }
+                GgufOp::Show {
+                    file,
+                    tensor,
+                    head,
+                    offset,
+                    raw,
+                    ternary,
+                    stats,
+                } => gguf_show(&file, &tensor, head, offset, raw, ternary, stats),
+                GgufOp::Hexdump {
+                    file,
+                    tensor,
+                    bytes,
+                } => gguf_hexdump(&file, &tensor, bytes),
+                GgufOp::Validate {
+                    file,
+                    tree,
+                    require_arch,
+                    require,
+                } => gguf_validate(&file, tree, require_arch, &require),
+                GgufOp::Checksum {
+                    file,
+                    algo,
+                    output_format,
+                } => gguf_checksum(&file, algo, output_format),
+                GgufOp::Tensors {
+                    file,
+                    sort,
+                    filter,
+                    output_format,
+                } => gguf_tensors(&file, sort, filter.as_deref(), output_format),
+                GgufOp::Types {
+                    file,
+                    output_format,
+                } => gguf_types(&file, output_format),
+                GgufOp::Vocab { file, range } => gguf_vocab(&file, range.as_deref()),
+                GgufOp::Diff {
+                    a,
+                    b,
+                    output_format,
+                } => gguf_diff(&a, &b, output_format),
+                GgufOp::ExportHeader { file, out } => gguf_export_header(&file, out.as_deref()),
+                GgufOp::CompareData {
+                    original,
+                    quantized,
+                    tensor,
+                } => gguf_compare_data(&original, &quantized, &tensor),
+                GgufOp::Set {
+                    key,
+                    value,
+                    input,
+                    output,
+                    ty,
+                    dry_run,
+                } => gguf_set(&key, &value, ty, &input, &output, dry_run),
+                GgufOp::Merge {
+                    template,
+                    input,
+                    output,
+                    dry_run,
+                } => gguf_merge(&template, &input, &output, dry_run),
+                GgufOp::Rename {
+                    old,
+                    new,
+                    input,
+                    output,
+                    dry_run,
+                } => gguf_rename(&old, &new, &input, &output, dry_run),
+                GgufOp::Reorder {
+                    input,
+                    output,
+                    by,
+                    dry_run,
+                } => gguf_reorder(&input, &output, by, dry_run),
+                GgufOp::Strip {
+                    input,
+                    output,
+                    drop,
+                    ignore_missing,
+                    dry_run,
+                } => gguf_strip(&input, &output, &drop, ignore_missing, dry_run),
+                GgufOp::Align {
+                    input,
+                    output,
+                    alignment,
+                    dry_run,
+                } => gguf_align(&input, &output, alignment, dry_run),
+            }
+        }
+        Commands::Calc {
+            expr,
+            decimal,
+            group,
+            show_both_ternary,
+            count_trits,
+            explain,
+            repl,
+            div_mode,
+            input,
+            allow_constants,
+            strict,
+            timeout_ms,
+            implicit_mul,
+            native_ternary,
+            input_base,
+            modulus,
+            output_format,
+        } => {
+            CALC_CONSTANTS_ON.store(allow_constants, Ordering::Relaxed);
+            CALC_IMPLICIT_MUL.store(implicit_mul, Ordering::Relaxed);
+            CALC_INPUT_BASE.store(input_base, Ordering::Relaxed);
+            CALC_NATIVE_TERNARY.store(native_ternary, Ordering::Relaxed);
+            if let Some(ms) = timeout_ms {
+                CALC_TIMEOUT.set(Duration::from_millis(ms)).unwrap();
+            }
+            if let Some(m) = modulus {
+                CALC_MODULUS.set(m).unwrap();
+            }
+            let format = CalcFormat {
+                decimal,
+                group,
+                both_ternary: show_both_ternary,
+                count_trits,
+            };
+            if repl {
+                let prompt = stderr_shows(Verbosity::Normal);
+                calc_repl(io::stdin().lock(), &mut Output, format, div_mode, prompt)
+                    .unwrap_or_else(|e| exit_with(ExitCategory::Io, e));
+            } else {
+                run_calc(
+                    expr,
+                    input,
+                    format,
+                    output_format,
+                    div_mode,
+                    explain,
+                    strict,
+                );
+            }
+        }
+        Commands::Checksum { file, algo } => run_checksum(&file, algo),
+        Commands::Selftest => run_selftest(),
+        Commands::Random {
+            digits,
+            count,
+            seed,
+            balanced,
+            max,
+        } => run_random(digits, count, seed, balanced, max),
+        Commands::Tfloat { op } => match op {
+            TfloatOp::Encode {
+                value,
+                mantissa,
+                exp,
+            } => run_tfloat_encode(value, mantissa, exp),
+            TfloatOp::Decode { code } => {
+                let t = TFloat::parse(&code).unwrap_or_else(|e| exit_with(ExitCategory::Usage, e));
+                outln!("{}", t.decode());
+            }
         },
-        _ => println!("The ternary age has come. Rejoice."),
+        Commands::Convert {
+            value,
+            from_base,
+            to_base,
+            group,
+            batch: _,
+            input,
+            strict,
+            output_format,
+            big,
+            gray,
+            to_gray,
+            from_gray,
+            hamming,
+            validate,
+            allow_leading_zeros,
+            ternary_sci,
+            cf,
+            terms,
+            rle,
+            from_rle,
+            glyphs,
+            from_glyphs,
+        } => {
+            if let Some(text) = from_rle {
+                let trits =
+                    ternary_rle_decode(&text).unwrap_or_else(|e| exit_with(ExitCategory::Usage, e));
+                outln!("{}", trits);
+            } else if let Some(text) = ternary_sci {
+                run_ternary_sci(&text, to_base);
+            } else if let Some(text) = cf {
+                run_continued_fraction(&text, terms as usize);
+            } else if let Some(digits) = validate {
+                if let Err(e) = is_canonical_ternary(&digits, allow_leading_zeros) {
+                    exit_with(ExitCategory::Check, format!("'{}': {}", digits, e));
+                }
+            } else if let Some(pair) = hamming {
+                run_hamming(&pair[0], &pair[1]);
+            } else if let Some(digits) = gray {
+                for code in ternary_gray_code(digits as usize) {
+                    outln!("{}", code);
+                }
+            } else if let Some(value) = to_gray {
+                run_gray(gray_encode(&value, from_base));
+            } else if let Some(code) = from_gray {
+                run_gray(gray_decode(&code, to_base));
+            } else if let Some(value) = value {
+                if glyphs.is_some() || from_glyphs.is_some() {
+                    let (from, to) = (from_glyphs.as_deref(), glyphs.as_deref());
+                    run_glyphs(&value, from_base, to_base, from, to);
+                } else if rle {
+                    run_rle(&value, from_base, to_base, big);
+                } else {
+                    run_convert(&value, from_base, to_base, group, big);
+                }
+            } else {
+                run_convert_batch(
+                    input.as_deref().unwrap_or("-"),
+                    from_base,
+                    to_base,
+                    group,
+                    big,
+                    strict,
+                    output_format,
+                );
+            }
+        }
     }
 }
 
@@ -162,10 +1040,11 @@ fn main() {
 =====================================================================*/
 
 fn gguf_summary(path: &str, ternary: bool) {
-    let mut f = File::open(path).expect("File not found — are you in the correct timeline?");
-    let header = parse_header(&mut f);
-    let metadata = parse_metadata(&mut f, header.n_metadata_kv);
-    let tensors = parse_tensors(&mut f, header.n_tensors);
+    let mut f =
+        open_gguf(path).unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+    let header = parse_header(&mut f).unwrap_or_else(|e| e.exit());
+    let metadata = parse_metadata(&mut f, &header).unwrap_or_else(|e| e.exit());
+    let tensors = parse_tensors(&mut f, &header).unwrap_or_else(|e| e.exit());
 
     let arch = metadata
         .get("general.architecture")
@@ -178,18 +1057,18 @@ fn gguf_summary(path: &str, ternary: bool) {
         .map(|t| gguf_type_name(t.kind))
         .unwrap_or("unknown");
 
-    println!("GGUF | {} | v{}", arch, header.version);
-    println!(
+    outln!("GGUF | {} | v{}", arch, header.version);
+    outln!(
         "Parameters : {} ({})",
         params,
         int_to_balanced_ternary(params as i64)
     );
-    println!(
+    outln!(
         "Tensors    : {} ({})",
         tensors.len(),
         int_to_balanced_ternary(tensors.len() as i64)
     );
-    println!(
+    outln!(
         "Quant      : {} → {}",
         first_quant,
         if first_quant.contains("Q") || first_quant.starts_with("IQ") {
@@ -198,30 +1077,56 @@ fn gguf_summary(path: &str, ternary: bool) {
             "pure ternary soul"
         }
     );
-    println!("Metadata   : {} pairs", header.n_metadata_kv);
-    println!("Ternary Checksum : {}", ternary_checksum(&metadata));
-    println!();
+    outln!("Metadata   : {} pairs", header.n_metadata_kv);
+    outln!("Ternary Checksum : {}", ternary_checksum(&metadata));
+    outln!();
     if ternary {
-        println!("Ternary hardware readiness: 100% (the machines are dreaming in base-3)");
+        outln!("Ternary hardware readiness: 100% (the machines are dreaming in base-3)");
     } else {
-        println!("Ternary hardware readiness: YES — run with --ternary to ascend");
+        outln!("Ternary hardware readiness: YES — run with --ternary to ascend");
     }
 }
 
-fn gguf_info(path: &str, ternary: bool) {
-    let mut f = File::open(path).unwrap();
-    let header = parse_header(&mut f);
-    let metadata = parse_metadata(&mut f, header.n_metadata_kv);
-    let tensors = parse_tensors(&mut f, header.n_tensors);
+/// With `lenient`, a damaged file still shows the metadata pairs and
+/// tensor infos read before the error, which is then reported as usual.
+fn gguf_info(
+    path: &str,
+    ternary: bool,
+    max_values: Option<usize>,
+    only: Option<&str>,
+    lenient: bool,
+) {
+    let mut f =
+        open_gguf(path).unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+    let header = parse_header(&mut f).unwrap_or_else(|e| e.exit());
+    let (kvs, error) = parse_metadata_kvs_lenient(&mut f, &header);
+    let mut damage = error.map(|e| if lenient { e } else { e.exit() });
+    if let Some(field) = only {
+        let value = info_field(&header, &kvs, field, ternary)
+            .unwrap_or_else(|e| exit_with(ExitCategory::Usage, format!("{}: {}", path, e)));
+        outln!("{}", value);
+        return;
+    }
+    let tensors = if damage.is_none() {
+        let (tensors, error) = parse_tensors_lenient(&mut f, &header);
+        damage = error.map(|e| if lenient { e } else { e.exit() });
+        Some(tensors)
+    } else {
+        None
+    };
 
-    println!(
+    outln!(
         "GGUF v{} | {} tensors | {} metadata KV",
-        header.version, header.n_tensors, header.n_metadata_kv
+        header.version,
+        header.n_tensors,
+        header.n_metadata_kv
     );
-    println!("{:=<80}", "=");
-    println!("METADATA");
-    println!("{:=<80}", "=");
-    for (k, v) in &metadata {
+    outln!("{:=<80}", "=");
+    outln!("METADATA");
+    outln!("{:=<80}", "=");
+    for kv in &kvs {
+        let (k, v) = (&kv.key, metadata_display(&kv.value, max_values));
+        let ty = metadata_type_label(kv);
         if ternary
             && (k.contains("count")
                 || k.contains("size")
@@ -230,20 +1135,28 @@ fn gguf_info(path: &str, ternary: bool) {
                 || k.contains("length"))
         {
             if let Ok(n) = v.parse::<i64>() {
-                println!(
-                    "{:<40} = {} ({})",
+                outln!(
+                    "{:<40} = {} ({}) ({})",
                     k,
                     v,
-                    int_to_balanced_ternary(n)
+                    int_to_balanced_ternary(n),
+                    ty
                 );
                 continue;
             }
         }
-        println!("{:<40} = {}", k, v);
+        outln!("{:<40} = {} ({})", k, v, ty);
     }
-    println!("\n{:=<80}", "=");
-    println!("TENSORS");
-    println!("{:=<80}", "=");
+    let Some(tensors) = tensors else {
+        let read = format!("{} of {} metadata pairs", kvs.len(), header.n_metadata_kv);
+        info_stopped(path, damage.unwrap(), &read);
+    };
+    outln!("\n{:=<80}", "=");
+    outln!("TENSORS");
+    outln!("{:=<80}", "=");
+    let mut total_bytes = 0u64;
+    let mut unsized_tensors = 0;
+    let n_read = tensors.len();
     for t in tensors {
         let shape = t
             .dims
@@ -251,389 +1164,7831 @@ fn gguf_info(path: &str, ternary: bool) {
             .map(|d| d.to_string())
             .collect::<Vec<_>>()
             .join("×");
-        let type_name = gguf_type_name(t.kind);
-        println!(
-            "{:<48} {:<20} {:<12} offset={}",
-            t.name, shape, type_name, t.offset
+        let type_name = gguf_type_label(t.kind);
+        let size = match tensor_byte_size(&t) {
+            Some(n) => {
+                total_bytes = total_bytes.saturating_add(n);
+                format!("{} ({})", n, int_to_balanced_ternary(n as i64))
+            }
+            None => {
+                unsized_tensors += 1;
+                "?".to_string()
+            }
+        };
+        outln!(
+            "{:<48} {:<20} {:<14} offset={} bytes={}",
+            t.name,
+            shape,
+            type_name,
+            t.offset,
+            size
         );
     }
-}
-
-fn gguf_show(path: &str, tensor_name: &str, head: usize, raw: bool, ternary: bool) {
-    let mut f = File::open(path).unwrap();
-    let header = parse_header(&mut f);
-    parse_metadata(&mut f, header.n_metadata_kv);
-    let tensors = parse_tensors(&mut f, header.n_tensors);
-
-    let tensor = tensors
-        .iter()
-        .find(|t| t.name == tensor_name)
-        .expect("Tensor not found — did you spell it correctly in this timeline?");
-
-    let shape_str = tensor
-        .dims
-        .iter()
-        .map(|d| d.to_string())
-        .collect::<Vec<_>>()
-        .join("×");
-    println!(
-        "Tensor : {} | Shape : {} | Type : {}",
-        tensor.name,
-        shape_str,
-        gguf_type_name(tensor.kind)
+    outln!(
+        "\nTotal tensor data : {} bytes ({})",
+        total_bytes,
+        int_to_balanced_ternary(total_bytes as i64)
     );
-
-    f.seek(SeekFrom::Start(tensor.offset)).unwrap();
-    let (element_size, decoder) = gguf_type_decoder(tensor.kind);
-
-    let elements_to_read = head.min(256);
-    let mut buffer = vec![0u8; element_size * elements_to_read];
-    let bytes_read = f.read(&mut buffer).unwrap_or(0);
-    let elements_read = bytes_read / element_size;
-
-    for i in 0..elements_read {
-        let chunk = &buffer[i * element_size..(i + 1) * element_size];
-        let value = decoder(chunk);
-
-        if raw {
-            print!("{:4}: ", i);
-            for b in chunk {
-                print!("{:02x} ", b);
-            }
-            println!();
-        } else if ternary {
-            match &value {
-                GgufValue::Float32(_) | GgufValue::Float64(_) => {
-                    // Floats stay decimal; ternary is about discrete states here
-                    println!(" [{}] {}", i, value);
-                }
-                GgufValue::Int64(n) => {
-                    println!(" [{}] {}", i, int_to_balanced_ternary(*n));
-                }
-                GgufValue::Int32(n) => {
-                    println!(" [{}] {}", i, int_to_balanced_ternary(*n as i64));
-                }
-                GgufValue::Uint64(n) => {
-                    println!(" [{}] {}", i, int_to_balanced_ternary(*n as i64));
-                }
-                GgufValue::Uint32(n) => {
-                    println!(" [{}] {}", i, int_to_balanced_ternary(*n as i64));
-                }
-                _ => {
-                    println!(" [{}] {}", i, value);
-                }
-            }
-        } else {
-            println!(" [{}] {}", i, value);
-        }
+    if unsized_tensors > 0 {
+        outln!("Unknown-type tensors : {} (not counted)", unsized_tensors);
     }
-
-    if elements_read < head {
-        println!("... (reached end of tensor)");
+    if let Some(e) = damage {
+        let read = format!("{} of {} tensor infos", n_read, header.n_tensors);
+        info_stopped(path, e, &read);
     }
 }
 
-fn gguf_validate(path: &str) {
-    let mut f = File::open(path).unwrap();
-    let header = parse_header(&mut f);
-    let metadata = parse_metadata(&mut f, header.n_metadata_kv);
-    let _tensors = parse_tensors(&mut f, header.n_tensors);
-    println!("GGUF file validated successfully — structure is sound.");
-    println!("Ternary metaphysical checksum : {}", ternary_checksum(&metadata));
-    println!("This model is ready for the ternary singularity.");
+/// Report the error `info --lenient` stopped at, after `read` was printed.
+fn info_stopped(path: &str, e: GgufError, read: &str) -> ! {
+    let msg = format!("{}: {} (stopped after {})", path, e, read);
+    exit_with(e.category(), msg)
 }
 
-/*=====================================================================
-  Ternary Soul — base-3 and balanced views
-=====================================================================*/
-
-fn int_to_ternary(mut n: i64) -> String {
-    if n == 0 {
-        return "0".to_string();
-    }
-    if n < 0 {
-        return format!("-{}", int_to_ternary(-n));
-    }
-    let mut digits = Vec::new();
-    while n > 0 {
-        let rem = (n % 3) as u8;
-        digits.push(char::from_digit(rem as u32, 10).unwrap());
-        n /= 3;
+/// Stable, so tensors that tie keep their file order.
+fn sort_tensors(tensors: &mut [GgufTensorInfo], sort: TensorSort) {
+    match sort {
+        TensorSort::File => {}
+        TensorSort::Size => tensors.sort_by_key(|t| std::cmp::Reverse(tensor_byte_size(t))),
+        TensorSort::Name => tensors.sort_by(|a, b| a.name.cmp(&b.name)),
+        TensorSort::Offset => tensors.sort_by_key(|t| t.offset),
     }
-    digits.reverse();
-    digits.into_iter().collect()
 }
 
-/// Balanced ternary: digits in {-1, 0, +1} rendered as '-', '0', '+'
-/// Negative numbers get a leading '-' to distinguish sign from digit.
-fn int_to_balanced_ternary(mut n: i64) -> String {
-    if n == 0 {
-        return "0".to_string();
+fn gguf_tensors(path: &str, sort: TensorSort, filter: Option<&str>, format: OutputFormat) {
+    let (_, mut tensors) = load_gguf(path);
+    if let Some(needle) = filter {
+        tensors.retain(|t| t.name.contains(needle));
     }
+    sort_tensors(&mut tensors, sort);
 
-    let neg = n < 0;
-    if neg {
-        n = -n;
+    if format == OutputFormat::Json {
+        let entries = tensors.iter().map(|t| {
+            json_object! {
+                "name": t.name.as_str(),
+                "shape": json_shape(&t.dims),
+                "type": gguf_type_name(t.kind),
+                "offset": t.offset,
+                "bytes": tensor_byte_size(t),
+            }
+        });
+        print_json(&Json::Array(entries.collect()));
+        return;
     }
-
-    let mut digits = Vec::new();
-    while n != 0 {
-        let mut rem = n % 3; // 0, 1, 2 for positive n
-        n /= 3;
-        if rem == 2 {
-            rem = -1;
-            n += 1;
-        }
-        let ch = match rem {
-            -1 => '-',
-            0 => '0',
-            1 => '+',
-            _ => unreachable!(),
-        };
-        digits.push(ch);
+    for t in &tensors {
+        let size = tensor_byte_size(t).map_or("?".to_string(), |n| {
+            format!("{} ({})", n, int_to_balanced_ternary(n as i64))
+        });
+        outln!(
+            "{:<48} {:<20} {:<14} offset={} bytes={}",
+            t.name,
+            format_shape(&t.dims),
+            gguf_type_label(t.kind),
+            t.offset,
+            size
+        );
     }
+}
 
-    digits.reverse();
-    let mut s: String = digits.into_iter().collect();
-    if neg {
-        s.insert(0, '-');
+/// Prints a status line for `path` every `interval` until interrupted, for
+/// watching a GGUF that another process is writing.
+fn gguf_watch(path: &str, interval: Duration) -> ! {
+    if path == "-" {
+        exit_with(
+            ExitCategory::Usage,
+            "--watch re-reads a file; stdin can only be read once",
+        );
+    }
+    loop {
+        let status = watch_status(path)
+            .unwrap_or_else(|e| exit_with(e.category(), format!("{}: {}", path, e)));
+        outln!("{}", status);
+        std::thread::sleep(interval);
     }
-    s
 }
 
-fn ternary_checksum(meta: &HashMap<String, String>) -> String {
-    let mut h = 0i64;
-    for (k, v) in meta {
-        for c in k.bytes().chain(v.bytes()) {
-            h = h.wrapping_add(c as i64);
-            h = h.wrapping_mul(3);
+/// Header counts and file size of `path` as it is right now. A file cut
+/// off inside the header or tensor infos, or whose data does not reach the
+/// end of the last tensor yet, is reported as incomplete, not an error.
+fn watch_status(path: &str) -> Result<String, GgufError> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut f = BufReader::with_capacity(GGUF_READ_BUFFER, file);
+    let parsed = parse_header(&mut f).and_then(|header| {
+        let kvs = parse_metadata_kvs(&mut f, &header)?;
+        let tensors = parse_tensors(&mut f, &header)?;
+        Ok((header, kvs, tensors))
+    });
+    let (header, kvs, tensors) = match parsed {
+        Err(e) if e.is_truncated() => {
+            return Ok(format!("incomplete | {} bytes | {}", file_len, e));
         }
-    }
-    // Checksums stay as plain base-3
-    int_to_ternary(h.abs())
+        parsed => parsed?,
+    };
+    let alignment = match read_alignment_key(&kvs) {
+        Ok(key) => key.map_or(GGUF_DEFAULT_ALIGNMENT, u64::from),
+        Err(e) => return Ok(format!("invalid | {} bytes | {}", file_len, e)),
+    };
+    let data_start = align_up(f.stream_position()?, alignment);
+    let data_len = tensors
+        .iter()
+        .filter_map(|t| Some(t.offset.saturating_add(tensor_byte_size(t)?)))
+        .max()
+        .unwrap_or(0);
+    let have = file_len.saturating_sub(data_start);
+    let state = if have >= data_len {
+        "complete".to_string()
+    } else {
+        format!("incomplete | data {} of {} bytes", have, data_len)
+    };
+    Ok(format!(
+        "GGUF v{} | {} tensors | {} metadata KV | {} bytes | {}",
+        header.version, header.n_tensors, header.n_metadata_kv, file_len, state
+    ))
 }
 
-/*=====================================================================
-  Correct GGUF Parsing — No More Heresy
-=====================================================================*/
-
-const GGUF_MAGIC: u32 = u32::from_le_bytes(*b"GGUF");
-
-fn parse_header(f: &mut File) -> GgufHeader {
-    let mut buf = [0u8; 24];
-    f.read_exact(&mut buf).unwrap();
+/// One `info` field on its own line for scripts: a header field or
+/// `meta:<key>`. With `ternary` the integer is printed balanced instead.
+fn info_field(
+    header: &GgufHeader,
+    kvs: &[GgufKv],
+    field: &str,
+    ternary: bool,
+) -> Result<String, String> {
+    let n = match field {
+        "magic" if !ternary => {
+            return Ok(String::from_utf8_lossy(&header.magic.to_le_bytes()).into_owned())
+        }
+        "magic" => header.magic as i64,
+        "version" => header.version as i64,
+        "n_tensors" => header.n_tensors as i64,
+        "n_kv" => header.n_metadata_kv as i64,
+        _ => {
+            let key = field.strip_prefix("meta:").ok_or_else(|| {
+                format!(
+                    "unknown field '{}' (expected magic, version, n_tensors, n_kv or meta:<key>)",
+                    field
+                )
+            })?;
+            let kv = kvs
+                .iter()
+                .find(|kv| kv.key == key)
+                .ok_or_else(|| format!("no metadata key '{}'", key))?;
+            // Integers bare, without the variant name Display gives them
+            match kv.value {
+                GgufValue::Uint8(v) => v as i64,
+                GgufValue::Int8(v) => v as i64,
+                GgufValue::Uint16(v) => v as i64,
+                GgufValue::Int16(v) => v as i64,
+                GgufValue::Uint32(v) => v as i64,
+                GgufValue::Int32(v) => v as i64,
+                GgufValue::Uint64(v) if !ternary => return Ok(v.to_string()),
+                GgufValue::Uint64(v) => v as i64,
+                GgufValue::Int64(v) => v,
+                _ if !ternary => return Ok(kv.value.to_string()),
+                _ => return Err(format!("'{}' is not an integer: {}", key, kv.value)),
+            }
+        }
+    };
+    Ok(if ternary {
+        int_to_balanced_ternary(n)
+    } else {
+        n.to_string()
+    })
+}
 
-    let magic = u32::from_le_bytes(buf[0..4].try_into().unwrap());
-    if magic != GGUF_MAGIC {
-        panic!("Not a GGUF file (magic=0x{:08x})", magic);
+/// Tensor count per type — how the model was quantized, at a glance.
+fn gguf_types(path: &str, format: OutputFormat) {
+    let (_, tensors) = load_gguf(path);
+    let mut counts: BTreeMap<u32, u64> = BTreeMap::new();
+    for t in &tensors {
+        *counts.entry(t.kind).or_default() += 1;
     }
 
-    GgufHeader {
-        magic,
-        version: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
-        n_tensors: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
-        n_metadata_kv: u64::from_le_bytes(buf[16..24].try_into().unwrap()),
+    if format == OutputFormat::Json {
+        let entries = counts.iter().map(|(&kind, &n)| {
+            json_object! {
+                "type": gguf_type_name(kind),
+                "type_id": kind,
+                "count": n,
+            }
+        });
+        print_json(&Json::Array(entries.collect()));
+        return;
+    }
+    for (kind, n) in &counts {
+        outln!(
+            "{:<14} : {} ({})",
+            gguf_type_label(*kind),
+            n,
+            int_to_balanced_ternary(*n as i64)
+        );
     }
 }
 
-fn parse_metadata(f: &mut File, count: u64) -> HashMap<String, String> {
-    let mut map = HashMap::new();
-    for _ in 0..count {
-        let key = read_string(f);
-        let ty = read_u32(f);
-        let value = read_value(f, ty);
-        map.insert(key, value.to_string());
+/// Every token of `tokenizer.ggml.tokens` in full, as `index (balanced
+/// index)  token`. Control characters are escaped so each token stays on
+/// its own line.
+fn gguf_vocab(path: &str, range: Option<&str>) {
+    let mut f =
+        open_gguf(path).unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+    let header = parse_header(&mut f).unwrap_or_else(|e| e.exit());
+    let kvs = parse_metadata_kvs(&mut f, &header).unwrap_or_else(|e| e.exit());
+    let key = "tokenizer.ggml.tokens";
+    let tokens = match kvs.iter().find(|kv| kv.key == key).map(|kv| &kv.value) {
+        Some(GgufValue::Array(tokens)) => tokens,
+        Some(_) => exit_with(
+            ExitCategory::Format,
+            format!("{}: {} is not an array", path, key),
+        ),
+        None => exit_with(
+            ExitCategory::Usage,
+            format!("{}: no {} metadata", path, key),
+        ),
+    };
+    let (start, end) = match range {
+        Some(spec) => parse_index_range(spec, tokens.len())
+            .unwrap_or_else(|e| exit_with(ExitCategory::Usage, e)),
+        None => (0, tokens.len()),
+    };
+
+    let label = |i: usize| format!("{} ({})", i, int_to_balanced_ternary(i as i64));
+    let width = (start..end).map(|i| label(i).len()).max().unwrap_or(0);
+    for (i, token) in tokens.iter().enumerate().take(end).skip(start) {
+        let text = match token {
+            GgufValue::String(s) => s
+                .chars()
+                .map(|c| {
+                    if c.is_control() {
+                        c.escape_debug().to_string()
+                    } else {
+                        c.to_string()
+                    }
+                })
+                .collect(),
+            other => other.to_string(),
+        };
+        outln!("{:<width$}  {}", label(i), text, width = width);
     }
-    map
 }
 
-fn parse_tensors(f: &mut File, count: u64) -> Vec<GgufTensorInfo> {
-    let mut vec = Vec::with_capacity(count as usize);
-    for _ in 0..count {
-        let name = read_string(f);
-        let n_dims = read_u32(f) as usize;
-        let mut dims = Vec::with_capacity(n_dims);
-        for _ in 0..n_dims {
-            dims.push(read_u64(f));
-        }
-        let kind = read_u32(f);
-        let offset = read_u64(f);
-        vec.push(GgufTensorInfo {
-            name,
-            dims,
-            kind,
-            offset,
-        });
+/// `a..b`, `a..` or `..b` (end excluded) as a window into `len` items.
+/// The end is clipped to `len`; a start past it is an error.
+fn parse_index_range(spec: &str, len: usize) -> Result<(usize, usize), String> {
+    let (a, b) = spec
+        .split_once("..")
+        .ok_or_else(|| format!("range '{}' is not a..b", spec))?;
+    let bound = |s: &str, default: usize| match s.trim() {
+        "" => Ok(default),
+        s => s
+            .parse::<usize>()
+            .map_err(|_| format!("range '{}': '{}' is not an index", spec, s)),
+    };
+    let (start, end) = (bound(a, 0)?, bound(b, len)?.min(len));
+    if start > end {
+        return Err(format!("range '{}' is outside the {} entries", spec, len));
     }
-    vec
+    Ok((start, end))
 }
 
-fn read_string(f: &mut File) -> String {
-    let len = read_u64(f) as usize;
-    let mut buf = vec![0u8; len];
-    f.read_exact(&mut buf).unwrap();
-    String::from_utf8_lossy(&buf).into_owned()
+/// " (closest: a, b)" for a tensor name that is not in the file, or
+/// nothing when no name comes close.
+fn did_you_mean(wanted: &str, tensors: &[GgufTensorInfo]) -> String {
+    let names = closest_names(wanted, tensors.iter().map(|t| t.name.as_str()));
+    if names.is_empty() {
+        return String::new();
+    }
+    format!(" (closest: {})", names.join(", "))
 }
 
-fn read_u16(f: &mut File) -> u16 {
-    let mut b = [0u8; 2];
-    f.read_exact(&mut b).unwrap();
-    u16::from_le_bytes(b)
+/// Up to three of `names` that `wanted` is probably a typo of: those it
+/// is a prefix of (or that are a prefix of it) first, then those within a
+/// few edits, nearest first.
+fn closest_names<'a>(wanted: &str, names: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let max_edits = (wanted.chars().count() / 4).max(2);
+    let mut close: Vec<(bool, usize, &str)> = names
+        .filter_map(|name| {
+            let prefix = name.starts_with(wanted) || wanted.starts_with(name);
+            let edits = edit_distance(wanted, name);
+            (prefix || edits <= max_edits).then_some((!prefix, edits, name))
+        })
+        .collect();
+    close.sort();
+    close.into_iter().take(3).map(|(_, _, name)| name).collect()
 }
 
-fn read_u32(f: &mut File) -> u32 {
-    let mut b = [0u8; 4];
-    f.read_exact(&mut b).unwrap();
-    u32::from_le_bytes(b)
+/// Levenshtein distance over chars: insertions, deletions and
+/// substitutions, one row of the table at a time.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
 }
 
-fn read_u64(f: &mut File) -> u64 {
-    let mut b = [0u8; 8];
-    f.read_exact(&mut b).unwrap();
-    u64::from_le_bytes(b)
-}
+fn gguf_show(
+    path: &str,
+    tensor_name: &str,
+    head: usize,
+    skip: u64,
+    raw: bool,
+    ternary: bool,
+    stats: bool,
+) {
+    let mut f =
+        open_gguf(path).unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+    let header = parse_header(&mut f).unwrap_or_else(|e| e.exit());
+    let kvs = parse_metadata_kvs(&mut f, &header).unwrap_or_else(|e| e.exit());
+    let tensors = parse_tensors(&mut f, &header).unwrap_or_else(|e| e.exit());
+    let data_start = data_section_start(path, &mut f, &kvs);
 
-fn read_value(f: &mut File, ty: u32) -> GgufValue {
-    match ty {
-        0 => {
-            let mut b = [0u8; 1];
-            f.read_exact(&mut b).unwrap();
-            GgufValue::Uint8(b[0])
-        }
-        1 => {
-            let mut b = [0u8; 1];
-            f.read_exact(&mut b).unwrap();
-            GgufValue::Int8(b[0] as i8)
-        }
-        2 => GgufValue::Uint16(read_u16(f)),
-        3 => {
-            let v = read_u16(f);
-            GgufValue::Int16(v as i16)
-        }
-        4 => GgufValue::Uint32(read_u32(f)),
-        5 => {
-            let v = read_u32(f);
-            GgufValue::Int32(v as i32)
-        }
-        6 => {
-            let mut b = [0u8; 4];
-            f.read_exact(&mut b).unwrap();
-            GgufValue::Float32(f32::from_le_bytes(b))
-        }
-        7 => GgufValue::Uint64(read_u64(f)),
-        8 => {
-            let v = read_u64(f);
-            GgufValue::Int64(v as i64)
+    let tensor = tensors
+        .iter()
+        .find(|t| t.name == tensor_name)
+        .unwrap_or_else(|| {
+            exit_with(
+                ExitCategory::Usage,
+                format!(
+                    "no tensor named '{}' — did you spell it correctly in this timeline?{}",
+                    tensor_name,
+                    did_you_mean(tensor_name, &tensors)
+                ),
+            )
+        });
+
+    let shape_str = tensor
+        .dims
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join("×");
+    outln!(
+        "Tensor : {} | Shape : {} | Type : {}",
+        tensor.name,
+        shape_str,
+        gguf_type_label(tensor.kind)
+    );
+
+    let (element_size, decoder) = gguf_type_decoder(tensor.kind).unwrap_or_else(|| {
+        exit_with(
+            ExitCategory::Usage,
+            format!(
+                "no value decoder for {}; gguf hexdump shows its bytes",
+                gguf_type_label(tensor.kind)
+            ),
+        )
+    });
+    if stats {
+        let stats = tensor_stats(&mut f, data_start, tensor)
+            .unwrap_or_else(|e| exit_with(ExitCategory::Format, format!("{}: {}", path, e)));
+        print_tensor_stats(&stats);
+        return;
+    }
+    // Offsets and limits count values; a block type is read whole blocks
+    // at a time, so the window has to start on one
+    let block_elems = type_size_bytes(tensor.kind).map_or(1, |(n, _)| n);
+    if !skip.is_multiple_of(block_elems) {
+        exit_with(
+            ExitCategory::Usage,
+            format!(
+                "offset {} is inside a {} block; use a multiple of {}",
+                skip,
+                gguf_type_name(tensor.kind),
+                block_elems
+            ),
+        );
+    }
+    let available = tensor.dims.iter().try_fold(1u64, |n, &d| n.checked_mul(d));
+    if let Some(n) = available {
+        if skip >= n {
+            exit_with(
+                ExitCategory::Usage,
+                format!(
+                    "offset {} is past the end of tensor '{}' ({} elements)",
+                    skip, tensor.name, n
+                ),
+            );
         }
-        9 => {
-            let mut b = [0u8; 8];
-            f.read_exact(&mut b).unwrap();
-            GgufValue::Float64(f64::from_le_bytes(b))
+    }
+    let window = available.map_or(head as u64, |n| (head as u64).min(n - skip));
+
+    // Tensor offsets count from the start of the aligned data section
+    let start = tensor_data_start(data_start, tensor)
+        .and_then(|start| {
+            let into = (skip / block_elems).checked_mul(element_size as u64);
+            into.and_then(|into| start.checked_add(into))
+                .ok_or_else(|| {
+                    format!(
+                        "offset {} of tensor '{}' is past 2^64 bytes",
+                        skip, tensor.name
+                    )
+                })
+        })
+        .unwrap_or_else(|e| exit_with(ExitCategory::Format, format!("{}: {}", path, e)));
+    f.seek(SeekFrom::Start(start))
+        .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+
+    let mut chunk = vec![0u8; element_size];
+    let mut shown = 0;
+    while shown < window && f.read_exact(&mut chunk).is_ok() {
+        if raw {
+            // One line per block, numbered by its first value
+            out!("{:4}: ", skip + shown);
+            for b in &chunk {
+                out!("{:02x} ", b);
+            }
+            outln!();
+            shown = (shown + block_elems).min(window);
+            continue;
         }
-        10 => GgufValue::Bool(read_u32(f) != 0),
-        11 => GgufValue::String(read_string(f)),
-        12 => {
-            let len = read_u64(f) as usize;
-            let elem_ty = read_u32(f);
-            let mut arr = Vec::with_capacity(len);
-            for _ in 0..len {
-                arr.push(read_value(f, elem_ty));
+        let values = match decoder(&chunk) {
+            GgufValue::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values.into_iter().take((window - shown) as usize) {
+            let i = skip + shown;
+            shown += 1;
+            if ternary {
+                match &value {
+                    GgufValue::Float32(_) | GgufValue::Float64(_) => {
+                        // Floats stay decimal; ternary is about discrete states here
+                        outln!(" [{}] {}", i, value);
+                    }
+                    GgufValue::Int64(n) => {
+                        outln!(" [{}] {}", i, int_to_balanced_ternary(*n));
+                    }
+                    GgufValue::Int32(n) => {
+                        outln!(" [{}] {}", i, int_to_balanced_ternary(*n as i64));
+                    }
+                    GgufValue::Uint64(n) => {
+                        outln!(" [{}] {}", i, int_to_balanced_ternary(*n as i64));
+                    }
+                    GgufValue::Uint32(n) => {
+                        outln!(" [{}] {}", i, int_to_balanced_ternary(*n as i64));
+                    }
+                    _ => {
+                        outln!(" [{}] {}", i, value);
+                    }
+                }
+            } else {
+                outln!(" [{}] {}", i, value);
             }
-            GgufValue::Array(arr)
         }
-        _ => GgufValue::String(format!("UNKNOWN_TYPE_{}", ty)),
+    }
+
+    if shown < head as u64 {
+        outln!("... (reached end of tensor)");
     }
 }
 
-/*=====================================================================
-  Parameter Estimation
-=====================================================================*/
+/// The raw view `show --raw` decodes from: offsets count from the start
+/// of the tensor, and the header line says where that is in the file.
+fn gguf_hexdump(path: &str, tensor_name: &str, bytes: u64) {
+    let (mut f, data_start, tensor) = open_tensor(path, tensor_name);
+    // A tensor of unknown type has no known size; the end of the file stops it
+    let len = tensor_byte_size(&tensor).map_or(bytes, |size| size.min(bytes));
+    let start = tensor_data_start(data_start, &tensor)
+        .unwrap_or_else(|e| exit_with(ExitCategory::Format, format!("{}: {}", path, e)));
+    f.seek(SeekFrom::Start(start))
+        .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+    let mut data = Vec::new();
+    f.take(len)
+        .read_to_end(&mut data)
+        .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+    outln!(
+        "Tensor : {} | Type : {} | Data at byte {} of the file",
+        tensor.name,
+        gguf_type_label(tensor.kind),
+        start
+    );
+    for (i, line) in data.chunks(HEXDUMP_WIDTH).enumerate() {
+        outln!("{}", hexdump_line((i * HEXDUMP_WIDTH) as u64, line));
+    }
+    if (data.len() as u64) < len {
+        outln!("... (file ends {} bytes into the tensor)", data.len());
+    }
+}
 
-fn estimate_parameters(metadata: &HashMap<String, String>, tensors: &[GgufTensorInfo]) -> u64 {
-    metadata
-        .get("general.parameter_count")
-        .and_then(|s| s.parse::<u64>().ok())
-        .or_else(|| {
-            metadata
-                .get("llama.block_count")
-                .and_then(|s| s.parse::<u64>().ok())
-                .map(|b| b * 110_000_000)
-        })
-        .unwrap_or_else(|| {
-            tensors
-                .iter()
-                .filter(|t| t.name.contains(".weight") || t.name.contains(".bias"))
-                .map(|t| t.dims.iter().product::<u64>())
-                .sum()
+/// Bytes per hexdump line; fewer than the classic 16 to leave room for
+/// the six trits each byte takes.
+const HEXDUMP_WIDTH: usize = 8;
+
+/// `offset  hex bytes  |ascii|  ternary bytes`, the last line padded so
+/// its columns line up with the rest.
+fn hexdump_line(offset: u64, bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let ascii: String = bytes
+        .iter()
+        .map(|&b| match b {
+            b' '..=b'~' => b as char,
+            _ => '.',
         })
+        .collect();
+    // Six trits, as many as 255 takes
+    let width = ternary_digit_count(u8::MAX as i64);
+    let trits: Vec<String> = bytes
+        .iter()
+        .map(|&b| format!("{:0>1$}", int_to_ternary(b as i64), width))
+        .collect();
+    format!(
+        "{:08x}  {:<hex_width$}  |{:<w$}|  {}",
+        offset,
+        hex.join(" "),
+        ascii,
+        trits.join(" "),
+        hex_width = HEXDUMP_WIDTH * 3 - 1,
+        w = HEXDUMP_WIDTH
+    )
 }
 
-/*=====================================================================
-  Quantization Types & Preview Decoding
-=====================================================================*/
+fn gguf_validate(path: &str, tree: bool, require_arch: bool, require: &[String]) {
+    let mut f =
+        open_gguf(path).unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+    let header = parse_header(&mut f).unwrap_or_else(|e| e.exit());
+    let kvs = parse_metadata_kvs(&mut f, &header).unwrap_or_else(|e| e.exit());
+    let tensors = parse_tensors(&mut f, &header).unwrap_or_else(|e| e.exit());
+    let alignment = gguf_alignment(&kvs);
+    let data_start = align_up(f.stream_position().unwrap(), alignment);
+    let file_len = f
+        .seek(SeekFrom::End(0))
+        .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+    let data_len = file_len.saturating_sub(data_start);
+    let layout = read_alignment_key(&kvs).and_then(|key| match key {
+        Some(a) if !a.is_multiple_of(8) => {
+            Err(format!("general.alignment {} is not a multiple of 8", a))
+        }
+        _ => check_tensor_layout(&tensors, alignment, data_len),
+    });
+    if let Err(e) = layout {
+        exit_with(ExitCategory::Format, format!("invalid GGUF: {}", e));
+    }
+    let mut required: Vec<(String, Option<u32>)> = Vec::new();
+    if require_arch {
+        let keys = REQUIRED_ARCH_KEYS.iter();
+        required.extend(keys.map(|&(key, ty)| (key.to_string(), Some(ty as u32))));
+    }
+    for spec in require {
+        let key = parse_required_key(spec).unwrap_or_else(|e| exit_with(ExitCategory::Usage, e));
+        required.push(key);
+    }
+    let problems = missing_metadata(&kvs, &required);
+    for problem in &problems {
+        eprintln!("ternary-tools: {}: {}", path, problem);
+    }
+    if !problems.is_empty() {
+        let n = problems.len();
+        exit_with(
+            ExitCategory::Check,
+            format!("{}: {} required metadata keys missing or mistyped", path, n),
+        );
+    }
 
-fn gguf_type_name(kind: u32) -> &'static str {
-    match kind {
-        0 => "F32",
-        1 => "F16",
-        2 => "Q4_0",
-        3 => "Q4_1",
-        6 => "Q5_0",
-        7 => "Q5_1",
-        8 => "Q8_0",
-        9 => "Q8_1",
-        10 => "Q2_K",
-        11 => "Q3_K",
-        12 => "Q4_K",
-        13 => "Q5_K",
-        14 => "Q6_K",
-        15 => "Q8_K",
-        16 => "IQ2_XXS",
-        17 => "IQ2_XS",
-        18 => "IQ3_XXS",
-        19 => "IQ3_XS",
-        20 => "IQ4_XS",
-        21 => "IQ4_NL",
-        _ => "UNKNOWN",
+    let metadata = metadata_strings(kvs);
+    outln!("GGUF file validated successfully — structure is sound.");
+    outln!(
+        "Ternary metaphysical checksum : {}",
+        ternary_checksum(&metadata)
+    );
+    if tree {
+        let (keys, root) = ternary_checksum_tree(&metadata);
+        let width = keys.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+        for (key, digest) in &keys {
+            outln!("  {:<width$} : {}", key, digest, width = width);
+        }
+        outln!("  {:<width$} : {}", "root", root, width = width);
     }
+    outln!("This model is ready for the ternary singularity.");
 }
 
-type DecoderFn = fn(&[u8]) -> GgufValue;
+/// What `validate --require-arch` expects, after the keys llama.cpp reads
+/// for every llama-style model; `{arch}` is the file's general.architecture.
+const REQUIRED_ARCH_KEYS: [(&str, GgufValueType); 6] = [
+    ("general.architecture", GgufValueType::String),
+    ("{arch}.context_length", GgufValueType::Uint32),
+    ("{arch}.embedding_length", GgufValueType::Uint32),
+    ("{arch}.feed_forward_length", GgufValueType::Uint32),
+    ("{arch}.block_count", GgufValueType::Uint32),
+    ("{arch}.attention.head_count", GgufValueType::Uint32),
+];
 
-fn gguf_type_decoder(kind: u32) -> (usize, DecoderFn) {
-    match kind {
-        0 => (
-            4,
-            |b| {
-                let arr: [u8; 4] = b.try_into().unwrap();
-                GgufValue::Float32(f32::from_le_bytes(arr))
-            },
-        ),
-        1 => (2, |_| GgufValue::String("F16(decode not impl)".into())),
-        8 => (1, |b| GgufValue::Int32(b[0] as i8 as i32)), // Q8_0 (rough view)
-        2 => (
-            16,
-            |b| {
-                // Q4_0 block preview (very rough)
-                let d = f32::from_le_bytes(b[0..4].try_into().unwrap());
-                let qs = &b[4..];
-                let mut vals = Vec::new();
-                for i in 0..8 {
-                    let q = ((qs[i / 2] >> (4 * (i % 2))) & 0xF) as f32;
-                    vals.push(GgufValue::Float32(d * (q - 8.0)));
+/// `key` or `key:TYPE`, the type named as in `info` (`UINT32`, `STRING`…).
+fn parse_required_key(spec: &str) -> Result<(String, Option<u32>), String> {
+    let Some((key, name)) = spec.rsplit_once(':') else {
+        return Ok((spec.to_string(), None));
+    };
+    let ty = (0..=12)
+        .find(|&ty| gguf_value_type_name(ty).eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("--require {}: unknown type '{}'", spec, name))?;
+    Ok((key.to_string(), Some(ty)))
+}
+
+/// One line per `required` key that is absent or of another type. Keys
+/// naming `{arch}` are skipped when general.architecture is missing too.
+fn missing_metadata(kvs: &[GgufKv], required: &[(String, Option<u32>)]) -> Vec<String> {
+    let arch = kvs.iter().find(|kv| kv.key == "general.architecture");
+    let arch = match arch.map(|kv| &kv.value) {
+        Some(GgufValue::String(arch)) => Some(arch.as_str()),
+        _ => None,
+    };
+    let mut problems = Vec::new();
+    for (key, ty) in required {
+        let key = match (key.contains("{arch}"), arch) {
+            (true, Some(arch)) => key.replace("{arch}", arch),
+            (true, None) => continue,
+            (false, _) => key.clone(),
+        };
+        match (kvs.iter().find(|kv| kv.key == key), ty) {
+            (None, Some(ty)) => problems.push(format!(
+                "missing metadata key '{}' ({})",
+                key,
+                gguf_value_type_name(*ty)
+            )),
+            (None, None) => problems.push(format!("missing metadata key '{}'", key)),
+            (Some(kv), Some(ty)) if kv.ty != *ty => problems.push(format!(
+                "metadata key '{}' is {}, expected {}",
+                key,
+                gguf_value_type_name(kv.ty),
+                gguf_value_type_name(*ty)
+            )),
+            _ => {}
+        }
+    }
+    problems
+}
+
+/// One checksum per tensor over its raw bytes, then a root over those
+/// digests (each followed by a newline) in file order — the data-section
+/// counterpart of `validate --tree`, for bit rot in the weights.
+fn gguf_checksum(path: &str, algo: ChecksumAlgo, format: OutputFormat) {
+    let mut f =
+        open_gguf(path).unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+    let header = parse_header(&mut f).unwrap_or_else(|e| e.exit());
+    let kvs = parse_metadata_kvs(&mut f, &header).unwrap_or_else(|e| e.exit());
+    let tensors = parse_tensors(&mut f, &header).unwrap_or_else(|e| e.exit());
+    let data_start = data_section_start(path, &mut f, &kvs);
+
+    // Read in offset order so stdin only ever seeks forward
+    let mut order: Vec<usize> = (0..tensors.len()).collect();
+    order.sort_by_key(|&i| tensors[i].offset);
+    let mut digests = vec![String::new(); tensors.len()];
+    let mut buf = vec![0u8; 64 * 1024];
+    for i in order {
+        let t = &tensors[i];
+        let size = tensor_byte_size(t).unwrap_or_else(|| {
+            exit_with(
+                ExitCategory::Format,
+                format!("{}: {}", path, unsized_tensor(t)),
+            )
+        });
+        let start = tensor_data_start(data_start, t)
+            .unwrap_or_else(|e| exit_with(ExitCategory::Format, format!("{}: {}", path, e)));
+        f.seek(SeekFrom::Start(start))
+            .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+        let mut checksum = checksum_state(algo);
+        let mut remaining = size;
+        while remaining > 0 {
+            let chunk = &mut buf[..remaining.min(64 * 1024) as usize];
+            f.read_exact(chunk).unwrap_or_else(|e| {
+                exit_with(
+                    ExitCategory::Format,
+                    format!("{}: tensor '{}' data: {}", path, t.name, e),
+                )
+            });
+            checksum.update(chunk);
+            remaining -= chunk.len() as u64;
+        }
+        digests[i] = checksum.finalize();
+    }
+    let mut root = checksum_state(algo);
+    for digest in &digests {
+        root.update(digest.as_bytes());
+        root.update(b"\n");
+    }
+    let root = root.finalize();
+
+    if format == OutputFormat::Json {
+        let entries = tensors.iter().zip(&digests).map(|(t, digest)| {
+            json_object! {
+                "name": t.name.as_str(),
+                "checksum": digest.as_str(),
+            }
+        });
+        print_json(&json_object! {
+            "tensors": Json::Array(entries.collect()),
+            "root": root,
+        });
+        return;
+    }
+    // At least as wide as "root"
+    let width = tensors.iter().map(|t| t.name.len()).fold(4, usize::max);
+    for (t, digest) in tensors.iter().zip(&digests) {
+        outln!("{:<width$} : {}", t.name, digest, width = width);
+    }
+    outln!("{:<width$} : {}", "root", root, width = width);
+}
+
+/// Running statistics over the decoded values of a tensor.
+struct TensorStats {
+    count: u64,
+    zeros: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+}
+
+impl TensorStats {
+    fn add(&mut self, x: f64) {
+        self.count += 1;
+        self.zeros += (x == 0.0) as u64;
+        self.min = self.min.min(x);
+        self.max = self.max.max(x);
+        self.sum += x;
+    }
+}
+
+fn tensor_stats(
+    f: &mut impl ReadSeek,
+    data_start: u64,
+    tensor: &GgufTensorInfo,
+) -> Result<TensorStats, String> {
+    let mut stats = TensorStats {
+        count: 0,
+        zeros: 0,
+        min: f64::INFINITY,
+        max: f64::NEG_INFINITY,
+        sum: 0.0,
+    };
+    for x in tensor_values(f, data_start, tensor)? {
+        stats.add(x?);
+    }
+    Ok(stats)
+}
+
+/// The decoded values of a whole tensor, read in chunks of about 64 KiB so
+/// memory stays flat however large it is. Block types yield every value of
+/// each block.
+struct TensorValues<'a, R> {
+    f: &'a mut R,
+    tensor: &'a GgufTensorInfo,
+    element_size: usize,
+    decoder: DecoderFn,
+    /// Decoder units (values, or whole blocks) not read yet
+    remaining: u64,
+    buf: Vec<u8>,
+    /// Decoded from the last chunk but not handed out yet
+    pending: std::vec::IntoIter<f64>,
+}
+
+fn tensor_values<'a, R: ReadSeek>(
+    f: &'a mut R,
+    data_start: u64,
+    tensor: &'a GgufTensorInfo,
+) -> Result<TensorValues<'a, R>, String> {
+    let size = tensor_byte_size(tensor).ok_or_else(|| unsized_tensor(tensor))?;
+    let start = tensor_data_start(data_start, tensor)?;
+    f.seek(SeekFrom::Start(start)).map_err(|e| e.to_string())?;
+    let (element_size, decoder) = gguf_type_decoder(tensor.kind).ok_or_else(|| {
+        format!(
+            "no value decoder for {} (tensor '{}')",
+            gguf_type_label(tensor.kind),
+            tensor.name
+        )
+    })?;
+    let per_chunk = (64 * 1024 / element_size).max(1);
+    Ok(TensorValues {
+        f,
+        tensor,
+        element_size,
+        decoder,
+        remaining: size / element_size as u64,
+        buf: vec![0u8; per_chunk * element_size],
+        pending: Vec::new().into_iter(),
+    })
+}
+
+impl<R: ReadSeek> TensorValues<'_, R> {
+    fn read_chunk(&mut self) -> Result<(), String> {
+        let per_chunk = (self.buf.len() / self.element_size) as u64;
+        let n = self.remaining.min(per_chunk) as usize;
+        let chunk = &mut self.buf[..n * self.element_size];
+        self.f
+            .read_exact(chunk)
+            .map_err(|e| format!("tensor '{}' data: {}", self.tensor.name, e))?;
+        let mut values = Vec::new();
+        for element in chunk.chunks(self.element_size) {
+            match (self.decoder)(element) {
+                GgufValue::Array(block) => {
+                    for v in block {
+                        values.push(stat_value(&v, self.tensor)?);
+                    }
                 }
-                GgufValue::Array(vals)
-            },
+                v => values.push(stat_value(&v, self.tensor)?),
+            }
+        }
+        self.remaining -= n as u64;
+        self.pending = values.into_iter();
+        Ok(())
+    }
+}
+
+impl<R: ReadSeek> Iterator for TensorValues<'_, R> {
+    type Item = Result<f64, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(x) = self.pending.next() {
+                return Some(Ok(x));
+            }
+            if self.remaining == 0 {
+                return None;
+            }
+            if let Err(e) = self.read_chunk() {
+                // Nothing after a bad chunk can be trusted
+                self.remaining = 0;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+fn stat_value(value: &GgufValue, tensor: &GgufTensorInfo) -> Result<f64, String> {
+    match *value {
+        GgufValue::Float32(x) => Ok(x as f64),
+        GgufValue::Int32(n) => Ok(n as f64),
+        _ => Err(format!(
+            "no value decoder for {} (tensor '{}')",
+            gguf_type_label(tensor.kind),
+            tensor.name
+        )),
+    }
+}
+
+fn print_tensor_stats(stats: &TensorStats) {
+    // The integer parts of the figures in balanced ternary, as elsewhere
+    let int_part = |x: f64| int_to_balanced_ternary(x.trunc() as i64);
+    outln!(
+        "Values    : {} ({})",
+        stats.count,
+        int_to_balanced_ternary(stats.count as i64)
+    );
+    if stats.count == 0 {
+        return;
+    }
+    let mean = stats.sum / stats.count as f64;
+    outln!("Min       : {:.6} ({})", stats.min, int_part(stats.min));
+    outln!("Max       : {:.6} ({})", stats.max, int_part(stats.max));
+    outln!("Mean      : {:.6} ({})", mean, int_part(mean));
+    outln!(
+        "Zeros     : {} ({:.4} of all values)",
+        stats.zeros,
+        stats.zeros as f64 / stats.count as f64
+    );
+}
+
+/// Both tensors are decoded to floats and streamed side by side, so the
+/// files may store them in different types (F32 against F16, Q8_0 or
+/// Q4_0). Other block types have no decoder yet and are refused.
+fn gguf_compare_data(original: &str, quantized: &str, tensor_name: &str) {
+    let (mut fa, start_a, a) = open_tensor(original, tensor_name);
+    let (mut fb, start_b, b) = open_tensor(quantized, tensor_name);
+    if a.dims != b.dims {
+        exit_with(
+            ExitCategory::Usage,
+            format!(
+                "tensor '{}' is {} in {} but {} in {}",
+                tensor_name,
+                format_shape(&a.dims),
+                original,
+                format_shape(&b.dims),
+                quantized
+            ),
+        );
+    }
+    let values_a = tensor_values(&mut fa, start_a, &a)
+        .unwrap_or_else(|e| exit_with(ExitCategory::Format, format!("{}: {}", original, e)));
+    let values_b = tensor_values(&mut fb, start_b, &b)
+        .unwrap_or_else(|e| exit_with(ExitCategory::Format, format!("{}: {}", quantized, e)));
+    let drift =
+        tensor_drift(values_a, values_b).unwrap_or_else(|e| exit_with(ExitCategory::Format, e));
+
+    outln!(
+        "Tensor    : {} | Shape : {} | Type : {} → {}",
+        tensor_name,
+        format_shape(&a.dims),
+        gguf_type_label(a.kind),
+        gguf_type_label(b.kind)
+    );
+    outln!(
+        "Values    : {} ({})",
+        drift.count,
+        int_to_balanced_ternary(drift.count as i64)
+    );
+    if drift.count == 0 {
+        return;
+    }
+    outln!("Max abs   : {:.6e}", drift.max_abs);
+    outln!("Mean abs  : {:.6e}", drift.sum_abs / drift.count as f64);
+    match drift.cosine() {
+        Some(c) => outln!("Cosine    : {:.9}", c),
+        None => outln!("Cosine    : undefined (an all-zero tensor)"),
+    }
+}
+
+/// The named tensor's infos, with its file and where the data section
+/// starts; exits with suggestions when the file has no such tensor.
+fn open_tensor(path: &str, name: &str) -> (Box<dyn ReadSeek>, u64, GgufTensorInfo) {
+    let mut f =
+        open_gguf(path).unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+    let header = parse_header(&mut f).unwrap_or_else(|e| e.exit());
+    let kvs = parse_metadata_kvs(&mut f, &header).unwrap_or_else(|e| e.exit());
+    let mut tensors = parse_tensors(&mut f, &header).unwrap_or_else(|e| e.exit());
+    let data_start = data_section_start(path, &mut f, &kvs);
+    match tensors.iter().position(|t| t.name == name) {
+        Some(i) => (f, data_start, tensors.swap_remove(i)),
+        None => exit_with(
+            ExitCategory::Usage,
+            format!(
+                "{}: no tensor named '{}'{}",
+                path,
+                name,
+                did_you_mean(name, &tensors)
+            ),
         ),
-        _ => (
-            4,
-            |b| {
-                let mut s = String::new();
-                for i in 0..4 {
-                    s.push_str(&format!("{:02x}", b[i]));
+    }
+}
+
+/// Element-by-element differences between two tensors' values.
+struct TensorDrift {
+    count: u64,
+    max_abs: f64,
+    sum_abs: f64,
+    dot: f64,
+    sum_sq_a: f64,
+    sum_sq_b: f64,
+}
+
+impl TensorDrift {
+    /// `None` when either tensor is all zeros and has no direction.
+    fn cosine(&self) -> Option<f64> {
+        let norms = (self.sum_sq_a * self.sum_sq_b).sqrt();
+        (norms > 0.0).then(|| self.dot / norms)
+    }
+}
+
+fn tensor_drift(
+    mut a: impl Iterator<Item = Result<f64, String>>,
+    mut b: impl Iterator<Item = Result<f64, String>>,
+) -> Result<TensorDrift, String> {
+    let mut drift = TensorDrift {
+        count: 0,
+        max_abs: 0.0,
+        sum_abs: 0.0,
+        dot: 0.0,
+        sum_sq_a: 0.0,
+        sum_sq_b: 0.0,
+    };
+    loop {
+        let (x, y) = match (a.next(), b.next()) {
+            (None, None) => return Ok(drift),
+            (Some(x), Some(y)) => (x?, y?),
+            _ => {
+                return Err(format!(
+                    "the tensors decode to different numbers of values (diverged after {})",
+                    drift.count
+                ))
+            }
+        };
+        let error = (x - y).abs();
+        drift.count += 1;
+        drift.max_abs = drift.max_abs.max(error);
+        drift.sum_abs += error;
+        drift.dot += x * y;
+        drift.sum_sq_a += x * x;
+        drift.sum_sq_b += y * y;
+    }
+}
+
+/// Every tensor must start on an `alignment` boundary, end within the
+/// `data_len` bytes of the data section and not overlap another tensor.
+/// Tensors of unknown type have no known size, so only alignment applies.
+fn check_tensor_layout(
+    tensors: &[GgufTensorInfo],
+    alignment: u64,
+    data_len: u64,
+) -> Result<(), String> {
+    let mut spans = Vec::new();
+    for t in tensors {
+        if t.offset % alignment != 0 {
+            return Err(format!(
+                "tensor '{}' offset {} is not a multiple of the alignment {}",
+                t.name, t.offset, alignment
+            ));
+        }
+        // Unknown types are skipped; absurd dimensions are not
+        let Some(size) = tensor_byte_size(t) else {
+            if type_size_bytes(t.kind).is_some() {
+                return Err(unsized_tensor(t));
+            }
+            continue;
+        };
+        let end = t.offset.checked_add(size).filter(|&end| end <= data_len);
+        match end {
+            Some(end) => spans.push((t.offset, end, &t.name)),
+            None => {
+                return Err(format!(
+                    "tensor '{}' ({} bytes at offset {}) runs past the end of the data ({} bytes)",
+                    t.name, size, t.offset, data_len
+                ))
+            }
+        }
+    }
+    spans.sort();
+    for pair in spans.windows(2) {
+        let ((_, end, a), (start, _, b)) = (pair[0], pair[1]);
+        if start < end {
+            return Err(format!("tensor '{}' overlaps tensor '{}'", b, a));
+        }
+    }
+    Ok(())
+}
+
+fn gguf_diff(path_a: &str, path_b: &str, format: OutputFormat) {
+    let (meta_a, tensors_a) = load_gguf(path_a);
+    let (meta_b, tensors_b) = load_gguf(path_b);
+
+    // BTree everywhere so the report itself diffs cleanly between runs
+    let keys: BTreeSet<&String> = meta_a.keys().chain(meta_b.keys()).collect();
+    let meta_changes: Vec<(&String, Option<&String>, Option<&String>)> = keys
+        .into_iter()
+        .map(|k| (k, meta_a.get(k), meta_b.get(k)))
+        .filter(|(_, a, b)| a != b)
+        .collect();
+
+    let by_name_a: BTreeMap<&str, &GgufTensorInfo> =
+        tensors_a.iter().map(|t| (t.name.as_str(), t)).collect();
+    let by_name_b: BTreeMap<&str, &GgufTensorInfo> =
+        tensors_b.iter().map(|t| (t.name.as_str(), t)).collect();
+    let names: BTreeSet<&str> = by_name_a.keys().chain(by_name_b.keys()).copied().collect();
+    let tensor_changes: Vec<(&str, Option<&GgufTensorInfo>, Option<&GgufTensorInfo>)> = names
+        .into_iter()
+        .map(|n| (n, by_name_a.get(n).copied(), by_name_b.get(n).copied()))
+        .filter(|(_, a, b)| match (a, b) {
+            (Some(a), Some(b)) => a.dims != b.dims || a.kind != b.kind || a.offset != b.offset,
+            _ => true,
+        })
+        .collect();
+
+    if format == OutputFormat::Json {
+        print_diff_json(&meta_changes, &tensor_changes);
+        return;
+    }
+
+    outln!("--- {}", path_a);
+    outln!("+++ {}", path_b);
+    if meta_changes.is_empty() && tensor_changes.is_empty() {
+        outln!("No differences — both files live in the same timeline.");
+        return;
+    }
+    outln!("METADATA ({} differences)", meta_changes.len());
+    for (k, a, b) in &meta_changes {
+        match (a, b) {
+            (Some(a), Some(b)) => outln!("~ {:<40} = {} → {}", k, a, b),
+            (Some(a), None) => outln!("- {:<40} = {}", k, a),
+            (None, Some(b)) => outln!("+ {:<40} = {}", k, b),
+            (None, None) => unreachable!(),
+        }
+    }
+    outln!("TENSORS ({} differences)", tensor_changes.len());
+    for (name, a, b) in &tensor_changes {
+        match (a, b) {
+            (Some(a), Some(b)) => {
+                let mut fields = Vec::new();
+                if a.dims != b.dims {
+                    fields.push(format!(
+                        "shape {} → {}",
+                        format_shape(&a.dims),
+                        format_shape(&b.dims)
+                    ));
+                }
+                if a.kind != b.kind {
+                    fields.push(format!(
+                        "type {} → {}",
+                        gguf_type_label(a.kind),
+                        gguf_type_label(b.kind)
+                    ));
                 }
-                GgufValue::String(s)
+                if a.offset != b.offset {
+                    fields.push(format!("offset {} → {}", a.offset, b.offset));
+                }
+                outln!("~ {:<48} {}", name, fields.join(", "));
+            }
+            (Some(t), None) | (None, Some(t)) => outln!(
+                "{} {:<48} {:<20} {:<14} offset={}",
+                if a.is_some() { '-' } else { '+' },
+                name,
+                format_shape(&t.dims),
+                gguf_type_label(t.kind),
+                t.offset
+            ),
+            (None, None) => unreachable!(),
+        }
+    }
+}
+
+fn print_diff_json(
+    meta_changes: &[(&String, Option<&String>, Option<&String>)],
+    tensor_changes: &[(&str, Option<&GgufTensorInfo>, Option<&GgufTensorInfo>)],
+) {
+    let meta = meta_changes.iter().map(|(k, a, b)| {
+        json_object! {
+            "key": k.as_str(),
+            "a": a.map(|s| s.as_str()),
+            "b": b.map(|s| s.as_str()),
+        }
+    });
+    let tensor = |t: Option<&GgufTensorInfo>| {
+        t.map(|t| {
+            json_object! {
+                "shape": json_shape(&t.dims),
+                "type_id": t.kind,
+                "offset": t.offset,
+            }
+        })
+    };
+    let tensors = tensor_changes.iter().map(|(name, a, b)| {
+        json_object! {
+            "name": *name,
+            "a": tensor(*a),
+            "b": tensor(*b),
+        }
+    });
+    print_json(&json_object! {
+        "metadata": Json::Array(meta.collect()),
+        "tensors": Json::Array(tensors.collect()),
+    });
+}
+
+/// The header a SafeTensors file with these tensors would start with:
+/// `__metadata__` (every value as text), then per tensor its dtype, its
+/// shape outermost dimension first (GGUF lists the innermost first) and
+/// `data_offsets` into the GGUF data section as it is, alignment gaps
+/// and all. Quantized tensors have no SafeTensors dtype. `out` is
+/// `--out`, a file to write it to in place of the results.
+fn gguf_export_header(path: &str, out: Option<&str>) {
+    let mut f =
+        open_gguf(path).unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+    let header = parse_header(&mut f).unwrap_or_else(|e| e.exit());
+    let kvs = parse_metadata_kvs(&mut f, &header).unwrap_or_else(|e| e.exit());
+    let tensors = parse_tensors(&mut f, &header).unwrap_or_else(|e| e.exit());
+    // The offsets count from a data section that only the alignment places
+    read_alignment_key(&kvs)
+        .unwrap_or_else(|e| exit_with(ExitCategory::Format, format!("{}: {}", path, e)));
+
+    let metadata = kvs
+        .iter()
+        .map(|kv| (kv.key.clone(), metadata_display(&kv.value, None).into()))
+        .collect();
+    let mut fields = vec![("__metadata__".to_string(), Json::Object(metadata))];
+    for t in &tensors {
+        // SafeTensors spells the plain types the way ggml does
+        let dtype = match t.kind {
+            0 | 1 | 24..=28 | 30 => gguf_type_name(t.kind),
+            kind => exit_with(
+                ExitCategory::Usage,
+                format!(
+                    "{}: tensor '{}' is {}, which SafeTensors has no dtype for",
+                    path,
+                    t.name,
+                    gguf_type_label(kind)
+                ),
+            ),
+        };
+        let end = tensor_byte_size(t)
+            .and_then(|size| t.offset.checked_add(size))
+            .unwrap_or_else(|| {
+                exit_with(
+                    ExitCategory::Format,
+                    format!("{}: {}", path, unsized_tensor(t)),
+                )
+            });
+        let shape = t.dims.iter().rev().map(|&d| d.into()).collect();
+        fields.push((
+            t.name.clone(),
+            json_object! {
+                "dtype": dtype,
+                "shape": Json::Array(shape),
+                "data_offsets": Json::Array(vec![t.offset.into(), end.into()]),
             },
-        ),
+        ));
+    }
+    let header = Json::Object(fields);
+    let Some(out) = out else {
+        print_json(&header);
+        return;
+    };
+    let text = header.to_text(PRETTY_JSON.load(Ordering::Relaxed));
+    std::fs::write(out, text + "\n")
+        .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", out, e)));
+}
+
+fn gguf_set(
+    key: &str,
+    value: &str,
+    ty: Option<GgufValueType>,
+    input: &str,
+    output: &str,
+    dry_run: bool,
+) {
+    let ty = rewrite_gguf(input, output, dry_run, |kvs, _| {
+        set_kv(kvs, key, value, ty.map(|t| t as u32))
+    });
+    if dry_run {
+        return;
+    }
+    outln!(
+        "{} = {} ({}) → {}",
+        key,
+        value,
+        gguf_value_type_name(ty),
+        output
+    );
+}
+
+/// Applies every entry of a JSON template to the metadata, e.g.
+/// `[{"key": "general.name", "value": "x"}, {"key": "a.b", "type": "uint32", "value": 7}]`.
+/// `type` is optional and follows the same rules as `set --type`.
+fn gguf_merge(template: &str, input: &str, output: &str, dry_run: bool) {
+    let text = std::fs::read_to_string(template)
+        .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", template, e)));
+    let entries = parse_merge_template(&text)
+        .unwrap_or_else(|e| exit_with(ExitCategory::Usage, format!("{}: {}", template, e)));
+    rewrite_gguf(input, output, dry_run, |kvs, _| {
+        for (key, value, ty) in &entries {
+            set_kv(kvs, key, value, *ty)?;
+        }
+        Ok(())
+    });
+    if dry_run {
+        return;
+    }
+    outln!("{} keys merged → {}", entries.len(), output);
+}
+
+/// Tensor offsets count from the data section, so a longer or shorter
+/// name only changes the padding in front of it; the data is copied as is.
+fn gguf_rename(old: &str, new: &str, input: &str, output: &str, dry_run: bool) {
+    rewrite_gguf(input, output, dry_run, |_, tensors| {
+        if tensors.iter().any(|t| t.name == new) {
+            return Err(format!("a tensor named '{}' already exists", new));
+        }
+        let i = tensors
+            .iter()
+            .position(|t| t.name == old)
+            .ok_or_else(|| format!("no tensor named '{}'{}", old, did_you_mean(old, tensors)))?;
+        tensors[i].name = new.to_string();
+        Ok(())
+    });
+    if dry_run {
+        return;
+    }
+    outln!("renamed {} to {} → {}", old, new, output);
+}
+
+/// Sorts the tensor infos by `by` and lays their data out in that same
+/// order, packed at the file's alignment.
+fn gguf_reorder(input: &str, output: &str, by: TensorSort, dry_run: bool) {
+    let count = rewrite_gguf(input, output, dry_run, |kvs, tensors| {
+        let names: BTreeSet<&str> = tensors.iter().map(|t| t.name.as_str()).collect();
+        if names.len() < tensors.len() {
+            return Err("tensor names repeat, so their data cannot be told apart".into());
+        }
+        sort_tensors(tensors, by);
+        let order: Vec<usize> = (0..tensors.len()).collect();
+        pack_tensors(tensors, &order, gguf_alignment(kvs))?;
+        Ok(tensors.len())
+    });
+    if dry_run {
+        return;
+    }
+    outln!("reordered {} tensors → {}", count, output);
+}
+
+/// Drops the metadata `keys`; the tensor infos and data are kept as they
+/// are, only moved up behind the shorter header.
+fn gguf_strip(input: &str, output: &str, keys: &[String], ignore_missing: bool, dry_run: bool) {
+    let dropped = rewrite_gguf(input, output, dry_run, |kvs, _| {
+        let mut dropped = 0;
+        for spec in keys {
+            let key = spec.strip_prefix("meta:").unwrap_or(spec);
+            if key == "general.alignment" {
+                return Err("dropping general.alignment moves every tensor; use gguf align".into());
+            }
+            let before = kvs.len();
+            kvs.retain(|kv| kv.key != key);
+            if kvs.len() == before && !ignore_missing {
+                return Err(format!("no metadata key '{}'", key));
+            }
+            dropped += before - kvs.len();
+        }
+        Ok(dropped)
+    });
+    if dry_run {
+        return;
+    }
+    outln!("dropped {} metadata keys → {}", dropped, output);
+}
+
+/// Moves the tensors onto `alignment`-byte boundaries, keeping their order
+/// in the data section, and records the new `general.alignment`.
+fn gguf_align(input: &str, output: &str, alignment: u32, dry_run: bool) {
+    let (count, data_len) = rewrite_gguf(input, output, dry_run, |kvs, tensors| {
+        realign_tensors(kvs, tensors, alignment)
+    });
+    if dry_run {
+        return;
+    }
+    outln!(
+        "{} tensors aligned to {} bytes, {} bytes of data → {}",
+        count,
+        alignment,
+        data_len,
+        output
+    );
+}
+
+/// Packs `tensors` back to back, each at the next multiple of `alignment`,
+/// in their current offset order. Returns the tensor count and the new
+/// length of the data section.
+fn realign_tensors(
+    kvs: &mut Vec<GgufKv>,
+    tensors: &mut [GgufTensorInfo],
+    alignment: u32,
+) -> Result<(usize, u64), String> {
+    // The spec requires a multiple of 8 so every scalar stays naturally aligned
+    if alignment == 0 || !alignment.is_multiple_of(8) {
+        return Err(format!("alignment {} is not a multiple of 8", alignment));
+    }
+    let mut order: Vec<usize> = (0..tensors.len()).collect();
+    order.sort_by_key(|&i| tensors[i].offset);
+    let end = pack_tensors(tensors, &order, alignment as u64)?;
+    store_kv(
+        kvs,
+        "general.alignment",
+        &alignment.to_string(),
+        Some(GgufValueType::Uint32 as u32),
+    )?;
+    Ok((tensors.len(), end))
+}
+
+/// Gives the tensors new offsets, back to back in `order` (indices into
+/// `tensors`), each at the next multiple of `alignment`. Returns the new
+/// length of the data section.
+fn pack_tensors(
+    tensors: &mut [GgufTensorInfo],
+    order: &[usize],
+    alignment: u64,
+) -> Result<u64, String> {
+    let mut end = 0u64;
+    for &i in order {
+        let t = &mut tensors[i];
+        let size = tensor_byte_size(t).ok_or_else(|| unsized_tensor(t))?;
+        let offset = end.checked_next_multiple_of(alignment);
+        let new_end = offset.and_then(|offset| offset.checked_add(size));
+        let (Some(offset), Some(new_end)) = (offset, new_end) else {
+            return Err(format!("tensor '{}' would end past 2^64 bytes", t.name));
+        };
+        t.offset = offset;
+        end = new_end;
+    }
+    Ok(end)
+}
+
+/// (key, value text, explicit type id) for each template entry.
+fn parse_merge_template(text: &str) -> Result<Vec<(String, String, Option<u32>)>, String> {
+    let Json::Array(items) = parse_json(text)? else {
+        return Err("template must be a JSON array of {key, type, value} objects".to_string());
+    };
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let field = |name: &str| match item {
+                Json::Object(fields) => fields.iter().find(|(k, _)| k == name).map(|(_, v)| v),
+                _ => None,
+            };
+            let key = match field("key") {
+                Some(Json::String(k)) => k.clone(),
+                _ => return Err(format!("entry {}: missing string \"key\"", i)),
+            };
+            let value = match field("value") {
+                Some(Json::String(v) | Json::Number(v)) => v.clone(),
+                Some(Json::Bool(b)) => b.to_string(),
+                _ => return Err(format!("entry {} ({}): missing scalar \"value\"", i, key)),
+            };
+            let ty = match field("type") {
+                None => None,
+                Some(Json::String(name)) => Some(
+                    (0..=12)
+                        .filter(|&ty| ty != 9)
+                        .find(|&ty| gguf_value_type_name(ty).eq_ignore_ascii_case(name))
+                        .ok_or_else(|| format!("entry {} ({}): unknown type '{}'", i, key, name))?,
+                ),
+                Some(_) => return Err(format!("entry {} ({}): \"type\" must be a string", i, key)),
+            };
+            Ok((key, value, ty))
+        })
+        .collect()
+}
+
+/// Sets `key` to `text`, encoded as `ty`. Without a type an existing key
+/// keeps its own and a new key becomes a string. Returns the type used.
+fn set_kv(kvs: &mut Vec<GgufKv>, key: &str, text: &str, ty: Option<u32>) -> Result<u32, String> {
+    if key == "general.alignment" {
+        return Err("changing general.alignment moves every tensor; use gguf align".into());
+    }
+    store_kv(kvs, key, text, ty)
+}
+
+/// `set_kv` without its guard on keys that have their own command.
+fn store_kv(kvs: &mut Vec<GgufKv>, key: &str, text: &str, ty: Option<u32>) -> Result<u32, String> {
+    let existing = kvs.iter().position(|kv| kv.key == key);
+    let ty = ty
+        .or(existing.map(|i| kvs[i].ty))
+        .unwrap_or(GgufValueType::String as u32);
+    let raw = encode_value(ty, text)?;
+    let kv = GgufKv {
+        key: key.to_string(),
+        ty,
+        // encode_value writes the v2+ layout, the only one we write
+        value: read_value(&mut raw.as_slice(), ty, 3).unwrap(),
+        raw,
+    };
+    match existing {
+        Some(i) => kvs[i] = kv,
+        None => kvs.push(kv),
+    }
+    Ok(ty)
+}
+
+/// Copies `input` to `output` with the metadata and tensor infos changed by
+/// `edit`. While no tensor offset changes the data section is copied
+/// verbatim and only its start moves; otherwise each tensor's bytes are
+/// copied to their new offset. With `dry_run` everything is read and laid
+/// out as usual, but only a summary of the changes is printed.
+fn rewrite_gguf<T>(
+    input: &str,
+    output: &str,
+    dry_run: bool,
+    edit: impl FnOnce(&mut Vec<GgufKv>, &mut [GgufTensorInfo]) -> Result<T, String>,
+) -> T {
+    if same_file(input, output) {
+        exit_with(
+            ExitCategory::Usage,
+            "refusing to write over the input file; pick another output path",
+        );
+    }
+    let mut f = open_gguf(input)
+        .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", input, e)));
+    let header = parse_header(&mut f).unwrap_or_else(|e| e.exit());
+    if header.version == 1 {
+        exit_with(
+            ExitCategory::Usage,
+            format!("{}: rewriting GGUF v1 files is not supported", input),
+        );
+    }
+    let mut kvs = parse_metadata_kvs(&mut f, &header).unwrap_or_else(|e| e.exit());
+    let original = parse_tensors(&mut f, &header).unwrap_or_else(|e| e.exit());
+    note!(
+        "{}: GGUF v{}, {} metadata pairs, {} tensors",
+        input,
+        header.version,
+        kvs.len(),
+        original.len()
+    );
+    // Where the data starts depends on it, so an unusable key is not guessed at
+    read_alignment_key(&kvs)
+        .unwrap_or_else(|e| exit_with(ExitCategory::Format, format!("{}: {}", input, e)));
+    let input_alignment = gguf_alignment(&kvs);
+    let data_start = align_up(f.stream_position().unwrap(), input_alignment);
+    // A damaged file, so caught before anything is written
+    if let Some(e) = original
+        .iter()
+        .find_map(|t| tensor_data_start(data_start, t).err())
+    {
+        exit_with(ExitCategory::Format, format!("{}: {}", input, e));
+    }
+
+    // Only the dry-run summary compares against the old metadata
+    let original_kvs = if dry_run { kvs.clone() } else { Vec::new() };
+    let mut tensors = original.clone();
+    let result = edit(&mut kvs, &mut tensors).unwrap_or_else(|e| exit_with(ExitCategory::Usage, e));
+    let alignment = gguf_alignment(&kvs);
+    // Setting general.alignment leaves the offsets as they were; they must
+    // already suit the new value, or the tensors are read from wrong places
+    let misaligned = tensors.iter().find(|t| t.offset % alignment != 0);
+    if let Some(t) = misaligned.filter(|_| alignment != input_alignment) {
+        exit_with(
+            ExitCategory::Format,
+            format!(
+                "{}: tensor '{}' offset {} is not a multiple of the alignment {}; use gguf align",
+                input, t.name, t.offset, alignment
+            ),
+        );
+    }
+    // Each tensor's info before the edit: the one at the same position, or,
+    // once `reorder` has shuffled them, the one with the same name
+    let original: Vec<GgufTensorInfo> = tensors
+        .iter()
+        .enumerate()
+        .map(|(i, t)| match original.get(i) {
+            Some(o) if o.name == t.name => o.clone(),
+            _ => original
+                .iter()
+                .find(|o| o.name == t.name)
+                .unwrap_or(&original[i])
+                .clone(),
+        })
+        .collect();
+    let moved = tensors
+        .iter()
+        .zip(&original)
+        .any(|(t, o)| t.offset != o.offset);
+
+    f.seek(SeekFrom::Start(data_start))
+        .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", input, e)));
+    // Written next to the output and renamed into place once complete, so a
+    // failed run leaves no half-written file behind
+    let partial = format!("{}.partial-{}", output, std::process::id());
+    let out: Box<dyn Write> = if dry_run {
+        Box::new(io::sink())
+    } else {
+        let file = File::create(&partial)
+            .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", output, e)));
+        Box::new(io::BufWriter::new(file))
+    };
+    let mut out = CountingWriter {
+        inner: out,
+        count: 0,
+    };
+    let written = write_gguf(&mut out, header.version, &kvs, &tensors, alignment)
+        .and_then(|_| {
+            if moved {
+                copy_tensor_data(&mut out, &mut f, data_start, &original, &tensors)
+            } else {
+                io::copy(&mut f, &mut out).map(|_| ())
+            }
+        })
+        .and_then(|_| out.flush());
+    let count = out.count;
+    drop(out);
+    let written = written.and_then(|_| match dry_run {
+        true => Ok(()),
+        false => std::fs::rename(&partial, output),
+    });
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&partial);
+        exit_with(ExitCategory::Io, format!("{}: {}", output, e));
+    }
+    if !dry_run {
+        let data = if moved {
+            "tensor data moved"
+        } else {
+            "tensor data copied as is"
+        };
+        note!("{}: wrote {} bytes, {}", output, count, data);
+    }
+    if dry_run {
+        let relocated = tensors
+            .iter()
+            .zip(&original)
+            .filter(|(t, o)| t.offset != o.offset)
+            .count();
+        outln!(
+            "dry run: {} would be {} bytes, {} tensors ({} moved)",
+            output,
+            count,
+            tensors.len(),
+            relocated
+        );
+        for line in metadata_changes(&original_kvs, &kvs) {
+            outln!("  {}", line);
+        }
+    }
+    result
+}
+
+/// `+ key (type) = value`, `~ key (type) = value` and `- key` lines for the
+/// pairs added, changed and removed between `before` and `after`.
+fn metadata_changes(before: &[GgufKv], after: &[GgufKv]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for kv in after {
+        let mark = match before.iter().find(|b| b.key == kv.key) {
+            None => '+',
+            Some(b) if b.ty != kv.ty || b.raw != kv.raw => '~',
+            Some(_) => continue,
+        };
+        lines.push(format!(
+            "{} {} ({}) = {}",
+            mark,
+            kv.key,
+            gguf_value_type_name(kv.ty),
+            metadata_display(&kv.value, Some(16))
+        ));
+    }
+    for kv in before {
+        if !after.iter().any(|a| a.key == kv.key) {
+            lines.push(format!("- {}", kv.key));
+        }
+    }
+    lines
+}
+
+/// Whether `output` is the file `input` names, however it is spelled:
+/// `./m.gguf`, an absolute path, a symlink or (on Unix) a hard link. An
+/// output that doesn't exist yet can't be the input.
+fn same_file(input: &str, output: &str) -> bool {
+    if input == output {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if let (Ok(a), Ok(b)) = (std::fs::metadata(input), std::fs::metadata(output)) {
+            return (a.dev(), a.ino()) == (b.dev(), b.ino());
+        }
+    }
+    match (std::fs::canonicalize(input), std::fs::canonicalize(output)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Passes writes through to `inner`, counting the bytes.
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Copies each tensor's bytes from its offset in `old` (relative to
+/// `data_start` in `src`) to its offset in `new`, zero-filling the gaps.
+fn copy_tensor_data(
+    w: &mut impl Write,
+    src: &mut impl ReadSeek,
+    data_start: u64,
+    old: &[GgufTensorInfo],
+    new: &[GgufTensorInfo],
+) -> io::Result<()> {
+    // In output order, so the writer only ever moves forward
+    let mut order: Vec<usize> = (0..new.len()).collect();
+    order.sort_by_key(|&i| new[i].offset);
+    let mut pos = 0;
+    for i in order {
+        let size = tensor_byte_size(&new[i]).unwrap_or(0);
+        io::copy(&mut io::repeat(0).take(new[i].offset - pos), w)?;
+        let start = tensor_data_start(data_start, &old[i])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        src.seek(SeekFrom::Start(start))?;
+        if io::copy(&mut src.by_ref().take(size), w)? < size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("tensor '{}' data is truncated", new[i].name),
+            ));
+        }
+        pos = new[i].offset + size;
+    }
+    Ok(())
+}
+
+fn run_checksum(path: &str, algo: ChecksumAlgo) {
+    let mut input: Box<dyn Read> = if path == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(
+            File::open(path)
+                .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e))),
+        )
+    };
+    let mut checksum = checksum_state(algo);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => checksum.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => exit_with(ExitCategory::Io, format!("{}: {}", path, e)),
+        }
+    }
+    outln!("{}  {}", checksum.finalize(), path);
+}
+
+fn checksum_state(algo: ChecksumAlgo) -> Box<dyn TernaryChecksumAlgo> {
+    match algo {
+        ChecksumAlgo::Ternary => Box::<TernaryChecksum>::default(),
+        ChecksumAlgo::DigitSum => Box::<DigitSumChecksum>::default(),
+        ChecksumAlgo::Crc => Box::<TritCrc>::default(),
+        ChecksumAlgo::Fletcher => Box::<TernaryFletcher>::default(),
+    }
+}
+
+/// An expression on the command line must evaluate as a whole; one read
+/// from a file or stdin goes line by line unless `strict`, see
+/// `run_calc_tolerant`.
+fn run_calc(
+    expr: Option<String>,
+    input: Option<String>,
+    format: CalcFormat,
+    output: OutputFormat,
+    div: DivMode,
+    explain: bool,
+    strict: bool,
+) {
+    let tolerant = expr.is_none() && !strict && !explain;
+    let expr = expr.unwrap_or_else(|| {
+        read_input(input.as_deref().unwrap_or("-"))
+            .unwrap_or_else(|e| exit_with(ExitCategory::Io, e))
+    });
+    if tolerant {
+        run_calc_tolerant(&expr, format, output, div);
+        return;
+    }
+    if explain {
+        match explain_lines(&expr, format, div) {
+            Ok(tree) => out!("{}", tree),
+            Err(e) => exit_with(ExitCategory::Usage, format!("calc: {}", e)),
+        }
+        return;
+    }
+    match eval_lines(&expr, div) {
+        Ok(results) if output == OutputFormat::Json => print_json(&Json::Array(
+            results
+                .into_iter()
+                .map(|n| calc_result_json(n, format))
+                .collect(),
+        )),
+        Ok(results) => {
+            for n in results {
+                outln!("{}", format_calc_result(n, format));
+            }
+        }
+        Err(e) => exit_with(ExitCategory::Usage, format!("calc: {}", e)),
+    }
+}
+
+/// Evaluates each expression of `input` on its own, printing results as
+/// they come; a bad one is reported with its line number and skipped, as
+/// in `convert --batch`. Any bad line makes the exit status 2.
+fn run_calc_tolerant(input: &str, format: CalcFormat, output: OutputFormat, div: DivMode) {
+    let exprs = split_calc_input(input);
+    if exprs.is_empty() {
+        exit_with(
+            ExitCategory::Usage,
+            format!("calc: {}", ParseError::UnexpectedChar('\0')),
+        );
+    }
+    let mut vars = calc_vars();
+    let mut results = 0;
+    let mut failed = false;
+    let mut entries = Vec::new();
+    for (line, e) in &exprs {
+        match eval_expr(e, &mut vars, div) {
+            Ok(n) => {
+                results += 1;
+                record_result(&mut vars, results, n);
+                if output == OutputFormat::Json {
+                    entries.push(calc_result_json(n, format));
+                } else {
+                    outln!("{}", format_calc_result(n, format));
+                }
+            }
+            Err(e) => {
+                eprintln!("ternary-tools: line {}: calc: {}", line, e);
+                failed = true;
+            }
+        }
+    }
+    if output == OutputFormat::Json {
+        print_json(&Json::Array(entries));
+    }
+    if failed {
+        std::process::exit(ExitCategory::Usage as i32);
+    }
+}
+
+/// Evaluates `input` line by line as it arrives, printing each result to
+/// `out` and errors to stderr, until EOF or `quit`. The prompt goes to
+/// stderr so piped output holds nothing but results; `prompt` false
+/// leaves it off too.
+fn calc_repl(
+    input: impl BufRead,
+    out: &mut impl Write,
+    format: CalcFormat,
+    div: DivMode,
+    prompt: bool,
+) -> io::Result<()> {
+    let mut vars = calc_vars();
+    let mut results = 0;
+    let mut lines = input.lines();
+    loop {
+        if prompt {
+            eprint!("> ");
+            io::stderr().flush()?;
+        }
+        let Some(line) = lines.next() else {
+            if prompt {
+                eprintln!();
+            }
+            return Ok(());
+        };
+        let line = line?;
+        let code = line.split('#').next().unwrap_or("").trim();
+        match code {
+            "" => continue,
+            "quit" | "exit" => return Ok(()),
+            _ => {}
+        }
+        match eval_expr(code, &mut vars, div) {
+            Ok(n) => {
+                results += 1;
+                record_result(&mut vars, results, n);
+                writeln!(out, "{}", format_calc_result(n, format))?;
+            }
+            Err(e) => eprintln!("calc: {}", e),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CalcFormat {
+    decimal: bool,
+    /// Split the ternary digits into trytes of three
+    group: bool,
+    /// Unsigned base-3 and balanced ternary side by side
+    both_ternary: bool,
+    /// How often each digit occurs in those forms
+    count_trits: bool,
+}
+
+fn format_calc_result(n: i64, format: CalcFormat) -> String {
+    let mut ternary = int_to_ternary(n);
+    if format.group {
+        ternary = group_ternary(&ternary, 3);
+    }
+    let mut fields = if format.both_ternary {
+        vec![
+            format!("unsigned={}", ternary),
+            format!("balanced={}", int_to_balanced_ternary(n)),
+        ]
+    } else if format.decimal || format.count_trits {
+        vec![format!("ternary={}", ternary)]
+    } else {
+        return ternary;
+    };
+    if format.decimal {
+        fields.push(format!("decimal={}", n));
+    }
+    if format.count_trits {
+        fields.push(format!("trits={}", trit_counts(n)));
+        if format.both_ternary {
+            fields.push(format!("balanced_trits={}", balanced_trit_counts(n)));
+        }
+    }
+    fields.join(" ")
+}
+
+/// The fields of `format_calc_result` as a JSON object, with `decimal`
+/// always there: `{ternary, decimal}`, or `{unsigned, balanced, decimal}`
+/// with `--show-both-ternary`, plus the digit counts if asked for.
+fn calc_result_json(n: i64, format: CalcFormat) -> Json {
+    let mut ternary = int_to_ternary(n);
+    if format.group {
+        ternary = group_ternary(&ternary, 3);
+    }
+    let mut fields = if format.both_ternary {
+        vec![
+            ("unsigned".to_string(), Json::from(ternary)),
+            ("balanced".to_string(), int_to_balanced_ternary(n).into()),
+        ]
+    } else {
+        vec![("ternary".to_string(), Json::from(ternary))]
+    };
+    fields.push(("decimal".to_string(), n.into()));
+    if format.count_trits {
+        fields.push(("trits".to_string(), trit_counts(n).into()));
+        if format.both_ternary {
+            fields.push(("balanced_trits".to_string(), balanced_trit_counts(n).into()));
+        }
+    }
+    Json::Object(fields)
+}
+
+/// `0:a,1:b,2:c`, how often each digit occurs in |n| in base 3.
+fn trit_counts(n: i64) -> String {
+    let mut counts = [0; 3];
+    for b in int_to_ternary(n).bytes().filter(|&b| b != b'-') {
+        counts[(b - b'0') as usize] += 1;
+    }
+    format!("0:{},1:{},2:{}", counts[0], counts[1], counts[2])
+}
+
+/// `-:a,0:b,+:c` over the balanced trits of `n`; 0 is a single `0`.
+fn balanced_trit_counts(n: i64) -> String {
+    let mut trits = balanced_trits(n);
+    if trits.is_empty() {
+        trits.push(Trit::Zero);
+    }
+    let count = |t| trits.iter().filter(|&&x| x == t).count();
+    format!(
+        "-:{},0:{},+:{}",
+        count(Trit::Neg),
+        count(Trit::Zero),
+        count(Trit::Pos)
+    )
+}
+
+fn run_random(digits: u32, count: u64, seed: Option<u64>, balanced: bool, max: Option<u64>) {
+    let seed = seed.unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64)
+    });
+    let mut rng = SplitMix64(seed);
+    let limit = if balanced {
+        balanced_max(digits) as u64
+    } else {
+        3u64.pow(digits) - 1
+    };
+    let limit = max.map_or(limit, |m| m.min(limit));
+    let mut out = io::BufWriter::new(Output);
+    for _ in 0..count {
+        let line = if balanced {
+            let n = rng.below(2 * limit + 1) as i64 - limit as i64;
+            balanced_digits(n, digits)
+        } else {
+            let n = rng.below(limit + 1) as i64;
+            format!("{:0>1$}", int_to_ternary(n), digits as usize)
+        };
+        writeln!(out, "{}", line)
+            .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("output: {}", e)));
+    }
+    out.flush()
+        .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("output: {}", e)));
+}
+
+/// A `selftest` check; the error names the first value it got wrong.
+type SelfTest = fn() -> Result<(), String>;
+
+const SELFTESTS: &[(&str, SelfTest)] = &[
+    ("int ↔ base 2..=36", selftest_radix),
+    ("int ↔ balanced trits", selftest_balanced),
+    ("balanced full adder", selftest_full_adder),
+    ("native ternary + - *", selftest_native_ternary),
+    ("big ternary", selftest_big),
+    ("digit grouping", selftest_grouping),
+    ("Gray code", selftest_gray),
+    ("trit run lengths", selftest_rle),
+    ("ternary scientific notation", selftest_ternary_sci),
+    ("continued fractions", selftest_continued_fraction),
+    ("checksum check values", selftest_checksums),
+    ("tfloat precision", selftest_tfloat),
+    ("calc", selftest_calc),
+];
+
+fn run_selftest() {
+    let mut failed = 0;
+    for (name, check) in SELFTESTS {
+        match check() {
+            Ok(()) => outln!("ok   {}", name),
+            Err(e) => {
+                outln!("FAIL {}: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+    outln!("{} passed, {} failed", SELFTESTS.len() - failed, failed);
+    if failed > 0 {
+        std::process::exit(ExitCategory::Check as i32);
+    }
+}
+
+/// -1000..=1000, the i64 extremes and the powers of 3 either side of ±1.
+fn selftest_values() -> Vec<i64> {
+    let mut values: Vec<i64> = (-1000..=1000).collect();
+    values.extend([i64::MIN, i64::MIN + 1, i64::MAX]);
+    for k in 1..40 {
+        let p = 3i64.pow(k);
+        values.extend([p - 1, p + 1, -p - 1, -p + 1]);
+    }
+    values
+}
+
+fn selftest_radix() -> Result<(), String> {
+    for n in selftest_values() {
+        for base in 2..=36 {
+            let digits = int_to_base(n, base);
+            if base_to_int(&digits, base) != Ok(n) {
+                return Err(format!("{} → {} in base {}", n, digits, base));
+            }
+            if digits.trim_start_matches('-').len() != digit_count(n, base) {
+                return Err(format!("digit_count({}, {}) is off", n, base));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn selftest_balanced() -> Result<(), String> {
+    for n in selftest_values() {
+        let trits = balanced_trits(n);
+        if from_balanced_trits(&trits) != Some(n) || trits.last() == Some(&Trit::Zero) {
+            return Err(format!("{} → {:?}", n, trits));
+        }
+        let glyphs = to_glyphs(n, ['1', '0', 'T']);
+        if from_glyphs(&glyphs, ['1', '0', 'T']) != Ok(n) {
+            return Err(format!("{} → {} in glyphs 10T", n, glyphs));
+        }
+    }
+    Ok(())
+}
+
+fn selftest_full_adder() -> Result<(), String> {
+    for a in Trit::ALL {
+        if Trit::try_from(i8::from(a)) != Ok(a) {
+            return Err(format!("{:?} → {} and back", a, i8::from(a)));
+        }
+        for b in Trit::ALL {
+            for carry in Trit::ALL {
+                let (sum, out) = add_with_carry(a, b, carry);
+                let total = i8::from(a) + i8::from(b) + i8::from(carry);
+                if i8::from(sum) + 3 * i8::from(out) != total {
+                    let inputs = format!("{:?} + {:?} + {:?}", a, b, carry);
+                    return Err(format!("{} → {:?} carry {:?}", inputs, sum, out));
+                }
+            }
+        }
+    }
+    for n in -100..=100 {
+        for m in selftest_values() {
+            let sum = balanced_add(&balanced_trits(n), &balanced_trits(m));
+            let expected = m.checked_add(n);
+            if expected.is_some() && from_balanced_trits(&sum) != expected {
+                return Err(format!("{} + {} → {:?}", n, m, sum));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Random operands of every magnitude, from a fixed seed, checked against
+/// the i64 operations (overflow included).
+fn selftest_native_ternary() -> Result<(), String> {
+    let mut rng = SplitMix64(3);
+    let operand = |rng: &mut SplitMix64| (rng.next_u64() as i64) >> rng.below(64);
+    for _ in 0..10_000 {
+        let (a, b) = (operand(&mut rng), operand(&mut rng));
+        for (name, native) in [
+            ('+', balanced_add as TritOp),
+            ('-', balanced_sub),
+            ('*', balanced_mul),
+        ] {
+            let expected = match name {
+                '+' => a.checked_add(b),
+                '-' => a.checked_sub(b),
+                _ => a.checked_mul(b),
+            };
+            let result = native_ternary(a, b, native);
+            if result != expected {
+                return Err(format!("{} {} {} → {:?}", a, name, b, result));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn selftest_big() -> Result<(), String> {
+    for n in selftest_values() {
+        for base in [2, 3, 10, 36] {
+            let digits = BigTernary::parse(&int_to_ternary(n), 3)?.to_base_string(base);
+            if digits != int_to_base(n, base) {
+                return Err(format!("{} → {} in base {}", n, digits, base));
+            }
+        }
+    }
+    // 100 trits, about 159 bits; reference from an independent bignum
+    let n = BigTernary::parse(&"1202".repeat(25), 3)?;
+    if n.to_string() != "302784293430056656983920913737302497712488169175" {
+        return Err(format!("1202… (100 trits) → {}", n));
+    }
+    let three = BigTernary::from_i64(3);
+    let minus_one = BigTernary::from_i64(-1);
+    let all_twos = BigTernary::parse(&"2".repeat(100), 3)?;
+    let mut pow = BigTernary::from_i64(1);
+    for _ in 0..100 {
+        pow = pow.mul(&three);
+    }
+    if all_twos.add(&BigTernary::from_i64(1)) != pow
+        || pow.add(&minus_one) != all_twos
+        || pow.add(&all_twos.mul(&minus_one)).to_string() != "1"
+    {
+        return Err("3^100 - 1 does not match 100 twos".to_string());
+    }
+    Ok(())
+}
+
+fn selftest_grouping() -> Result<(), String> {
+    for n in selftest_values() {
+        let digits = int_to_ternary(n);
+        let grouped = group_ternary(&digits, 3);
+        if grouped.replace('_', "") != digits || grouped.split('_').skip(1).any(|g| g.len() != 3) {
+            return Err(format!("{} → {}", digits, grouped));
+        }
+    }
+    Ok(())
+}
+
+fn selftest_gray() -> Result<(), String> {
+    let codes = ternary_gray_code(4);
+    for (i, pair) in codes.windows(2).enumerate() {
+        if ternary_hamming(&pair[0], &pair[1]) != Ok(1) {
+            return Err(format!("codeword {} is not one trit from the next", i));
+        }
+    }
+    for (i, code) in codes.iter().enumerate() {
+        if gray_decode(code, 10) != Ok(i.to_string()) {
+            return Err(format!("{} does not decode to {}", code, i));
+        }
+    }
+    Ok(())
+}
+
+/// Round trip through `ternary_rle_encode`, plus one long run each way.
+fn selftest_rle() -> Result<(), String> {
+    let long = "0".repeat(500) + &"12".repeat(50);
+    let mut cases: Vec<String> = selftest_values().into_iter().map(int_to_ternary).collect();
+    cases.extend(["00011".to_string(), "2".repeat(1000), long]);
+    for digits in &cases {
+        let rle = ternary_rle_encode(digits);
+        if ternary_rle_decode(&rle).as_ref() != Ok(digits) {
+            return Err(format!("{} → {} does not expand back", digits, rle));
+        }
+    }
+    if ternary_rle_encode("00011") != "3(0)2(1)" {
+        return Err(format!("00011 → {}", ternary_rle_encode("00011")));
+    }
+    Ok(())
+}
+
+/// Every value written as `trits e k` reads back as the trits with k zeros
+/// after them, and as itself in decimal; `1.2e2` and `2e-1` as documented.
+fn selftest_ternary_sci() -> Result<(), String> {
+    let sci = |text: &str, base: u32| TernarySci::parse(text).and_then(|n| n.to_base_string(base));
+    for n in selftest_values() {
+        let trits = int_to_ternary(n);
+        for k in 0..3 {
+            let text = format!("{}e{}", trits, k);
+            let expected = if n == 0 {
+                "0".to_string()
+            } else {
+                trits.clone() + &"0".repeat(k)
+            };
+            if sci(&text, 3).as_ref() != Ok(&expected) {
+                return Err(format!("{} → {:?}", text, sci(&text, 3)));
+            }
+        }
+        if sci(&trits, 10) != Ok(n.to_string()) {
+            return Err(format!("{} → {:?} in decimal", trits, sci(&trits, 10)));
+        }
+    }
+    for (text, base, expected) in [("1.2e2", 3, "120"), ("1.2e2", 10, "15"), ("2e-1", 3, "0.2")] {
+        if sci(text, base).as_deref() != Ok(expected) {
+            return Err(format!("{} → {:?} in base {}", text, sci(text, base), base));
+        }
+    }
+    Ok(())
+}
+
+/// φ to ten places starts [1; 1, 1, …], with ratios of consecutive
+/// Fibonacci numbers as its convergents; 3/5 is 0.(1210) in base 3.
+fn selftest_continued_fraction() -> Result<(), String> {
+    let cf = ContinuedFraction::expand("1.6180339887", 12)?;
+    let (mut a, mut b) = (1, 1);
+    for (i, &convergent) in cf.convergents().iter().enumerate() {
+        if cf.terms[i] != 1 || convergent != (b, a) {
+            return Err(format!("φ convergent {} is {:?} of {}", i, convergent, cf));
+        }
+        (a, b) = (b, a + b);
+    }
+    let best = ternary_fraction(3, 5, 40);
+    if best != "0.(1210)" {
+        return Err(format!("3/5 → {}", best));
+    }
+    Ok(())
+}
+
+fn selftest_checksums() -> Result<(), String> {
+    // The check values documented in the README, the first one wrong in a
+    // `selftest-inject-failure` build to show a failure gets caught
+    let ternary = if cfg!(feature = "selftest-inject-failure") {
+        "2202020122001"
+    } else {
+        "2202020122000"
+    };
+    let algos = [
+        ("ternary", ChecksumAlgo::Ternary, ternary),
+        ("digit-sum", ChecksumAlgo::DigitSum, "000001112"),
+        ("crc", ChecksumAlgo::Crc, "110001200111"),
+        ("fletcher", ChecksumAlgo::Fletcher, "010012010000122200"),
+    ];
+    for (name, algo, expected) in algos {
+        let mut whole = checksum_state(algo);
+        whole.update(b"123456789");
+        let mut split = checksum_state(algo);
+        split.update(b"1234");
+        split.update(b"56789");
+        if whole.finalize() != expected || split.finalize() != expected {
+            return Err(format!("{} of 123456789 is not {}", name, expected));
+        }
+    }
+    Ok(())
+}
+
+fn selftest_tfloat() -> Result<(), String> {
+    for x in [3.25, -2.5, 1.0, 0.5, 2.0 / 3.0, -1e-7, 123456.789, 1e300] {
+        for mantissa in [4, 12, 30] {
+            let t = TFloat::encode(x, mantissa, 7)?;
+            let relative = ((t.decode() - x) / x).abs();
+            if relative > 3f64.powi(1 - mantissa as i32) || TFloat::parse(&t.to_string()) != Ok(t) {
+                return Err(format!("{} with {} trits → {}", x, mantissa, t));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn selftest_calc() -> Result<(), String> {
+    let cases = [
+        ("12 + 1", 6),
+        ("-(10 - 2) * 2", -2),
+        ("x = 11; x * x", 16),
+        ("1 & -1", -1),
+        ("fact(11) + gcd(110, 11)", 28),
+        ("lcm(11, 20, 2)", 12),
+    ];
+    for (expr, expected) in cases {
+        match eval_expr(expr, &mut HashMap::new(), DivMode::Trunc) {
+            Ok(n) if n == expected => {}
+            other => return Err(format!("{} gave {:?}, not {}", expr, other, expected)),
+        }
+    }
+    Ok(())
+}
+
+fn run_tfloat_encode(value: f64, mantissa_trits: u32, exp_trits: u32) {
+    let t = TFloat::encode(value, mantissa_trits, exp_trits)
+        .unwrap_or_else(|e| exit_with(ExitCategory::Usage, e));
+    let decoded = t.decode();
+    let error = decoded - value;
+    outln!("TFloat   : {}", t);
+    outln!("Exponent : {}", t.exponent);
+    outln!("Mantissa : {}", t.mantissa);
+    outln!("Decoded  : {}", decoded);
+    if value == 0.0 {
+        outln!("Error    : {:e}", error);
+    } else {
+        outln!(
+            "Error    : {:e} (relative {:e})",
+            error,
+            (error / value).abs()
+        );
+    }
+}
+
+fn run_convert(value: &str, from_base: u32, to_base: u32, group: bool, big: bool) {
+    let digits = convert_value(value, from_base, to_base, group, big)
+        .unwrap_or_else(|e| exit_with(ExitCategory::Usage, e));
+    outln!("{}", digits);
+}
+
+/// Converts one value per line of `path` (`-` for stdin). Blank lines pass
+/// through; a bad line is reported by number and, unless `strict`, skipped
+/// so the rest still converts. Any bad line makes the exit status 2. JSON
+/// entries are keyed by base, so the default direction gives
+/// `{decimal, ternary}` and `--from-base 3 --to-base 10` gives them the
+/// other way round.
+fn run_convert_batch(
+    path: &str,
+    from_base: u32,
+    to_base: u32,
+    group: bool,
+    big: bool,
+    strict: bool,
+    format: OutputFormat,
+) {
+    let text = read_input(path).unwrap_or_else(|e| exit_with(ExitCategory::Io, e));
+
+    let (from_key, to_key) = match (batch_key(from_base), batch_key(to_base)) {
+        (from, to) if from == to => ("input".to_string(), "output".to_string()),
+        keys => keys,
+    };
+    let mut failed = false;
+    let mut entries = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let value = line.trim();
+        if value.is_empty() {
+            if format == OutputFormat::Text {
+                outln!();
+            }
+            continue;
+        }
+        match convert_value(value, from_base, to_base, group, big) {
+            Ok(digits) if format == OutputFormat::Json => entries.push(Json::Object(vec![
+                (from_key.clone(), Json::from(value)),
+                (to_key.clone(), Json::from(digits)),
+            ])),
+            Ok(digits) => outln!("{}", digits),
+            Err(e) if strict => exit_with(ExitCategory::Usage, format!("line {}: {}", i + 1, e)),
+            Err(e) => {
+                eprintln!("ternary-tools: line {}: {}", i + 1, e);
+                failed = true;
+            }
+        }
+    }
+    if format == OutputFormat::Json {
+        print_json(&Json::Array(entries));
+    }
+    if failed {
+        std::process::exit(ExitCategory::Usage as i32);
+    }
+}
+
+/// JSON key for one side of a batch conversion: `decimal`, `ternary` or
+/// `base<N>`.
+fn batch_key(base: u32) -> String {
+    match base {
+        10 => "decimal".to_string(),
+        3 => "ternary".to_string(),
+        _ => format!("base{}", base),
+    }
+}
+
+/// `convert` with `--from-glyphs` and/or `--glyphs`: whichever side has
+/// glyphs is balanced ternary, the other side is `--from-base`/`--to-base`.
+fn run_glyphs(value: &str, from_base: u32, to_base: u32, from: Option<&str>, to: Option<&str>) {
+    let convert = || -> Result<String, String> {
+        let n = match from {
+            Some(glyphs) => from_glyphs(value, parse_glyphs(glyphs)?)?,
+            None => base_to_int(value, from_base)?,
+        };
+        Ok(match to {
+            Some(glyphs) => to_glyphs(n, parse_glyphs(glyphs)?),
+            None => int_to_base(n, to_base),
+        })
+    };
+    let digits = convert().unwrap_or_else(|e| exit_with(ExitCategory::Usage, e));
+    outln!("{}", digits);
+}
+
+fn run_ternary_sci(text: &str, to_base: u32) {
+    let value = TernarySci::parse(text)
+        .and_then(|n| n.to_base_string(to_base))
+        .unwrap_or_else(|e| exit_with(ExitCategory::Usage, format!("'{}': {}", text, e)));
+    outln!("{}", value);
+}
+
+fn run_continued_fraction(text: &str, terms: usize) {
+    let cf = ContinuedFraction::expand(text, terms)
+        .unwrap_or_else(|e| exit_with(ExitCategory::Usage, format!("'{}': {}", text, e)));
+    let (p, q) = *cf.convergents().last().unwrap();
+    let listed: Vec<String> = cf
+        .convergents()
+        .iter()
+        .map(|(p, q)| format!("{}/{}", p, q))
+        .collect();
+    outln!("Terms       : {}", cf);
+    outln!("Convergents : {}", listed.join(", "));
+    outln!(
+        "Best        : {}/{} = {} (ternary {}/{})",
+        p,
+        q,
+        ternary_fraction(p, q, 40),
+        int_to_base(p, 3),
+        int_to_base(q, 3)
+    );
+}
+
+fn run_hamming(a: &str, b: &str) {
+    let positions = ternary_mismatches(a, b)
+        .unwrap_or_else(|e| exit_with(ExitCategory::Usage, format!("hamming: {}", e)));
+    let listed: Vec<String> = positions.iter().map(|p| p.to_string()).collect();
+    outln!("Distance  : {}", positions.len());
+    outln!("Positions : {}", listed.join(", "));
+}
+
+/// `convert --rle`: the value in ternary, run-length encoded.
+fn run_rle(value: &str, from_base: u32, to_base: u32, big: bool) {
+    if to_base != 3 {
+        exit_with(
+            ExitCategory::Usage,
+            "--rle encodes ternary; leave --to-base at 3",
+        );
+    }
+    let digits = convert_value(value, from_base, 3, false, big)
+        .unwrap_or_else(|e| exit_with(ExitCategory::Usage, e));
+    outln!("{}", ternary_rle_encode(&digits));
+}
+
+fn run_gray(result: Result<String, String>) {
+    let digits = result.unwrap_or_else(|e| exit_with(ExitCategory::Usage, e));
+    outln!("{}", digits);
+}
+
+/// Gray codeword of `value` (read in `from_base`); only 0 and up have one.
+fn gray_encode(value: &str, from_base: u32) -> Result<String, String> {
+    let n = base_to_int(value, from_base)?;
+    if n < 0 {
+        return Err(format!("'{}' is negative; Gray codes start at 0", value));
+    }
+    Ok(ternary_to_gray(&int_to_ternary(n)))
+}
+
+/// Index of the ternary Gray codeword `code`, rendered in `to_base`.
+fn gray_decode(code: &str, to_base: u32) -> Result<String, String> {
+    if let Some(c) = code.chars().find(|c| !matches!(c, '0'..='2')) {
+        return Err(format!("digit '{}' is not a ternary Gray digit", c));
+    }
+    let n = base_to_int(&gray_to_ternary(code), 3)?;
+    Ok(int_to_base(n, to_base))
+}
+
+fn convert_value(
+    value: &str,
+    from_base: u32,
+    to_base: u32,
+    group: bool,
+    big: bool,
+) -> Result<String, String> {
+    let digits = if big {
+        BigTernary::parse(value, from_base)?.to_base_string(to_base)
+    } else {
+        int_to_base(base_to_int(value, from_base)?, to_base)
+    };
+    Ok(if group {
+        group_ternary(&digits, 3)
+    } else {
+        digits
+    })
+}
+
+/// Exit codes, so scripts can tell a typo from an unreadable or corrupt
+/// file. Clap's own argument errors also exit with 2.
+#[derive(Clone, Copy)]
+enum ExitCategory {
+    /// `selftest` found a check that fails, or `convert --validate` a
+    /// string that is not canonical
+    Check = 1,
+    /// Bad arguments, or an expression or number that does not parse
+    Usage = 2,
+    /// A file could not be opened, read or written
+    Io = 3,
+    /// The file was read but is not valid GGUF
+    Format = 4,
+}
+
+fn exit_with(category: ExitCategory, msg: impl std::fmt::Display) -> ! {
+    eprintln!("ternary-tools: {}", msg);
+    std::process::exit(category as i32);
+}
+
+/// All of `path` (`-` for stdin) as text, with what Windows editors add
+/// taken back out: a leading byte-order mark, and CRLF line endings.
+fn read_input(path: &str) -> Result<String, String> {
+    let mut text = String::new();
+    let read = if path == "-" {
+        io::stdin().read_to_string(&mut text)
+    } else {
+        File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+    };
+    let name = if path == "-" { "stdin" } else { path };
+    read.map_err(|e| format!("{}: {}", name, e))?;
+    let text = text.strip_prefix('\u{feff}').unwrap_or(&text);
+    Ok(text.replace("\r\n", "\n"))
+}
+
+fn load_gguf(path: &str) -> (HashMap<String, String>, Vec<GgufTensorInfo>) {
+    let mut f =
+        open_gguf(path).unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+    let header = parse_header(&mut f).unwrap_or_else(|e| e.exit());
+    let metadata = parse_metadata(&mut f, &header).unwrap_or_else(|e| e.exit());
+    let tensors = parse_tensors(&mut f, &header).unwrap_or_else(|e| e.exit());
+    (metadata, tensors)
+}
+
+fn format_shape(dims: &[u64]) -> String {
+    dims.iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join("×")
+}
+
+/// A metadata value as `info` shows it: integers bare (Display writes
+/// `Uint32(32)`), arrays cut to `max` entries when given.
+fn metadata_display(value: &GgufValue, max: Option<usize>) -> String {
+    match *value {
+        GgufValue::Uint8(v) => v.to_string(),
+        GgufValue::Int8(v) => v.to_string(),
+        GgufValue::Uint16(v) => v.to_string(),
+        GgufValue::Int16(v) => v.to_string(),
+        GgufValue::Uint32(v) => v.to_string(),
+        GgufValue::Int32(v) => v.to_string(),
+        GgufValue::Uint64(v) => v.to_string(),
+        GgufValue::Int64(v) => v.to_string(),
+        _ => match max {
+            Some(max) => truncate_value(value, max),
+            None => value.to_string(),
+        },
+    }
+}
+
+/// The GGUF type of a metadata value, `ARRAY[INT32]` for arrays — whose
+/// raw bytes start with the element type id.
+fn metadata_type_label(kv: &GgufKv) -> String {
+    match kv.raw.get(..4) {
+        Some(elem) if kv.ty == 9 => {
+            let elem = u32::from_le_bytes(elem.try_into().unwrap());
+            format!("ARRAY[{}]", gguf_value_type_name(elem))
+        }
+        _ => gguf_value_type_name(kv.ty).to_string(),
+    }
+}
+
+/// Display form of `value`, with arrays cut to their first `max` entries —
+/// tokenizer vocabularies would otherwise flood the terminal.
+fn truncate_value(value: &GgufValue, max: usize) -> String {
+    match value {
+        GgufValue::Array(arr) if arr.len() > max => {
+            let mut shown: Vec<String> = arr[..max].iter().map(|v| v.to_string()).collect();
+            shown.push(format!("... ({} total)", arr.len()));
+            format!("[{}]", shown.join(", "))
+        }
+        other => other.to_string(),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/*=====================================================================
+  Ternary Soul — base-3 and balanced views
+=====================================================================*/
+
+fn int_to_ternary(n: i64) -> String {
+    int_to_base(n, 3)
+}
+
+/// Any radix in 2..=36, digits 0-9 then a-z, negatives with a leading '-'.
+fn int_to_base(n: i64, base: u32) -> String {
+    // Fill from the least significant end; the sign, if any, stays in slot 0
+    let sign = (n < 0) as usize;
+    let mut digits = vec!['-'; sign + digit_count(n, base)];
+    // unsigned_abs so i64::MIN has a magnitude too
+    let mut m = n.unsigned_abs();
+    for slot in digits[sign..].iter_mut().rev() {
+        *slot = char::from_digit((m % base as u64) as u32, base).unwrap();
+        m /= base as u64;
+    }
+    digits.into_iter().collect()
+}
+
+/// Number of base-3 digits in `n`, without the sign and without building
+/// the string: 1 for 0, 40 for i64::MIN.
+fn ternary_digit_count(n: i64) -> usize {
+    digit_count(n, 3)
+}
+
+/// `ternary_digit_count` for any radix in 2..=36.
+fn digit_count(n: i64, base: u32) -> usize {
+    let mut m = n.unsigned_abs();
+    let mut count = 1;
+    while m >= base as u64 {
+        m /= base as u64;
+        count += 1;
+    }
+    count
+}
+
+fn base_to_int(s: &str, base: u32) -> Result<i64, String> {
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    if digits.is_empty() {
+        return Err(format!("'{}' has no digits", s));
+    }
+    let mut m = 0u64;
+    for c in digits.chars() {
+        let d = c
+            .to_digit(base)
+            .ok_or_else(|| format!("digit '{}' is out of range for base {}", c, base))?;
+        m = m
+            .checked_mul(base as u64)
+            .and_then(|m| m.checked_add(d as u64))
+            .ok_or_else(|| format!("'{}' does not fit in 64 bits", s))?;
+    }
+    match (neg, i64::try_from(m)) {
+        (false, Ok(n)) => Ok(n),
+        (true, Ok(n)) => Ok(-n),
+        (true, Err(_)) if m == i64::MIN.unsigned_abs() => Ok(i64::MIN),
+        _ => Err(format!("'{}' does not fit in 64 bits", s)),
+    }
+}
+
+/// Integer of any size, kept as base-3 digits (0..=2) least significant
+/// first with no trailing zeros, so zero is empty and never negative.
+/// Only what `convert --big` needs: parsing, printing, add and mul.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct BigTernary {
+    neg: bool,
+    trits: Vec<i8>,
+}
+
+impl BigTernary {
+    fn from_i64(n: i64) -> Self {
+        let mut m = n.unsigned_abs();
+        let mut trits = Vec::new();
+        while m > 0 {
+            trits.push((m % 3) as i8);
+            m /= 3;
+        }
+        BigTernary { neg: n < 0, trits }
+    }
+
+    /// Digits in `base` (2..=36), with an optional leading `-`.
+    fn parse(s: &str, base: u32) -> Result<Self, String> {
+        let (neg, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        if digits.is_empty() {
+            return Err(format!("'{}' has no digits", s));
+        }
+        let radix = BigTernary::from_i64(base as i64);
+        let mut n = BigTernary::from_i64(0);
+        for c in digits.chars() {
+            let d = c
+                .to_digit(base)
+                .ok_or_else(|| format!("digit '{}' is out of range for base {}", c, base))?;
+            n = n.mul(&radix).add(&BigTernary::from_i64(d as i64));
+        }
+        n.neg = neg && !n.trits.is_empty();
+        Ok(n)
+    }
+
+    fn add(&self, other: &BigTernary) -> BigTernary {
+        if self.neg == other.neg {
+            return BigTernary {
+                neg: self.neg,
+                trits: add_trits(&self.trits, &other.trits),
+            };
+        }
+        // Opposite signs: the larger magnitude wins and keeps its sign
+        let (big, small) = match cmp_trits(&self.trits, &other.trits) {
+            std::cmp::Ordering::Less => (other, self),
+            _ => (self, other),
+        };
+        let trits = sub_trits(&big.trits, &small.trits);
+        BigTernary {
+            neg: big.neg && !trits.is_empty(),
+            trits,
+        }
+    }
+
+    fn mul(&self, other: &BigTernary) -> BigTernary {
+        if self.trits.is_empty() || other.trits.is_empty() {
+            return BigTernary::from_i64(0);
+        }
+        let mut acc = vec![0i32; self.trits.len() + other.trits.len()];
+        for (i, &a) in self.trits.iter().enumerate() {
+            for (j, &b) in other.trits.iter().enumerate() {
+                acc[i + j] += a as i32 * b as i32;
+            }
+            // Carry after every row so no slot grows past a few units
+            for k in i..acc.len() - 1 {
+                acc[k + 1] += acc[k] / 3;
+                acc[k] %= 3;
+            }
+        }
+        let mut trits: Vec<i8> = acc.into_iter().map(|t| t as i8).collect();
+        trim_trits(&mut trits);
+        BigTernary {
+            neg: self.neg != other.neg,
+            trits,
+        }
+    }
+
+    /// Digits in `base` (2..=36), most significant first, by repeated long
+    /// division of the trits — quadratic, which is fine at a few hundred.
+    fn to_base_string(&self, base: u32) -> String {
+        let mut rest = self.trits.clone();
+        let mut digits = Vec::new();
+        while !rest.is_empty() {
+            let mut rem = 0u32;
+            for t in rest.iter_mut().rev() {
+                let cur = rem * 3 + *t as u32;
+                *t = (cur / base) as i8;
+                rem = cur % base;
+            }
+            digits.push(char::from_digit(rem, base).unwrap());
+            trim_trits(&mut rest);
+        }
+        if digits.is_empty() {
+            digits.push('0');
+        }
+        if self.neg {
+            digits.push('-');
+        }
+        digits.into_iter().rev().collect()
+    }
+}
+
+impl std::fmt::Display for BigTernary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.to_base_string(10))
+    }
+}
+
+fn trim_trits(trits: &mut Vec<i8>) {
+    while trits.last() == Some(&0) {
+        trits.pop();
+    }
+}
+
+fn cmp_trits(a: &[i8], b: &[i8]) -> std::cmp::Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_trits(a: &[i8], b: &[i8]) -> Vec<i8> {
+    let mut out = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0;
+    for i in 0..a.len().max(b.len()) {
+        let sum = a.get(i).unwrap_or(&0) + b.get(i).unwrap_or(&0) + carry;
+        out.push(sum % 3);
+        carry = sum / 3;
+    }
+    if carry > 0 {
+        out.push(carry);
+    }
+    out
+}
+
+/// `a - b` for magnitudes with `a >= b`.
+fn sub_trits(a: &[i8], b: &[i8]) -> Vec<i8> {
+    let mut out = Vec::with_capacity(a.len());
+    let mut borrow = 0;
+    for (i, &t) in a.iter().enumerate() {
+        let mut diff = t - b.get(i).unwrap_or(&0) - borrow;
+        borrow = (diff < 0) as i8;
+        if diff < 0 {
+            diff += 3;
+        }
+        out.push(diff);
+    }
+    trim_trits(&mut out);
+    out
+}
+
+/// Separates `digits` into groups of `size` from the least significant end,
+/// e.g. `1022110` → `1_022_110`. A leading `-` stays outside the groups.
+fn group_ternary(digits: &str, size: usize) -> String {
+    let (sign, digits) = match digits.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", digits),
+    };
+    if size == 0 {
+        return format!("{}{}", sign, digits);
+    }
+    let chars: Vec<char> = digits.chars().collect();
+    let head = chars.len() % size;
+    let mut groups: Vec<String> = Vec::new();
+    if head > 0 {
+        groups.push(chars[..head].iter().collect());
+    }
+    for group in chars[head..].chunks(size) {
+        groups.push(group.iter().collect());
+    }
+    format!("{}{}", sign, groups.join("_"))
+}
+
+/// The reflected ternary Gray code on `digits` trits, in order: 3^digits
+/// codewords, each one trit away from the next. The first block of each
+/// digit counts up, the second runs backwards, the third counts up again
+/// (`00 01 02 12 11 10 20 21 22`).
+fn ternary_gray_code(digits: usize) -> Vec<String> {
+    (0..3i64.pow(digits as u32))
+        .map(|i| ternary_to_gray(&format!("{:0>1$}", int_to_ternary(i), digits)))
+        .collect()
+}
+
+/// Maps plain ternary digits to the codeword at that index of the
+/// reflected Gray code. A digit is mirrored (d → 2 - d) when the codeword
+/// digits before it hold an odd number of 1s, i.e. inside a backwards block.
+fn ternary_to_gray(digits: &str) -> String {
+    let mut reflected = false;
+    digits
+        .chars()
+        .map(|c| {
+            let d = c.to_digit(3).unwrap();
+            let g = if reflected { 2 - d } else { d };
+            reflected ^= g == 1;
+            char::from_digit(g, 3).unwrap()
+        })
+        .collect()
+}
+
+/// Inverse of `ternary_to_gray`; `code` must hold only the digits 0-2.
+fn gray_to_ternary(code: &str) -> String {
+    let mut reflected = false;
+    code.chars()
+        .map(|c| {
+            let g = c.to_digit(3).unwrap();
+            let d = if reflected { 2 - g } else { g };
+            reflected ^= g == 1;
+            char::from_digit(d, 3).unwrap()
+        })
+        .collect()
+}
+
+/// Whether `s` is ternary as this tool writes it: an optional `-`, then
+/// digits 0-2 with no leading zeros (unless `allow_leading_zeros`), and
+/// zero only as `0`, never `-0`.
+fn is_canonical_ternary(s: &str, allow_leading_zeros: bool) -> Result<(), String> {
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    if digits.is_empty() {
+        return Err("no digits".to_string());
+    }
+    if let Some(c) = digits.chars().find(|c| !matches!(c, '0'..='2')) {
+        return Err(format!("'{}' is not a ternary digit (0, 1, 2)", c));
+    }
+    let is_zero = digits.bytes().all(|b| b == b'0');
+    if digits.len() > 1 && digits.starts_with('0') && !allow_leading_zeros {
+        return Err("leading zero".to_string());
+    }
+    if is_zero && digits.len() < s.len() {
+        return Err("negative zero".to_string());
+    }
+    Ok(())
+}
+
+/// Number of trit positions where `a` and `b` differ.
+fn ternary_hamming(a: &str, b: &str) -> Result<usize, ParseError> {
+    ternary_mismatches(a, b).map(|positions| positions.len())
+}
+
+/// Trit positions where `a` and `b` differ, counted from the least
+/// significant trit (0). The shorter string is padded with leading zeros,
+/// which keeps its value, so `12` against `0012` differs nowhere.
+fn ternary_mismatches(a: &str, b: &str) -> Result<Vec<usize>, ParseError> {
+    if let Some(c) = a.chars().chain(b.chars()).find(|c| !matches!(c, '0'..='2')) {
+        return Err(ParseError::InvalidDigit(c, 3));
+    }
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let trit = |s: &[u8], i: usize| s.len().checked_sub(i + 1).map_or(b'0', |j| s[j]);
+    Ok((0..a.len().max(b.len()))
+        .filter(|&i| trit(a, i) != trit(b, i))
+        .collect())
+}
+
+/// Run-length encoding of a ternary string, each run written as
+/// `count(trit)`: `00011` → `3(0)2(1)`, `2` → `1(2)`. A leading `-` stays
+/// in front. Nothing is checked here; `ternary_rle_decode` does that.
+fn ternary_rle_encode(s: &str) -> String {
+    let (sign, digits) = match s.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", s),
+    };
+    let mut out = sign.to_string();
+    let mut chars = digits.chars().peekable();
+    while let Some(c) = chars.next() {
+        let mut run = 1;
+        while chars.next_if_eq(&c).is_some() {
+            run += 1;
+        }
+        out.push_str(&format!("{}({})", run, c));
+    }
+    out
+}
+
+/// Most trits `--from-rle` expands to, so that `99999999999(0)` is an
+/// error rather than a failed allocation.
+const TERNARY_RLE_MAX_TRITS: usize = 1 << 24;
+
+/// Inverse of `ternary_rle_encode`, leading zeros and all. Counts are
+/// decimal and at least 1, each run holds a single trit, and nothing may
+/// come between runs.
+fn ternary_rle_decode(s: &str) -> Result<String, String> {
+    let (sign, mut rest) = match s.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", s),
+    };
+    if rest.is_empty() {
+        return Err("no runs".to_string());
+    }
+    let mut trits = String::new();
+    while !rest.is_empty() {
+        let not_a_run = || format!("'{}' is not a run; expected count(trit)", rest);
+        let (count, tail) = rest.split_at(rest.find('(').ok_or_else(not_a_run)?);
+        if count.is_empty() || !count.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(not_a_run());
+        }
+        let mut inner = tail[1..].chars();
+        let (Some(trit), Some(')')) = (inner.next(), inner.next()) else {
+            return Err(not_a_run());
+        };
+        if !matches!(trit, '0'..='2') {
+            return Err(format!("'{}' is not a ternary digit (0, 1, 2)", trit));
+        }
+        let run = match count.parse::<usize>() {
+            Ok(0) => return Err(format!("'{}({})' is an empty run", count, trit)),
+            Ok(n) if n <= TERNARY_RLE_MAX_TRITS - trits.len() => n,
+            _ => return Err(format!("expands past {} trits", TERNARY_RLE_MAX_TRITS)),
+        };
+        trits.extend(std::iter::repeat_n(trit, run));
+        rest = inner.as_str();
+    }
+    Ok(format!("{}{}", sign, trits))
+}
+
+/// A number in ternary scientific notation:
+///
+///   [-] trits [. trits] [(e|E) [+|-] exponent]
+///
+/// The mantissa is base 3 (digits 0-2) and the exponent is a *decimal*
+/// power of three, unlike base-10 `1.2e3`: `1.2e2` is 1.2₃ × 3² = 120₃ = 15,
+/// `-2e-1` is -2/3 and `1e12` is 3^12. Kept exact as integer trits × 3^exp.
+struct TernarySci {
+    neg: bool,
+    /// No leading zeros; only `exp < 0` keeps trailing ones
+    trits: String,
+    exp: i64,
+}
+
+/// Largest |exponent| `--ternary-sci` takes; 3^4096 already has 1955
+/// decimal digits.
+const TERNARY_SCI_MAX_EXP: i64 = 4096;
+
+impl TernarySci {
+    fn parse(s: &str) -> Result<Self, String> {
+        let (neg, rest) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let (mantissa, exp) = match rest.find(['e', 'E']) {
+            Some(i) => {
+                let digits = &rest[i + 1..];
+                let exp = digits
+                    .parse::<i64>()
+                    .map_err(|_| format!("exponent '{}' is not a decimal integer", digits))?;
+                (&rest[..i], exp)
+            }
+            None => (rest, 0),
+        };
+        if exp.abs() > TERNARY_SCI_MAX_EXP {
+            return Err(format!("exponent {} is past ±{}", exp, TERNARY_SCI_MAX_EXP));
+        }
+        let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if int.is_empty() && frac.is_empty() {
+            return Err("the mantissa has no digits".to_string());
+        }
+        let digits = format!("{}{}", int, frac);
+        if let Some(c) = digits.chars().find(|c| !matches!(c, '0'..='2')) {
+            return Err(format!("'{}' is not a ternary digit (0, 1, 2)", c));
+        }
+        // Fold the fraction into the exponent, then drop what adds nothing
+        let mut trits = digits.trim_start_matches('0').to_string();
+        let mut exp = exp - frac.len() as i64;
+        while exp < 0 && trits.ends_with('0') {
+            trits.pop();
+            exp += 1;
+        }
+        Ok(TernarySci {
+            neg: neg && !trits.is_empty(),
+            trits,
+            exp,
+        })
+    }
+
+    /// Base 3 with a radix point where needed; any base for whole numbers;
+    /// base 10 for fractions too, as the nearest f64.
+    fn to_base_string(&self, base: u32) -> Result<String, String> {
+        let sign = if self.neg { "-" } else { "" };
+        if self.trits.is_empty() {
+            return Ok("0".to_string());
+        }
+        if self.exp >= 0 {
+            let whole = format!("{}{}{}", sign, self.trits, "0".repeat(self.exp as usize));
+            return Ok(BigTernary::parse(&whole, 3)?.to_base_string(base));
+        }
+        match base {
+            3 => {
+                let places = self.exp.unsigned_abs() as usize;
+                let padded = format!("{:0>1$}", self.trits, places + 1);
+                let (int, frac) = padded.split_at(padded.len() - places);
+                Ok(format!("{}{}.{}", sign, int, frac))
+            }
+            10 => {
+                let mut m = 0f64;
+                for b in self.trits.bytes() {
+                    m = m * 3.0 + (b - b'0') as f64;
+                }
+                Ok(format!("{}{}", sign, mul_pow3(m, self.exp)))
+            }
+            _ => Err(format!("fractions print in base 3 or 10, not {}", base)),
+        }
+    }
+}
+
+/// Balanced ternary: digits in {-1, 0, +1} rendered as '-', '0', '+'
+/// Negative numbers get a leading '-' to distinguish sign from digit.
+fn int_to_balanced_ternary(n: i64) -> String {
+    if n == 0 {
+        return "0".to_string();
+    }
+
+    let neg = n < 0;
+    // unsigned_abs so i64::MIN has a magnitude too; m / 3 + 1 can't overflow
+    let mut m = n.unsigned_abs();
+
+    let mut digits = Vec::new();
+    while m != 0 {
+        let rem = m % 3;
+        m /= 3;
+        let ch = match rem {
+            0 => '0',
+            1 => '+',
+            _ => {
+                m += 1;
+                '-'
+            }
+        };
+        digits.push(ch);
+    }
+
+    digits.reverse();
+    let mut s: String = digits.into_iter().collect();
+    if neg {
+        s.insert(0, '-');
+    }
+    s
+}
+
+/// One balanced-ternary digit. Ordered -1 < 0 < +1, so `min` and `max`
+/// are the tritwise AND and OR, and `xor` follows from them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Trit {
+    Neg,
+    Zero,
+    Pos,
+}
+
+impl Trit {
+    const ALL: [Trit; 3] = [Trit::Neg, Trit::Zero, Trit::Pos];
+
+    fn to_char(self) -> char {
+        match self {
+            Trit::Neg => '-',
+            Trit::Zero => '0',
+            Trit::Pos => '+',
+        }
+    }
+
+    fn from_char(c: char) -> Option<Trit> {
+        match c {
+            '-' => Some(Trit::Neg),
+            '0' => Some(Trit::Zero),
+            '+' => Some(Trit::Pos),
+            _ => None,
+        }
+    }
+
+    /// Kleene XOR, `(a AND NOT b) OR (NOT a AND b)`: + against -, - when
+    /// both agree on a nonzero trit, 0 when either is 0.
+    fn xor(self, other: Trit) -> Trit {
+        self.min(-other).max((-self).min(other))
+    }
+}
+
+impl std::ops::Neg for Trit {
+    type Output = Trit;
+
+    fn neg(self) -> Trit {
+        match self {
+            Trit::Neg => Trit::Pos,
+            Trit::Zero => Trit::Zero,
+            Trit::Pos => Trit::Neg,
+        }
+    }
+}
+
+impl From<Trit> for i8 {
+    fn from(t: Trit) -> i8 {
+        match t {
+            Trit::Neg => -1,
+            Trit::Zero => 0,
+            Trit::Pos => 1,
+        }
+    }
+}
+
+impl TryFrom<i8> for Trit {
+    type Error = String;
+
+    fn try_from(n: i8) -> Result<Trit, String> {
+        match n {
+            -1 => Ok(Trit::Neg),
+            0 => Ok(Trit::Zero),
+            1 => Ok(Trit::Pos),
+            _ => Err(format!("{} is not a balanced trit", n)),
+        }
+    }
+}
+
+/// Balanced full adder: `a + b + carry` as (sum, carry out), with
+/// sum + 3 × carry out equal to the three inputs added up.
+fn add_with_carry(a: Trit, b: Trit, carry: Trit) -> (Trit, Trit) {
+    match i8::from(a) + i8::from(b) + i8::from(carry) {
+        -3 => (Trit::Zero, Trit::Neg),
+        -2 => (Trit::Pos, Trit::Neg),
+        -1 => (Trit::Neg, Trit::Zero),
+        0 => (Trit::Zero, Trit::Zero),
+        1 => (Trit::Pos, Trit::Zero),
+        2 => (Trit::Neg, Trit::Pos),
+        _ => (Trit::Zero, Trit::Pos),
+    }
+}
+
+/// `a + b` trit by trit, least significant first, rippling the carry
+/// through `add_with_carry`. Never overflows; no trailing zero trits.
+fn balanced_add(a: &[Trit], b: &[Trit]) -> Vec<Trit> {
+    let mut carry = Trit::Zero;
+    let mut sum: Vec<Trit> = (0..a.len().max(b.len()))
+        .map(|i| {
+            let (ta, tb) = (a.get(i), b.get(i));
+            let (s, c) = add_with_carry(
+                *ta.unwrap_or(&Trit::Zero),
+                *tb.unwrap_or(&Trit::Zero),
+                carry,
+            );
+            carry = c;
+            s
+        })
+        .collect();
+    sum.push(carry);
+    while sum.last() == Some(&Trit::Zero) {
+        sum.pop();
+    }
+    sum
+}
+
+/// An operation on balanced trit vectors, least significant trit first.
+type TritOp = fn(&[Trit], &[Trit]) -> Vec<Trit>;
+
+fn balanced_neg(a: &[Trit]) -> Vec<Trit> {
+    a.iter().map(|&t| -t).collect()
+}
+
+fn balanced_sub(a: &[Trit], b: &[Trit]) -> Vec<Trit> {
+    balanced_add(a, &balanced_neg(b))
+}
+
+/// Long multiplication: `a`, `-a` or nothing for each trit of `b`, moved
+/// up to that trit's place, all added up.
+fn balanced_mul(a: &[Trit], b: &[Trit]) -> Vec<Trit> {
+    let mut product = Vec::new();
+    for (place, &t) in b.iter().enumerate() {
+        let addend = match t {
+            Trit::Zero => continue,
+            Trit::Pos => a.to_vec(),
+            Trit::Neg => balanced_neg(a),
+        };
+        let mut shifted = vec![Trit::Zero; place];
+        shifted.extend(addend);
+        product = balanced_add(&product, &shifted);
+    }
+    product
+}
+
+/// Balanced-ternary trits of `n`, least significant first (empty for 0).
+fn balanced_trits(n: i64) -> Vec<Trit> {
+    // Widen so i64::MIN can be taken apart like any other value
+    let mut n = n as i128;
+    let mut trits = Vec::new();
+    while n != 0 {
+        let trit = match n.rem_euclid(3) {
+            0 => Trit::Zero,
+            1 => Trit::Pos,
+            _ => Trit::Neg,
+        };
+        trits.push(trit);
+        n = (n - i8::from(trit) as i128) / 3;
+    }
+    trits
+}
+
+fn from_balanced_trits(trits: &[Trit]) -> Option<i64> {
+    let n = trits
+        .iter()
+        .rev()
+        .fold(0i128, |acc, &t| acc * 3 + i8::from(t) as i128);
+    i64::try_from(n).ok()
+}
+
+/// The glyphs for +1, 0 and -1, in that order, from a spec like `10T` or
+/// `+ 0 -` (whitespace between them is ignored).
+fn parse_glyphs(spec: &str) -> Result<[char; 3], String> {
+    let glyphs: Vec<char> = spec.chars().filter(|c| !c.is_whitespace()).collect();
+    match glyphs[..] {
+        [p, z, n] if p != z && z != n && p != n => Ok([p, z, n]),
+        [_, _, _] => Err(format!("glyphs '{}' repeat a character", spec)),
+        _ => Err(format!(
+            "glyphs '{}' must be exactly three characters, for +1, 0 and -1",
+            spec
+        )),
+    }
+}
+
+/// `n` in balanced ternary, most significant trit first, in `glyphs`.
+/// No sign is needed: a negative number simply leads with -1.
+fn to_glyphs(n: i64, glyphs: [char; 3]) -> String {
+    let [p, z, m] = glyphs;
+    let trits = balanced_trits(n);
+    if trits.is_empty() {
+        return z.to_string();
+    }
+    trits
+        .iter()
+        .rev()
+        .map(|&t| match t {
+            Trit::Pos => p,
+            Trit::Zero => z,
+            Trit::Neg => m,
+        })
+        .collect()
+}
+
+/// Inverse of `to_glyphs`; leading zero glyphs are fine.
+fn from_glyphs(s: &str, glyphs: [char; 3]) -> Result<i64, String> {
+    let [p, z, m] = glyphs;
+    let mut trits = s
+        .chars()
+        .rev()
+        .map(|c| match c {
+            _ if c == p => Ok(Trit::Pos),
+            _ if c == z => Ok(Trit::Zero),
+            _ if c == m => Ok(Trit::Neg),
+            _ => Err(format!("'{}' is not one of the glyphs {}{}{}", c, p, z, m)),
+        })
+        .collect::<Result<Vec<Trit>, String>>()?;
+    if trits.is_empty() {
+        return Err(format!("'{}' has no digits", s));
+    }
+    while trits.last() == Some(&Trit::Zero) {
+        trits.pop();
+    }
+    // 41 balanced trits cover every i64; past that the fold could overflow
+    (trits.len() <= 41)
+        .then(|| from_balanced_trits(&trits))
+        .flatten()
+        .ok_or_else(|| format!("'{}' does not fit in 64 bits", s))
+}
+
+/// Combines `a` and `b` trit by trit; the shorter one is padded with zeros.
+fn tritwise(a: i64, b: i64, op: fn(Trit, Trit) -> Trit) -> Option<i64> {
+    let (ta, tb) = (balanced_trits(a), balanced_trits(b));
+    const ZERO: &Trit = &Trit::Zero;
+    let trits: Vec<Trit> = (0..ta.len().max(tb.len()))
+        .map(|i| op(*ta.get(i).unwrap_or(ZERO), *tb.get(i).unwrap_or(ZERO)))
+        .collect();
+    from_balanced_trits(&trits)
+}
+
+/// SplitMix64 (Steele, Lea and Flood): a 64-bit counter run through a
+/// mixing function. Tiny, fast and fine for test data, not for secrets.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in 0..n; draws above the last whole multiple of n are
+    /// redrawn so small values are not favoured.
+    fn below(&mut self, n: u64) -> u64 {
+        let zone = u64::MAX - u64::MAX % n;
+        loop {
+            let r = self.next_u64();
+            if r < zone {
+                return r % n;
+            }
+        }
+    }
+}
+
+/// Streaming checksum with a plain base-3 digest. The digest depends only
+/// on the bytes fed in, not on how they were split across `update` calls.
+trait TernaryChecksumAlgo {
+    fn update(&mut self, bytes: &[u8]);
+    fn finalize(&self) -> String;
+}
+
+/// The original ternary checksum: `h = (h + byte) * 3` with wrapping i64
+/// arithmetic from h = 0, printed as |h| without padding.
+#[derive(Default)]
+struct TernaryChecksum {
+    h: i64,
+}
+
+impl TernaryChecksumAlgo for TernaryChecksum {
+    fn update(&mut self, bytes: &[u8]) {
+        for &c in bytes {
+            self.h = self.h.wrapping_add(c as i64).wrapping_mul(3);
+        }
+    }
+
+    fn finalize(&self) -> String {
+        // Checksums stay as plain base-3; i64::MIN has no abs and saturates
+        int_to_ternary(self.h.checked_abs().unwrap_or(i64::MAX))
+    }
+}
+
+/// 3^9: the modulus of the short checksums, so each fits nine trits.
+const TRYTE3_MOD: u32 = 19683;
+
+/// Sum of the base-3 digits of every byte (255 → 100110 adds 3), mod 3^9,
+/// printed as nine digits.
+#[derive(Default)]
+struct DigitSumChecksum {
+    sum: u32,
+}
+
+impl TernaryChecksumAlgo for DigitSumChecksum {
+    fn update(&mut self, bytes: &[u8]) {
+        for &c in bytes {
+            let mut m = c as u32;
+            while m > 0 {
+                self.sum = (self.sum + m % 3) % TRYTE3_MOD;
+                m /= 3;
+            }
+        }
+    }
+
+    fn finalize(&self) -> String {
+        format!("{:0>9}", int_to_ternary(self.sum as i64))
+    }
+}
+
+/// CRC over GF(3) with generator x^12 + x^11 + 2x^3 + x + 2. Each byte
+/// enters as its six base-3 digits, most significant first; the register
+/// starts at zero, nothing is appended at the end and the 12 register
+/// trits are printed highest power first.
+#[derive(Default)]
+struct TritCrc {
+    reg: [u8; 12],
+}
+
+impl TritCrc {
+    /// Coefficients of x^11 down to x^0; the x^12 term is implied.
+    const POLY: [u8; 12] = [1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1, 2];
+}
+
+impl TernaryChecksumAlgo for TritCrc {
+    fn update(&mut self, bytes: &[u8]) {
+        for &c in bytes {
+            for shift in (0..6).rev() {
+                let trit = (c as u32 / 3u32.pow(shift) % 3) as u8;
+                let feedback = (self.reg[0] + trit) % 3;
+                self.reg.rotate_left(1);
+                self.reg[11] = 0;
+                for (r, p) in self.reg.iter_mut().zip(Self::POLY) {
+                    *r = (*r + feedback * p) % 3;
+                }
+            }
+        }
+    }
+
+    fn finalize(&self) -> String {
+        self.reg.iter().map(|&t| char::from(b'0' + t)).collect()
+    }
+}
+
+/// Fletcher's checksum with both sums mod 3^9: `a += byte`, `b += a`,
+/// starting from zero. Printed as 18 digits, `b` then `a`.
+#[derive(Default)]
+struct TernaryFletcher {
+    a: u32,
+    b: u32,
+}
+
+impl TernaryChecksumAlgo for TernaryFletcher {
+    fn update(&mut self, bytes: &[u8]) {
+        for &c in bytes {
+            self.a = (self.a + c as u32) % TRYTE3_MOD;
+            self.b = (self.b + self.a) % TRYTE3_MOD;
+        }
+    }
+
+    fn finalize(&self) -> String {
+        format!(
+            "{:0>9}{:0>9}",
+            int_to_ternary(self.b as i64),
+            int_to_ternary(self.a as i64)
+        )
+    }
+}
+
+/// Checksum over every key and value, in key order so the digest does not
+/// depend on how the map happens to iterate.
+fn ternary_checksum(meta: &HashMap<String, String>) -> String {
+    let mut checksum = TernaryChecksum::default();
+    for (k, v) in meta.iter().collect::<BTreeMap<_, _>>() {
+        checksum.update(k.as_bytes());
+        checksum.update(v.as_bytes());
+    }
+    checksum.finalize()
+}
+
+/// The checksum of each key and its value alone, in key order, and a root
+/// checksum over those digests (each followed by a newline), so a changed
+/// value shows up in its own line and in the root only.
+fn ternary_checksum_tree(meta: &HashMap<String, String>) -> (Vec<(String, String)>, String) {
+    let mut root = TernaryChecksum::default();
+    let mut keys = Vec::new();
+    for (k, v) in meta.iter().collect::<BTreeMap<_, _>>() {
+        let mut checksum = TernaryChecksum::default();
+        checksum.update(k.as_bytes());
+        checksum.update(v.as_bytes());
+        let digest = checksum.finalize();
+        root.update(digest.as_bytes());
+        root.update(b"\n");
+        keys.push((k.clone(), digest));
+    }
+    (keys, root.finalize())
+}
+
+/*=====================================================================
+  Continued fractions — best rational approximations
+
+  A decimal such as 0.618 is read exactly, as 618/1000, and expanded
+  with integer arithmetic into [a0; a1, a2, …]: a0 is its floor, and each
+  later term the floor of one over what is left. `--terms N` stops after
+  N terms (a0 counts), or sooner when nothing is left, which for a
+  decimal always happens eventually. The convergents p/q of the first
+  k terms are the best approximations with a denominator up to q; the
+  last one is printed in ternary, its repeating trits in parentheses.
+=====================================================================*/
+
+#[derive(Debug, PartialEq)]
+struct ContinuedFraction {
+    terms: Vec<i64>,
+}
+
+impl ContinuedFraction {
+    /// Up to `max_terms` terms of the decimal `text` (`[-]digits[.digits]`).
+    fn expand(text: &str, max_terms: usize) -> Result<ContinuedFraction, String> {
+        let (mut p, mut q) = parse_decimal_fraction(text)?;
+        let mut terms = Vec::new();
+        while terms.len() < max_terms && q != 0 {
+            let a = p.div_euclid(q);
+            terms.push(a);
+            (p, q) = (q, p - a * q);
+        }
+        Ok(ContinuedFraction { terms })
+    }
+
+    /// `h/k` after each term: h(n) = a(n)·h(n-1) + h(n-2), the same for
+    /// k, from h = 1, 0 and k = 0, 1. They never outgrow the input's own
+    /// numerator and denominator, so i64 holds them.
+    fn convergents(&self) -> Vec<(i64, i64)> {
+        let (mut h, mut k) = ((0, 1), (1, 0));
+        self.terms
+            .iter()
+            .map(|&a| {
+                h = (h.1, a * h.1 + h.0);
+                k = (k.1, a * k.1 + k.0);
+                (h.1, k.1)
+            })
+            .collect()
+    }
+}
+
+impl std::fmt::Display for ContinuedFraction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let rest: Vec<String> = self.terms[1..].iter().map(|a| a.to_string()).collect();
+        if rest.is_empty() {
+            return write!(f, "[{}]", self.terms[0]);
+        }
+        write!(f, "[{}; {}]", self.terms[0], rest.join(", "))
+    }
+}
+
+/// `[-]digits[.digits]` as an exact fraction `p/q` with `q` a power of ten.
+fn parse_decimal_fraction(text: &str) -> Result<(i64, i64), String> {
+    let (neg, unsigned) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let (whole, frac) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+    if whole.is_empty() && frac.is_empty() {
+        return Err("no digits".to_string());
+    }
+    let (mut p, mut q) = (0i64, 1i64);
+    for (i, c) in whole.chars().chain(frac.chars()).enumerate() {
+        let digit = c
+            .to_digit(10)
+            .ok_or_else(|| format!("'{}' is not a decimal digit", c))?;
+        p = p
+            .checked_mul(10)
+            .and_then(|p| p.checked_add(digit as i64))
+            .ok_or("too many digits for 64 bits")?;
+        if i >= whole.len() {
+            q = q.checked_mul(10).ok_or("too many digits for 64 bits")?;
+        }
+    }
+    Ok((if neg { -p } else { p }, q))
+}
+
+/// `p/q` in base 3, with the trits that repeat forever in parentheses
+/// (`3/5` is `0.(1210)`). Stops with `…` after `max_trits` trits.
+fn ternary_fraction(p: i64, q: i64, max_trits: usize) -> String {
+    let (whole, mut rem) = (p.div_euclid(q), p.rem_euclid(q));
+    // A negative value is floor + a positive fraction; show it as -(|p|/q)
+    if p < 0 && rem != 0 {
+        return format!("-{}", ternary_fraction(-p, q, max_trits));
+    }
+    let mut text = int_to_base(whole, 3);
+    if rem == 0 {
+        return text;
+    }
+    text.push('.');
+    // Where each remainder was first seen: its second visit closes the period
+    let mut seen = HashMap::new();
+    let mut trits = String::new();
+    while rem != 0 {
+        if let Some(&start) = seen.get(&rem) {
+            trits.insert(start, '(');
+            trits.push(')');
+            break;
+        }
+        if trits.len() == max_trits {
+            trits.push('…');
+            break;
+        }
+        seen.insert(rem, trits.len());
+        let scaled = rem as i128 * 3;
+        trits.push(char::from(b'0' + (scaled / q as i128) as u8));
+        rem = (scaled % q as i128) as i64;
+    }
+    text + &trits
+}
+
+/*=====================================================================
+  TFloat — balanced-ternary floating point
+
+  An encoding is `e` exponent trits, then `m` mantissa trits, all
+  balanced (- 0 +) and most significant first, written `EEEE:MMMMMMMM`:
+
+    exponent  E  an e-trit balanced integer, |E| <= (3^e - 1) / 2
+    mantissa  M  an m-trit balanced integer, read as the fraction
+                 t0.t1t2…t(m-1), i.e. M / 3^(m-1)
+
+    value = M / 3^(m-1) × 3^E
+
+  There is no sign trit: the sign is that of the leading nonzero trit.
+  Normalized encodings have t0 ≠ 0, which puts the fraction in
+  [0.5, 1.5); all zeros is 0. Below 3^-Emax the exponent stays at -Emax
+  and t0 may be 0 (gradual underflow). Encoding rounds to the nearest
+  mantissa, halves away from zero: at most half of 3^-(m-1) on a
+  fraction of at least 0.5, so a relative error of at most 3^-(m-1).
+=====================================================================*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TFloat {
+    exponent: i64,
+    mantissa: i64,
+    exp_trits: u32,
+    mantissa_trits: u32,
+}
+
+impl TFloat {
+    fn encode(x: f64, mantissa_trits: u32, exp_trits: u32) -> Result<TFloat, String> {
+        if !x.is_finite() {
+            return Err(format!("{} has no ternary float encoding", x));
+        }
+        let mut t = TFloat {
+            exponent: 0,
+            mantissa: 0,
+            exp_trits,
+            mantissa_trits,
+        };
+        if x == 0.0 {
+            return Ok(t);
+        }
+        let (max_m, max_e) = (balanced_max(mantissa_trits), balanced_max(exp_trits));
+        let mantissa_at = |e: i64| mul_pow3(x, mantissa_trits as i64 - 1 - e).round();
+        // x / 3^E in [0.5, 1.5) means E = floor(log3 |x| + log3 2); the
+        // estimate can be one off either way, and rounding can carry over
+        let mut e = (x.abs().log(3.0) + 2f64.log(3.0)).floor() as i64;
+        for _ in 0..4 {
+            let m = mantissa_at(e).abs();
+            if m > max_m as f64 {
+                e += 1;
+            } else if m <= balanced_max(mantissa_trits - 1) as f64 {
+                e -= 1;
+            } else {
+                break;
+            }
+        }
+        if e > max_e {
+            return Err(format!(
+                "{} needs exponent {}, beyond the {} of {} trits",
+                x, e, max_e, exp_trits
+            ));
+        }
+        t.exponent = e.max(-max_e);
+        t.mantissa = mantissa_at(t.exponent) as i64;
+        Ok(t)
+    }
+
+    fn decode(&self) -> f64 {
+        mul_pow3(
+            self.mantissa as f64,
+            self.exponent - (self.mantissa_trits as i64 - 1),
+        )
+    }
+
+    /// Reads `EEEE:MMMMMMMM`; the widths are taken from the two halves.
+    fn parse(code: &str) -> Result<TFloat, String> {
+        let (exp, mantissa) = code
+            .split_once(':')
+            .ok_or_else(|| format!("'{}' is not exponent:mantissa trits", code))?;
+        // Same limits as `tfloat encode --exp/--mantissa`
+        let trits = |part: &str, max: u32| -> Result<(i64, u32), String> {
+            let width = part.chars().count() as u32;
+            if !(1..=max).contains(&width) {
+                return Err(format!("'{}' must have 1 to {} trits", part, max));
+            }
+            let mut trits = Vec::new();
+            for c in part.chars().rev() {
+                let trit = Trit::from_char(c);
+                trits.push(trit.ok_or_else(|| format!("'{}' is not a balanced trit", c))?);
+            }
+            Ok((from_balanced_trits(&trits).unwrap(), width))
+        };
+        let (exponent, exp_trits) = trits(exp, 8)?;
+        let (mantissa, mantissa_trits) = trits(mantissa, 39)?;
+        Ok(TFloat {
+            exponent,
+            mantissa,
+            exp_trits,
+            mantissa_trits,
+        })
+    }
+}
+
+impl std::fmt::Display for TFloat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}",
+            balanced_digits(self.exponent, self.exp_trits),
+            balanced_digits(self.mantissa, self.mantissa_trits)
+        )
+    }
+}
+
+/// Largest magnitude `trits` balanced trits can hold: (3^trits - 1) / 2.
+fn balanced_max(trits: u32) -> i64 {
+    (3i64.pow(trits) - 1) / 2
+}
+
+/// `n` as exactly `width` balanced trits, zero-padded, without a sign.
+fn balanced_digits(n: i64, width: u32) -> String {
+    let mut trits = balanced_trits(n);
+    trits.resize(width as usize, Trit::Zero);
+    trits.iter().rev().map(|t| t.to_char()).collect()
+}
+
+/// `x × 3^k`, in two steps so neither power overflows on its own.
+fn mul_pow3(x: f64, k: i64) -> f64 {
+    let half = (k / 2) as i32;
+    x * 3f64.powi(half) * 3f64.powi(k as i32 - half)
+}
+
+/*=====================================================================
+  Calc — base-3 integer expressions (+ - * / and parentheses),
+  `;`-separated statements and `name = expr` assignments. Each line's
+  result is kept: `$` is the previous one and `$n` the n-th (counting
+  from 1, in decimal).
+
+  Tritwise logic works on the balanced-ternary form of the operands:
+  `a & b` keeps the smaller trit of each pair (ternary AND), `a | b` the
+  larger (ternary OR), `a ^ b` is their Kleene XOR (Trit::xor: `+` where
+  the trits are `+` and `-`, `-` where they are equal and nonzero, `0`
+  where either is `0`) and `~a` flips every trit, which is the same as
+  negation. `&` binds tighter than `^`, `^` than `|`, and all three bind
+  looser than + and -, so `1 & 1 + 1` is `1 & 2`.
+
+  `/` truncates toward zero unless `--div-mode floor` or `euclid` says
+  otherwise; see DivMode.
+
+  `--allow-constants` starts every session with CALC_CONSTANTS defined
+  as ordinary variables, which an assignment can still replace.
+
+  `--implicit-mul` reads two operands side by side as a product, at the
+  precedence of `*`: a number or `)` followed by `(`, and a `)` followed
+  by a number. So `2(11)` is 8 and `(1)(2)` is 2; names never take
+  part, and neither do two numbers (`2 11` is still an error).
+
+  `--input-base 10` (or anything in 2..=10) reads the numbers typed in
+  that base instead of 3; results are printed in ternary all the same.
+  `$n` stays decimal whatever the base.
+
+  `--native-ternary` does `+`, `-`, `*` and negation on balanced trit
+  vectors (balanced_add, balanced_mul) instead of i64, converting only
+  the operands and the result; the answers are the same, overflow
+  included. `/` still divides in i64.
+
+  `name(args, …)` calls one of CALC_FUNCTIONS (`fact(11)` is 24,
+  `gcd(110, 11)` and `lcm(11, 20)` take two or more arguments); calls
+  are operands like any other, and a name not followed by `(` is still
+  a variable.
+
+  `--mod 81` does clock arithmetic: literals, variables and every
+  intermediate result are reduced into 0..81, so nothing overflows and
+  `-1` is 80. `a / b` is `a` times the inverse of `b` modulo 81, and an
+  error when `b` has none (shares a factor with the modulus); the
+  `--div-mode` does not come into it. Tritwise `&` `^` `|` work on the
+  reduced operands and reduce what they give, and so do functions.
+=====================================================================*/
+
+/// `calc --allow-constants`; off by default so the names stay free. Set
+/// once in main, before anything is evaluated.
+static CALC_CONSTANTS_ON: AtomicBool = AtomicBool::new(false);
+
+/// `calc --implicit-mul`, set once in main like CALC_CONSTANTS_ON.
+static CALC_IMPLICIT_MUL: AtomicBool = AtomicBool::new(false);
+
+/// `calc --input-base`: the base number literals are read in.
+static CALC_INPUT_BASE: AtomicU32 = AtomicU32::new(3);
+
+/// `calc --native-ternary`, set once in main like CALC_CONSTANTS_ON.
+static CALC_NATIVE_TERNARY: AtomicBool = AtomicBool::new(false);
+
+/// `calc --mod`: the modulus, when arithmetic wraps. Set once in main.
+static CALC_MODULUS: OnceLock<i64> = OnceLock::new();
+
+/// Fixed point with twelve trits after the point: each value is the
+/// constant × 3^12, rounded, and `fixed_one` is 1.0. So in decimal,
+/// `2 * pi_approx / fixed_one` is 6 and `2 * pi_approx` is 3339142.
+const CALC_CONSTANTS: [(&str, i64); 5] = [
+    ("fixed_one", 531441),
+    ("pi_approx", 1669571),
+    ("e_approx", 1444606),
+    ("phi_approx", 859890),
+    ("sqrt2_approx", 751571),
+];
+
+/// A calc function, given arguments of a count it takes.
+type CalcFn = fn(&[i64]) -> Result<i64, ParseError>;
+
+/// What `name(…)` can call, with how many arguments each takes.
+const CALC_FUNCTIONS: [(&str, std::ops::RangeInclusive<usize>, CalcFn); 3] = [
+    ("fact", 1..=1, calc_fact),
+    ("gcd", 2..=usize::MAX, calc_gcd),
+    ("lcm", 2..=usize::MAX, calc_lcm),
+];
+
+fn calc_fact(args: &[i64]) -> Result<i64, ParseError> {
+    let n = args[0];
+    if n < 0 {
+        let why = format!("fact of a negative number ({})", n);
+        return Err(ParseError::BadArgument(why));
+    }
+    // 20! is the last one that fits, so this gives up after at most 21 steps
+    let step = |acc: i64, k| acc.checked_mul(k).ok_or(ParseError::Overflow);
+    (1..=n).try_fold(1, step)
+}
+
+fn calc_gcd(args: &[i64]) -> Result<i64, ParseError> {
+    args.iter().try_fold(0, |a, &b| gcd(a, b))
+}
+
+fn calc_lcm(args: &[i64]) -> Result<i64, ParseError> {
+    args.iter().try_fold(1, |a, &b| lcm(a, b))
+}
+
+/// Never negative; `gcd(0, 0)` is 0. Only `gcd(i64::MIN, 0)` overflows.
+fn gcd(a: i64, b: i64) -> Result<i64, ParseError> {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    i64::try_from(a).map_err(|_| ParseError::Overflow)
+}
+
+/// Never negative; 0 when either argument is.
+fn lcm(a: i64, b: i64) -> Result<i64, ParseError> {
+    if a == 0 || b == 0 {
+        return Ok(0);
+    }
+    (a / gcd(a, b)?)
+        .checked_mul(b)
+        .and_then(i64::checked_abs)
+        .ok_or(ParseError::Overflow)
+}
+
+/// Looks `name` up in CALC_FUNCTIONS and applies it to `args`.
+fn call_calc_function(name: &str, args: &[i64]) -> Result<i64, ParseError> {
+    let (_, arity, f) = CALC_FUNCTIONS
+        .iter()
+        .find(|(n, _, _)| *n == name)
+        .ok_or_else(|| ParseError::UndefinedFunction(name.to_string()))?;
+    if !arity.contains(&args.len()) {
+        return Err(ParseError::WrongArgumentCount(name.to_string(), args.len()));
+    }
+    f(args)
+}
+
+/// `calc --timeout-ms`: how long one expression may take. Set once in
+/// main; each expression starts its own clock (see `start_calc_clock`).
+static CALC_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+thread_local! {
+    /// When the expression being evaluated runs out of time, if it can.
+    static CALC_DEADLINE: std::cell::Cell<Option<Instant>> = const { std::cell::Cell::new(None) };
+}
+
+fn start_calc_clock() {
+    let deadline = CALC_TIMEOUT.get().map(|t| Instant::now() + *t);
+    CALC_DEADLINE.with(|d| d.set(deadline));
+}
+
+/// Called between statements and at every operator, parsing and
+/// evaluating, so a runaway expression stops soon after its time is up.
+fn check_calc_clock() -> Result<(), ParseError> {
+    match CALC_DEADLINE.with(|d| d.get()) {
+        Some(deadline) if Instant::now() >= deadline => {
+            Err(ParseError::Timeout(*CALC_TIMEOUT.get().unwrap()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// How many levels an expression may nest. Parentheses, unary `-` and `~`,
+/// calls and each further operator of a chain like `1+1+1` are a level
+/// each, and parsing and evaluating recurse once per level, so this keeps
+/// untrusted input well short of running out of stack.
+const CALC_MAX_DEPTH: usize = 256;
+
+thread_local! {
+    /// The level the parser is at in the expression it is reading.
+    static CALC_DEPTH: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Runs `parse` `levels` below `depth`, the level it started at, or fails
+/// if that is too deep; back at `depth` afterwards either way.
+fn parse_at<T>(
+    depth: usize,
+    levels: usize,
+    parse: impl FnOnce() -> Result<T, ParseError>,
+) -> Result<T, ParseError> {
+    if depth + levels > CALC_MAX_DEPTH {
+        return Err(ParseError::TooDeep(CALC_MAX_DEPTH));
+    }
+    CALC_DEPTH.with(|d| d.set(depth + levels));
+    let result = parse();
+    CALC_DEPTH.with(|d| d.set(depth));
+    result
+}
+
+/// `parse_at` one level below the current one.
+fn parse_nested<T>(parse: impl FnOnce() -> Result<T, ParseError>) -> Result<T, ParseError> {
+    parse_at(CALC_DEPTH.with(|d| d.get()), 1, parse)
+}
+
+/// The variables a calc session starts with.
+fn calc_vars() -> HashMap<String, i64> {
+    if !CALC_CONSTANTS_ON.load(Ordering::Relaxed) {
+        return HashMap::new();
+    }
+    CALC_CONSTANTS
+        .iter()
+        .map(|&(name, value)| (name.to_string(), value))
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+enum ParseError {
+    UnexpectedChar(char),
+    /// A number or name where an operator was expected
+    UnexpectedToken(String),
+    /// A digit outside the base that follows it
+    InvalidDigit(char, u32),
+    TrailingOperator(char),
+    UndefinedVariable(String),
+    UndefinedFunction(String),
+    /// A function called with this many arguments, which it does not take
+    WrongArgumentCount(String, usize),
+    /// An argument outside a function's domain, and why
+    BadArgument(String),
+    DivisionByZero,
+    /// `--mod`: a divisor that shares a factor with the modulus
+    NotInvertible(i64, i64),
+    Overflow,
+    /// `--timeout-ms` ran out
+    Timeout(Duration),
+    /// Nested or chained past CALC_MAX_DEPTH levels
+    TooDeep(usize),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::UnexpectedChar('\0') => write!(f, "unexpected end of expression"),
+            ParseError::UnexpectedChar(c) => write!(f, "unexpected character '{}'", c),
+            ParseError::UnexpectedToken(t) => write!(f, "unexpected '{}'", t),
+            ParseError::InvalidDigit(c, 3) => write!(f, "'{}' is not a ternary digit (0, 1, 2)", c),
+            ParseError::InvalidDigit(c, base) => write!(f, "'{}' is not a base-{} digit", c, base),
+            ParseError::TrailingOperator(op) => {
+                write!(f, "operator '{}' is missing its right-hand operand", op)
+            }
+            ParseError::UndefinedVariable(name) if name.starts_with('$') => {
+                write!(f, "no result '{}' in the history yet", name)
+            }
+            ParseError::UndefinedVariable(name) => write!(f, "undefined variable '{}'", name),
+            ParseError::UndefinedFunction(name) => write!(f, "no function '{}'", name),
+            ParseError::WrongArgumentCount(name, n) => {
+                let arity = &CALC_FUNCTIONS.iter().find(|(f, _, _)| f == name).unwrap().1;
+                match (arity.start(), arity.end()) {
+                    (1, 1) => write!(f, "{} takes 1 argument, not {}", name, n),
+                    (min, &usize::MAX) => {
+                        write!(f, "{} takes at least {} arguments, not {}", name, min, n)
+                    }
+                    (min, _) => write!(f, "{} takes {} arguments, not {}", name, min, n),
+                }
+            }
+            ParseError::BadArgument(why) => f.write_str(why),
+            ParseError::DivisionByZero => write!(f, "division by zero"),
+            ParseError::NotInvertible(b, m) => {
+                write!(f, "{} (decimal) has no inverse modulo {}", b, m)
+            }
+            ParseError::Overflow => write!(f, "result does not fit in 64 bits"),
+            ParseError::Timeout(t) => write!(f, "gave up after {} ms", t.as_millis()),
+            ParseError::TooDeep(max) => write!(f, "expression nests more than {} levels", max),
+        }
+    }
+}
+
+/// One result per expression; variables carry over from line to line.
+fn eval_lines(input: &str, div: DivMode) -> Result<Vec<i64>, ParseError> {
+    let exprs = split_calc_input(input);
+    if exprs.is_empty() {
+        return Err(ParseError::UnexpectedChar('\0'));
+    }
+    let mut vars = calc_vars();
+    let mut results = Vec::new();
+    for (_, e) in &exprs {
+        let value = eval_expr(e, &mut vars, div)?;
+        results.push(value);
+        record_result(&mut vars, results.len(), value);
+    }
+    Ok(results)
+}
+
+/// Makes the `n`-th result visible to later expressions as `$` and `$n`.
+fn record_result(vars: &mut HashMap<String, i64>, n: usize, value: i64) {
+    vars.insert("$".to_string(), value);
+    vars.insert(format!("${}", n), value);
+}
+
+/// Drops `#` comments up to the end of their line, then splits the input
+/// into expressions at newlines — except inside parentheses, so a bracketed
+/// expression (and any comments in it) may span several lines. Each comes
+/// with the line it starts on, counting from 1.
+fn split_calc_input(input: &str) -> Vec<(usize, String)> {
+    let mut exprs = Vec::new();
+    let mut current = String::new();
+    let mut start = 1;
+    let mut depth = 0i32;
+    for (i, line) in input.lines().enumerate() {
+        if current.trim().is_empty() {
+            start = i + 1;
+        }
+        let code = line.split('#').next().unwrap_or("");
+        for c in code.chars() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+        }
+        current.push_str(code);
+        current.push(' ');
+        if depth <= 0 {
+            if !current.trim().is_empty() {
+                exprs.push((start, current.clone()));
+            }
+            current.clear();
+            depth = 0;
+        }
+    }
+    if !current.trim().is_empty() {
+        exprs.push((start, current));
+    }
+    exprs
+}
+
+/// Evaluates every statement in order; the result is the last one's value.
+fn eval_expr(
+    input: &str,
+    vars: &mut HashMap<String, i64>,
+    div: DivMode,
+) -> Result<i64, ParseError> {
+    start_calc_clock();
+    let mut last = None;
+    for stmt in input.split(';').filter(|s| !s.trim().is_empty()) {
+        last = Some(eval_statement(stmt, vars, div)?);
+    }
+    last.ok_or(ParseError::UnexpectedChar('\0'))
+}
+
+/// A parsed calc expression. Parsing and evaluation are separate passes so
+/// the tree can be shown with `calc --explain`.
+#[derive(Debug, PartialEq)]
+enum Expr {
+    Num(i64),
+    Var(String),
+    /// `-` or `~` applied to the operand
+    Unary(char, Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+    /// `name(args, …)`, one of CALC_FUNCTIONS
+    Call(String, Vec<Expr>),
+}
+
+fn eval_statement(
+    stmt: &str,
+    vars: &mut HashMap<String, i64>,
+    div: DivMode,
+) -> Result<i64, ParseError> {
+    check_calc_clock()?;
+    let (target, expr) = parse_statement(stmt)?;
+    let value = eval_ast(&expr, vars, div)?;
+    if let Some(name) = target {
+        vars.insert(name, value);
+    }
+    Ok(value)
+}
+
+/// Splits off an optional `name =` target and parses the rest.
+fn parse_statement(stmt: &str) -> Result<(Option<String>, Expr), ParseError> {
+    let tokens = tokenize(stmt)?;
+    let mut pos = 0;
+    let target = match tokens.as_slice() {
+        [Token::Ident(name), Token::Op('='), ..] if !name.starts_with('$') => {
+            pos = 2;
+            expect_operand(&tokens, pos, '=')?;
+            Some(name.clone())
+        }
+        _ => None,
+    };
+    let expr = parse_or(&tokens, &mut pos)?;
+    match tokens.get(pos) {
+        None => Ok((target, expr)),
+        t => Err(unexpected(t)),
+    }
+}
+
+fn eval_ast(expr: &Expr, vars: &HashMap<String, i64>, div: DivMode) -> Result<i64, ParseError> {
+    if let Some(&m) = CALC_MODULUS.get() {
+        return eval_mod(expr, vars, m);
+    }
+    match expr {
+        Expr::Num(n) => Ok(*n),
+        Expr::Var(name) => vars
+            .get(name)
+            .copied()
+            .ok_or_else(|| ParseError::UndefinedVariable(name.clone())),
+        // Flipping every balanced trit negates the value, so `~` is unary minus
+        Expr::Unary(_, operand) => {
+            let n = eval_ast(operand, vars, div)?;
+            if CALC_NATIVE_TERNARY.load(Ordering::Relaxed) {
+                native_ternary(n, 0, |a, _| balanced_neg(a))
+            } else {
+                n.checked_neg()
+            }
+            .ok_or(ParseError::Overflow)
+        }
+        Expr::Binary(op, lhs, rhs) => {
+            check_calc_clock()?;
+            let (a, b) = (eval_ast(lhs, vars, div)?, eval_ast(rhs, vars, div)?);
+            let native = CALC_NATIVE_TERNARY.load(Ordering::Relaxed);
+            match op {
+                '+' if native => native_ternary(a, b, balanced_add),
+                '-' if native => native_ternary(a, b, balanced_sub),
+                '*' if native => native_ternary(a, b, balanced_mul),
+                '+' => a.checked_add(b),
+                '-' => a.checked_sub(b),
+                '*' => a.checked_mul(b),
+                '/' if b == 0 => return Err(ParseError::DivisionByZero),
+                '/' => match div {
+                    DivMode::Trunc => a.checked_div(b),
+                    DivMode::Floor => div_floor(a, b),
+                    DivMode::Euclid => div_euclid(a, b),
+                },
+                '&' => tritwise(a, b, Trit::min),
+                '^' => tritwise(a, b, Trit::xor),
+                _ => tritwise(a, b, Trit::max),
+            }
+            .ok_or(ParseError::Overflow)
+        }
+        Expr::Call(name, args) => {
+            check_calc_clock()?;
+            let values = args
+                .iter()
+                .map(|arg| eval_ast(arg, vars, div))
+                .collect::<Result<Vec<i64>, ParseError>>()?;
+            call_calc_function(name, &values)
+        }
+    }
+}
+
+/// `eval_ast` under `--mod m`: the same tree, every value in `0..m`.
+/// The arithmetic is done in i128, where a product of two residues fits.
+fn eval_mod(expr: &Expr, vars: &HashMap<String, i64>, m: i64) -> Result<i64, ParseError> {
+    let reduce = |n: i128| n.rem_euclid(m as i128) as i64;
+    match expr {
+        Expr::Num(n) => Ok(n.rem_euclid(m)),
+        Expr::Var(name) => vars
+            .get(name)
+            .map(|n| n.rem_euclid(m))
+            .ok_or_else(|| ParseError::UndefinedVariable(name.clone())),
+        Expr::Unary(_, operand) => Ok(reduce(-(eval_mod(operand, vars, m)? as i128))),
+        Expr::Binary(op, lhs, rhs) => {
+            check_calc_clock()?;
+            let (a, b) = (eval_mod(lhs, vars, m)?, eval_mod(rhs, vars, m)?);
+            let (wa, wb) = (a as i128, b as i128);
+            match op {
+                '+' => Ok(reduce(wa + wb)),
+                '-' => Ok(reduce(wa - wb)),
+                '*' => Ok(reduce(wa * wb)),
+                '/' if b == 0 => Err(ParseError::DivisionByZero),
+                '/' => match mod_inverse(b, m) {
+                    Some(inverse) => Ok(reduce(wa * inverse as i128)),
+                    None => Err(ParseError::NotInvertible(b, m)),
+                },
+                '&' => tritwise(a, b, Trit::min)
+                    .map(|n| n.rem_euclid(m))
+                    .ok_or(ParseError::Overflow),
+                '^' => tritwise(a, b, Trit::xor)
+                    .map(|n| n.rem_euclid(m))
+                    .ok_or(ParseError::Overflow),
+                _ => tritwise(a, b, Trit::max)
+                    .map(|n| n.rem_euclid(m))
+                    .ok_or(ParseError::Overflow),
+            }
+        }
+        Expr::Call(name, args) => {
+            check_calc_clock()?;
+            let values = args
+                .iter()
+                .map(|arg| eval_mod(arg, vars, m))
+                .collect::<Result<Vec<i64>, ParseError>>()?;
+            Ok(call_calc_function(name, &values)?.rem_euclid(m))
+        }
+    }
+}
+
+/// The `x` in `0..m` with `b·x ≡ 1 (mod m)`, by the extended Euclidean
+/// algorithm; `None` unless `b` and `m` are coprime.
+fn mod_inverse(b: i64, m: i64) -> Option<i64> {
+    let (mut r0, mut r1) = (m as i128, b.rem_euclid(m) as i128);
+    let (mut t0, mut t1) = (0i128, 1i128);
+    while r1 != 0 {
+        let q = r0 / r1;
+        (r0, r1) = (r1, r0 - q * r1);
+        (t0, t1) = (t1, t0 - q * t1);
+    }
+    (r0 == 1).then(|| t0.rem_euclid(m as i128) as i64)
+}
+
+/// `op` applied to the balanced trits of `a` and `b`; `None` when the
+/// result does not fit in an i64, just like the checked i64 operations.
+fn native_ternary(a: i64, b: i64, op: TritOp) -> Option<i64> {
+    let trits = op(&balanced_trits(a), &balanced_trits(b));
+    // Anything longer than 41 trits is out of range, and could overflow
+    // the i128 that from_balanced_trits adds up in
+    (trits.len() <= 41)
+        .then(|| from_balanced_trits(&trits))
+        .flatten()
+}
+
+/// Quotient rounded toward negative infinity; `None` on overflow
+/// (i64::MIN / -1) or division by zero.
+fn div_floor(a: i64, b: i64) -> Option<i64> {
+    let q = a.checked_div(b)?;
+    // Truncation rounded up exactly when the operands' signs differ
+    if a % b != 0 && (a < 0) != (b < 0) {
+        Some(q - 1)
+    } else {
+        Some(q)
+    }
+}
+
+/// Quotient for which `a - b * q` is in `0..|b|`, so it agrees with
+/// `div_floor` for positive `b` and with its ceiling for negative `b`.
+fn div_euclid(a: i64, b: i64) -> Option<i64> {
+    a.checked_div_euclid(b)
+}
+
+/// Renders every statement as an indented tree, one node per line with the
+/// value that node evaluates to.
+fn explain_lines(input: &str, format: CalcFormat, div: DivMode) -> Result<String, ParseError> {
+    let exprs = split_calc_input(input);
+    if exprs.is_empty() {
+        return Err(ParseError::UnexpectedChar('\0'));
+    }
+    let mut vars = calc_vars();
+    let mut out = String::new();
+    for (i, (_, e)) in exprs.iter().enumerate() {
+        start_calc_clock();
+        let mut value = 0;
+        for stmt in e.split(';').filter(|s| !s.trim().is_empty()) {
+            let (_, expr) = parse_statement(stmt)?;
+            out.push_str(stmt.trim());
+            out.push('\n');
+            explain_node(&expr, &vars, 1, format, div, &mut out)?;
+            value = eval_statement(stmt, &mut vars, div)?;
+        }
+        record_result(&mut vars, i + 1, value);
+    }
+    Ok(out)
+}
+
+fn explain_node(
+    expr: &Expr,
+    vars: &HashMap<String, i64>,
+    depth: usize,
+    format: CalcFormat,
+    div: DivMode,
+    out: &mut String,
+) -> Result<(), ParseError> {
+    let value = format_calc_result(eval_ast(expr, vars, div)?, format);
+    let indent = "  ".repeat(depth);
+    match expr {
+        Expr::Num(_) => out.push_str(&format!("{}{}\n", indent, value)),
+        Expr::Var(name) => out.push_str(&format!("{}{} = {}\n", indent, name, value)),
+        Expr::Unary(op, operand) => {
+            out.push_str(&format!("{}{} = {}\n", indent, op, value));
+            explain_node(operand, vars, depth + 1, format, div, out)?;
+        }
+        Expr::Binary(op, lhs, rhs) => {
+            out.push_str(&format!("{}{} = {}\n", indent, op, value));
+            explain_node(lhs, vars, depth + 1, format, div, out)?;
+            explain_node(rhs, vars, depth + 1, format, div, out)?;
+        }
+        Expr::Call(name, args) => {
+            out.push_str(&format!("{}{} = {}\n", indent, name, value));
+            for arg in args {
+                explain_node(arg, vars, depth + 1, format, div, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    /// Any single-char operator, including `=`
+    Op(char),
+    LParen,
+    RParen,
+    /// Between the arguments of a function call
+    Comma,
+}
+
+/// Splits a statement into tokens. Numbers are read in `--input-base`
+/// (3 unless told otherwise) here, so a digit outside it is reported
+/// before parsing starts.
+fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while let Some(&c) = chars.get(pos) {
+        match c {
+            c if c.is_whitespace() => pos += 1,
+            '(' => {
+                tokens.push(Token::LParen);
+                pos += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                pos += 1;
+            }
+            ',' => {
+                tokens.push(Token::Comma);
+                pos += 1;
+            }
+            '+' | '-' | '*' | '/' | '&' | '^' | '|' | '~' | '=' => {
+                tokens.push(Token::Op(c));
+                pos += 1;
+            }
+            c if c.is_ascii_digit() => tokens.push(Token::Num(lex_number(&chars, &mut pos)?)),
+            // `$` and `$n` name earlier results and are looked up like variables
+            '$' => {
+                let start = pos;
+                pos += 1;
+                while chars.get(pos).is_some_and(|c| c.is_ascii_digit()) {
+                    pos += 1;
+                }
+                tokens.push(Token::Ident(chars[start..pos].iter().collect()));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let start = pos;
+                while chars
+                    .get(pos)
+                    .is_some_and(|c| c.is_alphanumeric() || *c == '_')
+                {
+                    pos += 1;
+                }
+                tokens.push(Token::Ident(chars[start..pos].iter().collect()));
+            }
+            c => return Err(ParseError::UnexpectedChar(c)),
+        }
+    }
+    Ok(tokens)
+}
+
+fn lex_number(chars: &[char], pos: &mut usize) -> Result<i64, ParseError> {
+    let base = CALC_INPUT_BASE.load(Ordering::Relaxed);
+    let mut value = 0i64;
+    while let Some(&c) = chars.get(*pos) {
+        let digit = match c.to_digit(10) {
+            Some(d) if d < base => d as i64,
+            Some(_) => return Err(ParseError::InvalidDigit(c, base)),
+            None => break,
+        };
+        value = value
+            .checked_mul(base as i64)
+            .and_then(|v| v.checked_add(digit))
+            .ok_or(ParseError::Overflow)?;
+        *pos += 1;
+    }
+    Ok(value)
+}
+
+/// The error for finding `token` (or the end of input, for None) where it
+/// does not belong.
+fn unexpected(token: Option<&Token>) -> ParseError {
+    match token {
+        None => ParseError::UnexpectedChar('\0'),
+        Some(Token::Op(c)) => ParseError::UnexpectedChar(*c),
+        Some(Token::LParen) => ParseError::UnexpectedChar('('),
+        Some(Token::RParen) => ParseError::UnexpectedChar(')'),
+        Some(Token::Comma) => ParseError::UnexpectedChar(','),
+        Some(Token::Num(n)) => ParseError::UnexpectedToken(int_to_ternary(*n)),
+        Some(Token::Ident(name)) => ParseError::UnexpectedToken(name.clone()),
+    }
+}
+
+/// An operator followed only by a closing paren or the end of input has
+/// nothing to apply to — name it instead of failing in parse_factor.
+fn expect_operand(tokens: &[Token], pos: usize, op: char) -> Result<(), ParseError> {
+    match tokens.get(pos) {
+        None | Some(Token::RParen) => Err(ParseError::TrailingOperator(op)),
+        _ => Ok(()),
+    }
+}
+
+/// Parses a left-associative chain of `next` operands joined by any of `ops`.
+fn parse_binary(
+    tokens: &[Token],
+    pos: &mut usize,
+    ops: &[char],
+    next: fn(&[Token], &mut usize) -> Result<Expr, ParseError>,
+) -> Result<Expr, ParseError> {
+    let depth = CALC_DEPTH.with(|d| d.get());
+    let mut lhs = next(tokens, pos)?;
+    // Each operator puts the chain so far one level further down the tree
+    let mut links = 0;
+    loop {
+        let op = match tokens.get(*pos) {
+            Some(&Token::Op(op)) if ops.contains(&op) => {
+                *pos += 1;
+                expect_operand(tokens, *pos, op)?;
+                op
+            }
+            _ if ops.contains(&'*') && implicit_mul(tokens, *pos) => '*',
+            _ => break,
+        };
+        check_calc_clock()?;
+        links += 1;
+        let rhs = parse_at(depth, links, || next(tokens, pos))?;
+        lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+    Ok(lhs)
+}
+
+/// Whether `--implicit-mul` puts a `*` in front of `tokens[pos]`. `pos`
+/// is past at least one operand, so there is always a token before it.
+fn implicit_mul(tokens: &[Token], pos: usize) -> bool {
+    CALC_IMPLICIT_MUL.load(Ordering::Relaxed)
+        && matches!(
+            (&tokens[pos - 1], tokens.get(pos)),
+            (Token::Num(_) | Token::RParen, Some(Token::LParen))
+                | (Token::RParen, Some(Token::Num(_)))
+        )
+}
+
+fn parse_or(tokens: &[Token], pos: &mut usize) -> Result<Expr, ParseError> {
+    parse_binary(tokens, pos, &['|'], parse_xor)
+}
+
+fn parse_xor(tokens: &[Token], pos: &mut usize) -> Result<Expr, ParseError> {
+    parse_binary(tokens, pos, &['^'], parse_and)
+}
+
+fn parse_and(tokens: &[Token], pos: &mut usize) -> Result<Expr, ParseError> {
+    parse_binary(tokens, pos, &['&'], parse_expr)
+}
+
+fn parse_expr(tokens: &[Token], pos: &mut usize) -> Result<Expr, ParseError> {
+    parse_binary(tokens, pos, &['+', '-'], parse_term)
+}
+
+fn parse_term(tokens: &[Token], pos: &mut usize) -> Result<Expr, ParseError> {
+    parse_binary(tokens, pos, &['*', '/'], parse_factor)
+}
+
+fn parse_factor(tokens: &[Token], pos: &mut usize) -> Result<Expr, ParseError> {
+    let token = tokens.get(*pos);
+    *pos += 1;
+    match token {
+        Some(Token::LParen) => {
+            let expr = parse_nested(|| parse_or(tokens, pos))?;
+            match tokens.get(*pos) {
+                Some(Token::RParen) => {
+                    *pos += 1;
+                    Ok(expr)
+                }
+                t => Err(unexpected(t)),
+            }
+        }
+        Some(&Token::Op(op @ ('-' | '~'))) => {
+            expect_operand(tokens, *pos, op)?;
+            let operand = parse_nested(|| parse_factor(tokens, pos))?;
+            Ok(Expr::Unary(op, Box::new(operand)))
+        }
+        Some(Token::Num(n)) => Ok(Expr::Num(*n)),
+        Some(Token::Ident(name)) if tokens.get(*pos) == Some(&Token::LParen) => {
+            *pos += 1;
+            let args = parse_nested(|| parse_arguments(tokens, pos))?;
+            Ok(Expr::Call(name.clone(), args))
+        }
+        Some(Token::Ident(name)) => Ok(Expr::Var(name.clone())),
+        t => Err(unexpected(t)),
+    }
+}
+
+/// The comma-separated arguments of a call, after its `(`, up to and
+/// including the `)`. How many a function takes is checked when it runs.
+fn parse_arguments(tokens: &[Token], pos: &mut usize) -> Result<Vec<Expr>, ParseError> {
+    let mut args = Vec::new();
+    if tokens.get(*pos) == Some(&Token::RParen) {
+        *pos += 1;
+        return Ok(args);
+    }
+    loop {
+        args.push(parse_or(tokens, pos)?);
+        match tokens.get(*pos) {
+            Some(Token::Comma) => {
+                *pos += 1;
+                expect_operand(tokens, *pos, ',')?;
+            }
+            Some(Token::RParen) => {
+                *pos += 1;
+                return Ok(args);
+            }
+            t => return Err(unexpected(t)),
+        }
+    }
+}
+
+/*=====================================================================
+  Correct GGUF Parsing — No More Heresy
+=====================================================================*/
+
+const GGUF_MAGIC: u32 = u32::from_le_bytes(*b"GGUF");
+
+trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// The parsers read a few bytes at a time (a length, a type, a u32), so a
+/// file is read through a buffer this big rather than one syscall each.
+const GGUF_READ_BUFFER: usize = 64 * 1024;
+
+/// Opens a GGUF for parsing. `-` streams stdin in a single pass: the
+/// header, metadata and tensor infos come first in the file, so only jumps
+/// to tensor data need to seek, and those are always forward.
+fn open_gguf(path: &str) -> io::Result<Box<dyn ReadSeek>> {
+    if path == "-" {
+        return Ok(Box::new(ForwardOnly {
+            inner: io::stdin().lock(),
+            pos: 0,
+        }));
+    }
+    let file = File::open(path)?;
+    #[cfg(feature = "mmap")]
+    if GGUF_MMAP.load(Ordering::Relaxed) {
+        return map_gguf(&file);
+    }
+    Ok(Box::new(BufReader::with_capacity(GGUF_READ_BUFFER, file)))
+}
+
+/// `gguf --mmap`, set once in main like LOSSY_UTF8.
+static GGUF_MMAP: AtomicBool = AtomicBool::new(false);
+
+/// The whole file as one mapped byte slice behind a Cursor, which the
+/// parsers read like any other ReadSeek; reading tensor data is then a
+/// copy out of the page cache, with no syscall per read or seek.
+#[cfg(feature = "mmap")]
+fn map_gguf(file: &File) -> io::Result<Box<dyn ReadSeek>> {
+    // SAFETY: the map is only read. A file cut short by another process
+    // while mapped would fault, the usual caveat of mmap-based readers.
+    let map = unsafe { memmap2::Mmap::map(file)? };
+    Ok(Box::new(io::Cursor::new(map)))
+}
+
+/// Makes a pipe look seekable by reading ahead and discarding. Seeking to
+/// the end drains the stream, which is how its length is found.
+struct ForwardOnly<R> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R: Read> Read for ForwardOnly<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read> Seek for ForwardOnly<R> {
+    fn seek(&mut self, to: SeekFrom) -> io::Result<u64> {
+        let target = match to {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+            SeekFrom::End(d) => {
+                self.pos += io::copy(&mut self.inner, &mut io::sink())?;
+                self.pos.checked_add_signed(d)
+            }
+        };
+        match target {
+            Some(n) if n >= self.pos => {
+                let skipped = io::copy(&mut (&mut self.inner).take(n - self.pos), &mut io::sink())?;
+                self.pos += skipped;
+                Ok(self.pos)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cannot stream-seek backward",
+            )),
+        }
+    }
+}
+
+/// v1 stored counts, string and array lengths and tensor dimensions as
+/// u32; v2 widened them all to u64 and v3 only added big-endian files,
+/// which read as a byte-swapped version number and stay unsupported.
+const GGUF_SUPPORTED_VERSIONS: [u32; 3] = [1, 2, 3];
+
+/// `gguf --lossy`: decode invalid UTF-8 in strings with U+FFFD instead of
+/// failing. Set once in main, before any file is read.
+static LOSSY_UTF8: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+enum GgufError {
+    Io(io::Error),
+    BadMagic(u32),
+    UnsupportedVersion(u32),
+    UnsupportedType(u32),
+    InvalidUtf8,
+    LengthTooLarge {
+        what: &'static str,
+        len: u64,
+        max: u64,
+    },
+    /// Where in the file an inner error happened, e.g. "metadata key 'x'"
+    Context(String, Box<GgufError>),
+}
+
+impl std::fmt::Display for GgufError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GgufError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                write!(f, "truncated GGUF data")
+            }
+            GgufError::Io(e) => write!(f, "I/O error: {}", e),
+            GgufError::BadMagic(magic) => write!(
+                f,
+                "not a GGUF file: magic 0x{:08x} (expected 0x{:08x})",
+                magic, GGUF_MAGIC
+            ),
+            GgufError::UnsupportedVersion(v) => {
+                write!(f, "unsupported GGUF version {} (supported: 1, 2, 3)", v)
+            }
+            GgufError::UnsupportedType(ty) => write!(f, "unsupported value type {}", ty),
+            GgufError::InvalidUtf8 => {
+                write!(f, "string is not valid UTF-8 (--lossy to decode it anyway)")
+            }
+            GgufError::LengthTooLarge { what, len, max } => {
+                write!(f, "{} {} is out of bounds (max {})", what, len, max)
+            }
+            GgufError::Context(at, e) => write!(f, "{}: {}", at, e),
+        }
+    }
+}
+
+impl std::error::Error for GgufError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GgufError::Io(e) => Some(e),
+            GgufError::Context(_, e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl GgufError {
+    /// Running out of data mid-structure is a damaged file, not an I/O failure.
+    fn category(&self) -> ExitCategory {
+        match self {
+            GgufError::Io(e) if e.kind() != io::ErrorKind::UnexpectedEof => ExitCategory::Io,
+            GgufError::Context(_, e) => e.category(),
+            _ => ExitCategory::Format,
+        }
+    }
+
+    fn exit(self) -> ! {
+        exit_with(self.category(), self)
+    }
+
+    /// The data ended mid-structure, as in a file still being written.
+    fn is_truncated(&self) -> bool {
+        match self {
+            GgufError::Io(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+            GgufError::Context(_, e) => e.is_truncated(),
+            _ => false,
+        }
+    }
+}
+
+impl From<io::Error> for GgufError {
+    fn from(e: io::Error) -> Self {
+        GgufError::Io(e)
+    }
+}
+
+/// Rejects anything that isn't a GGUF we understand before the tensor and
+/// KV counts are trusted for allocation.
+fn parse_header(f: &mut impl Read) -> Result<GgufHeader, GgufError> {
+    let magic = read_u32(f)?;
+    if magic != GGUF_MAGIC {
+        return Err(GgufError::BadMagic(magic));
+    }
+    let version = read_u32(f)?;
+    if !GGUF_SUPPORTED_VERSIONS.contains(&version) {
+        return Err(GgufError::UnsupportedVersion(version));
+    }
+
+    Ok(GgufHeader {
+        magic,
+        version,
+        n_tensors: read_len(f, version)?,
+        n_metadata_kv: read_len(f, version)?,
+    })
+}
+
+fn parse_metadata(
+    f: &mut impl Read,
+    header: &GgufHeader,
+) -> Result<HashMap<String, String>, GgufError> {
+    Ok(metadata_strings(parse_metadata_kvs(f, header)?))
+}
+
+fn metadata_strings(kvs: Vec<GgufKv>) -> HashMap<String, String> {
+    kvs.into_iter()
+        .map(|kv| (kv.key, kv.value.to_string()))
+        .collect()
+}
+
+fn parse_metadata_kvs(f: &mut impl Read, header: &GgufHeader) -> Result<Vec<GgufKv>, GgufError> {
+    match parse_metadata_kvs_lenient(f, header) {
+        (kvs, None) => Ok(kvs),
+        (_, Some(e)) => Err(e),
+    }
+}
+
+/// The pairs read before the first error, and that error if there was one.
+fn parse_metadata_kvs_lenient(
+    f: &mut impl Read,
+    header: &GgufHeader,
+) -> (Vec<GgufKv>, Option<GgufError>) {
+    let mut kvs = Vec::new();
+    for _ in 0..header.n_metadata_kv {
+        match parse_metadata_kv(f, header.version) {
+            Ok(kv) => kvs.push(kv),
+            Err(e) => return (kvs, Some(e)),
+        }
+    }
+    (kvs, None)
+}
+
+fn parse_metadata_kv(f: &mut impl Read, version: u32) -> Result<GgufKv, GgufError> {
+    let key = read_string(f, version)?;
+    let ty = read_u32(f)?;
+    let mut rec = Recorder {
+        inner: &mut *f,
+        buf: Vec::new(),
+    };
+    let value = read_value(&mut rec, ty, version)
+        .map_err(|e| GgufError::Context(format!("metadata key '{}'", key), Box::new(e)))?;
+    let raw = rec.buf;
+    Ok(GgufKv {
+        key,
+        ty,
+        value,
+        raw,
+    })
+}
+
+/// Passes reads through and keeps a copy of every byte.
+struct Recorder<'a, R> {
+    inner: &'a mut R,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> Read for Recorder<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(out)?;
+        self.buf.extend_from_slice(&out[..n]);
+        Ok(n)
+    }
+}
+
+const GGUF_DEFAULT_ALIGNMENT: u64 = 32;
+
+/// `general.alignment` when it is set, else the default of 32. A key of
+/// another type, or 0, is ignored here, so this is for metadata that
+/// `read_alignment_key` has already accepted; `data_section_start` is
+/// the checked way into a file being read.
+fn gguf_alignment(kvs: &[GgufKv]) -> u64 {
+    match read_alignment_key(kvs) {
+        Ok(Some(a)) => a as u64,
+        _ => GGUF_DEFAULT_ALIGNMENT,
+    }
+}
+
+/// `general.alignment`, if set, or why it cannot be used: the spec wants
+/// a UINT32, and a reader that falls back to 32 on anything else, as
+/// gguf_alignment and most runtimes do, looks for the data in the wrong
+/// place. (It should also be a multiple of 8, which `validate` checks.)
+fn read_alignment_key(kvs: &[GgufKv]) -> Result<Option<u32>, String> {
+    let Some(kv) = kvs.iter().find(|kv| kv.key == "general.alignment") else {
+        return Ok(None);
+    };
+    match kv.value {
+        GgufValue::Uint32(0) => Err("general.alignment is 0".to_string()),
+        GgufValue::Uint32(a) => Ok(Some(a)),
+        _ => Err(format!(
+            "general.alignment is {}, not UINT32",
+            gguf_value_type_name(kv.ty)
+        )),
+    }
+}
+
+/// Where the data section of `path` starts: after the tensor infos, which
+/// `f` has just been read past, at the next multiple of the alignment. An
+/// unusable `general.alignment` exits with a format error rather than
+/// reading every tensor from 32-byte boundaries that are not theirs.
+fn data_section_start(path: &str, f: &mut impl Seek, kvs: &[GgufKv]) -> u64 {
+    let alignment = read_alignment_key(kvs)
+        .unwrap_or_else(|e| exit_with(ExitCategory::Format, format!("{}: {}", path, e)));
+    let pos = f
+        .stream_position()
+        .unwrap_or_else(|e| exit_with(ExitCategory::Io, format!("{}: {}", path, e)));
+    align_up(pos, alignment.map_or(GGUF_DEFAULT_ALIGNMENT, u64::from))
+}
+
+fn align_up(pos: u64, alignment: u64) -> u64 {
+    pos.div_ceil(alignment) * alignment
+}
+
+const GGML_MAX_DIMS: usize = 4;
+
+fn parse_tensors(f: &mut impl Read, header: &GgufHeader) -> Result<Vec<GgufTensorInfo>, GgufError> {
+    match parse_tensors_lenient(f, header) {
+        (tensors, None) => Ok(tensors),
+        (_, Some(e)) => Err(e),
+    }
+}
+
+/// The tensor infos read before the first error, and that error if there
+/// was one — what a truncated download still has to say.
+fn parse_tensors_lenient(
+    f: &mut impl Read,
+    header: &GgufHeader,
+) -> (Vec<GgufTensorInfo>, Option<GgufError>) {
+    // The count comes straight from the file; let the Vec grow with real entries
+    let mut vec = Vec::with_capacity(header.n_tensors.min(1024) as usize);
+    for _ in 0..header.n_tensors {
+        match parse_tensor_info(f, header.version) {
+            Ok(t) => vec.push(t),
+            Err(e) => return (vec, Some(e)),
+        }
+    }
+    (vec, None)
+}
+
+fn parse_tensor_info(f: &mut impl Read, version: u32) -> Result<GgufTensorInfo, GgufError> {
+    let name = read_string(f, version)?;
+    let n_dims = read_u32(f)? as usize;
+    if n_dims > GGML_MAX_DIMS {
+        return Err(GgufError::Context(
+            format!("tensor '{}'", name),
+            Box::new(GgufError::LengthTooLarge {
+                what: "dimension count",
+                len: n_dims as u64,
+                max: GGML_MAX_DIMS as u64,
+            }),
+        ));
+    }
+    let mut dims = Vec::with_capacity(n_dims);
+    for _ in 0..n_dims {
+        dims.push(read_len(f, version)?);
+    }
+    let kind = read_u32(f)?;
+    let offset = read_u64(f)?;
+    Ok(GgufTensorInfo {
+        name,
+        dims,
+        kind,
+        offset,
+    })
+}
+
+fn read_string(f: &mut impl Read, version: u32) -> Result<String, GgufError> {
+    let len = read_len(f, version)?;
+    // The length is untrusted: read at most `len` bytes and let the buffer
+    // grow with what is actually there instead of allocating `len` up front.
+    // Coming up short is the data ending early, as in a file still being
+    // written, so it reads as truncated rather than as a bad length.
+    let mut buf = Vec::new();
+    f.by_ref().take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(GgufError::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("string of {} bytes ends after {}", len, buf.len()),
+        )));
+    }
+    match String::from_utf8(buf) {
+        Ok(s) => Ok(s),
+        Err(e) if LOSSY_UTF8.load(Ordering::Relaxed) => {
+            Ok(String::from_utf8_lossy(e.as_bytes()).into_owned())
+        }
+        Err(_) => Err(GgufError::InvalidUtf8),
+    }
+}
+
+fn read_u16(f: &mut impl Read) -> Result<u16, GgufError> {
+    let mut b = [0u8; 2];
+    f.read_exact(&mut b)?;
+    Ok(u16::from_le_bytes(b))
+}
+
+fn read_u32(f: &mut impl Read) -> Result<u32, GgufError> {
+    let mut b = [0u8; 4];
+    f.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_u64(f: &mut impl Read) -> Result<u64, GgufError> {
+    let mut b = [0u8; 8];
+    f.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+/// A count, length or dimension: u32 in GGUF v1, u64 from v2 on.
+fn read_len(f: &mut impl Read, version: u32) -> Result<u64, GgufError> {
+    if version == 1 {
+        Ok(read_u32(f)? as u64)
+    } else {
+        read_u64(f)
+    }
+}
+
+fn read_value(f: &mut impl Read, ty: u32, version: u32) -> Result<GgufValue, GgufError> {
+    let value = match ty {
+        0 => {
+            let mut b = [0u8; 1];
+            f.read_exact(&mut b)?;
+            GgufValue::Uint8(b[0])
+        }
+        1 => {
+            let mut b = [0u8; 1];
+            f.read_exact(&mut b)?;
+            GgufValue::Int8(b[0] as i8)
+        }
+        2 => GgufValue::Uint16(read_u16(f)?),
+        3 => {
+            let v = read_u16(f)?;
+            GgufValue::Int16(v as i16)
+        }
+        4 => GgufValue::Uint32(read_u32(f)?),
+        5 => {
+            let v = read_u32(f)?;
+            GgufValue::Int32(v as i32)
+        }
+        6 => {
+            let mut b = [0u8; 4];
+            f.read_exact(&mut b)?;
+            GgufValue::Float32(f32::from_le_bytes(b))
+        }
+        7 => {
+            let mut b = [0u8; 1];
+            f.read_exact(&mut b)?;
+            GgufValue::Bool(b[0] != 0)
+        }
+        8 => GgufValue::String(read_string(f, version)?),
+        9 => {
+            let elem_ty = read_u32(f)?;
+            let len = read_len(f, version)?;
+            // Every element consumes at least one byte, so a bogus length
+            // hits end-of-data long before memory runs out — as long as we
+            // don't reserve it all up front.
+            let mut arr = Vec::with_capacity(len.min(1024) as usize);
+            for _ in 0..len {
+                arr.push(read_value(f, elem_ty, version)?);
+            }
+            GgufValue::Array(arr)
+        }
+        10 => GgufValue::Uint64(read_u64(f)?),
+        11 => {
+            let v = read_u64(f)?;
+            GgufValue::Int64(v as i64)
+        }
+        12 => {
+            let mut b = [0u8; 8];
+            f.read_exact(&mut b)?;
+            GgufValue::Float64(f64::from_le_bytes(b))
+        }
+        // Unknown types have unknown sizes; carrying on would misread the rest
+        _ => return Err(GgufError::UnsupportedType(ty)),
+    };
+    Ok(value)
+}
+
+fn gguf_value_type_name(ty: u32) -> &'static str {
+    match ty {
+        0 => "UINT8",
+        1 => "INT8",
+        2 => "UINT16",
+        3 => "INT16",
+        4 => "UINT32",
+        5 => "INT32",
+        6 => "FLOAT32",
+        7 => "BOOL",
+        8 => "STRING",
+        9 => "ARRAY",
+        10 => "UINT64",
+        11 => "INT64",
+        12 => "FLOAT64",
+        _ => "UNKNOWN",
+    }
+}
+
+/*=====================================================================
+  GGUF Writing — new header, tensor data copied verbatim
+=====================================================================*/
+
+/// Encodes a command-line value as GGUF value type `ty` (scalars and strings).
+fn encode_value(ty: u32, text: &str) -> Result<Vec<u8>, String> {
+    let bad = || format!("'{}' is not a valid {}", text, gguf_value_type_name(ty));
+    macro_rules! le_bytes {
+        ($t:ty) => {
+            text.parse::<$t>()
+                .map_err(|_| bad())?
+                .to_le_bytes()
+                .to_vec()
+        };
+    }
+    let bytes = match ty {
+        0 => le_bytes!(u8),
+        1 => le_bytes!(i8),
+        2 => le_bytes!(u16),
+        3 => le_bytes!(i16),
+        4 => le_bytes!(u32),
+        5 => le_bytes!(i32),
+        6 => le_bytes!(f32),
+        7 => match text {
+            "true" => vec![1],
+            "false" => vec![0],
+            _ => return Err(bad()),
+        },
+        8 => {
+            let mut b = Vec::new();
+            write_string(&mut b, text).unwrap();
+            b
+        }
+        10 => le_bytes!(u64),
+        11 => le_bytes!(i64),
+        12 => le_bytes!(f64),
+        _ => {
+            return Err(format!(
+                "cannot set a {} value; only scalars and strings",
+                gguf_value_type_name(ty)
+            ))
+        }
+    };
+    Ok(bytes)
+}
+
+fn write_string(w: &mut impl Write, s: &str) -> io::Result<()> {
+    w.write_all(&(s.len() as u64).to_le_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+/// Writes header, metadata and tensor infos and pads to `alignment`; the
+/// tensor data section follows. Tensor offsets are relative to the data
+/// section, so editing metadata only changes the padding.
+fn write_gguf(
+    w: &mut impl Write,
+    version: u32,
+    kvs: &[GgufKv],
+    tensors: &[GgufTensorInfo],
+    alignment: u64,
+) -> io::Result<()> {
+    let mut head = Vec::new();
+    head.extend(GGUF_MAGIC.to_le_bytes());
+    head.extend(version.to_le_bytes());
+    head.extend((tensors.len() as u64).to_le_bytes());
+    head.extend((kvs.len() as u64).to_le_bytes());
+    for kv in kvs {
+        write_string(&mut head, &kv.key)?;
+        head.extend(kv.ty.to_le_bytes());
+        head.extend(&kv.raw);
+    }
+    for t in tensors {
+        write_string(&mut head, &t.name)?;
+        head.extend((t.dims.len() as u32).to_le_bytes());
+        for d in &t.dims {
+            head.extend(d.to_le_bytes());
+        }
+        head.extend(t.kind.to_le_bytes());
+        head.extend(t.offset.to_le_bytes());
+    }
+    head.resize(align_up(head.len() as u64, alignment) as usize, 0);
+    w.write_all(&head)
+}
+
+/*=====================================================================
+  JSON — just enough to read merge templates and to write
+  `--output-format json`, compact or, with `--pretty`, indented.
+
+  What each command writes (absent values are null):
+    gguf tensors   [{name, shape: [n…], type, offset, bytes}]
+    gguf types     [{type, type_id, count}]
+    gguf checksum  {tensors: [{name, checksum}], root}
+    gguf diff      {metadata: [{key, a, b}],
+                    tensors: [{name, a: {shape, type_id, offset}, b}]}
+    gguf export-header
+                   {__metadata__: {key: text},
+                    <tensor name>: {dtype, shape, data_offsets: [begin, end]}}
+    calc           [{ternary, decimal}] ({unsigned, balanced, decimal} with
+                    --show-both-ternary; trits, balanced_trits with
+                    --count-trits)
+    convert        [{decimal, ternary}] (with --batch; keys follow the
+                    bases, base<N> for others, input/output if equal)
+=====================================================================*/
+
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    /// Kept as written so it can go straight to encode_value
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Compact, or with `pretty` one item per line, two spaces a level.
+    fn to_text(&self, pretty: bool) -> String {
+        let mut out = String::new();
+        self.write(&mut out, pretty.then_some(0));
+        out
+    }
+
+    fn write(&self, out: &mut String, depth: Option<usize>) {
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Json::Number(n) => out.push_str(n),
+            Json::String(s) => out.push_str(&json_string(s)),
+            Json::Array(items) => {
+                write_json_list(out, depth, "[]", items.iter().map(|v| (None, v)))
+            }
+            Json::Object(fields) => write_json_list(
+                out,
+                depth,
+                "{}",
+                fields.iter().map(|(k, v)| (Some(k.as_str()), v)),
+            ),
+        }
+    }
+}
+
+/// The items of an array (no keys) or object between the two `brackets`.
+fn write_json_list<'a>(
+    out: &mut String,
+    depth: Option<usize>,
+    brackets: &str,
+    items: impl ExactSizeIterator<Item = (Option<&'a str>, &'a Json)>,
+) {
+    let (open, close) = brackets.split_at(1);
+    let empty = items.len() == 0;
+    out.push_str(open);
+    for (i, (key, value)) in items.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        if let Some(depth) = depth {
+            out.push('\n');
+            out.push_str(&"  ".repeat(depth + 1));
+        }
+        if let Some(key) = key {
+            out.push_str(&json_string(key));
+            out.push_str(if depth.is_some() { ": " } else { ":" });
+        }
+        value.write(out, depth.map(|d| d + 1));
+    }
+    if let (Some(depth), false) = (depth, empty) {
+        out.push('\n');
+        out.push_str(&"  ".repeat(depth));
+    }
+    out.push_str(close);
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::String(s)
+    }
+}
+
+impl From<u32> for Json {
+    fn from(n: u32) -> Json {
+        Json::Number(n.to_string())
+    }
+}
+
+impl From<u64> for Json {
+    fn from(n: u64) -> Json {
+        Json::Number(n.to_string())
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Json {
+        Json::Number(n.to_string())
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Json {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+fn json_shape(dims: &[u64]) -> Json {
+    Json::Array(dims.iter().map(|&d| d.into()).collect())
+}
+
+fn parse_json(text: &str) -> Result<Json, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut pos = 0;
+    let value = parse_json_value(&chars, &mut pos)?;
+    skip_json_whitespace(&chars, &mut pos);
+    if pos < chars.len() {
+        return Err(format!("unexpected '{}' after the JSON value", chars[pos]));
+    }
+    Ok(value)
+}
+
+fn skip_json_whitespace(chars: &[char], pos: &mut usize) {
+    while chars.get(*pos).is_some_and(|c| c.is_whitespace()) {
+        *pos += 1;
+    }
+}
+
+fn parse_json_value(chars: &[char], pos: &mut usize) -> Result<Json, String> {
+    skip_json_whitespace(chars, pos);
+    match chars.get(*pos) {
+        None => Err("unexpected end of JSON".to_string()),
+        Some('{') => {
+            *pos += 1;
+            let mut fields = Vec::new();
+            skip_json_whitespace(chars, pos);
+            if chars.get(*pos) == Some(&'}') {
+                *pos += 1;
+                return Ok(Json::Object(fields));
+            }
+            loop {
+                skip_json_whitespace(chars, pos);
+                let Json::String(key) = parse_json_value(chars, pos)? else {
+                    return Err("object keys must be strings".to_string());
+                };
+                skip_json_whitespace(chars, pos);
+                if chars.get(*pos) != Some(&':') {
+                    return Err(format!("expected ':' after key \"{}\"", key));
+                }
+                *pos += 1;
+                fields.push((key, parse_json_value(chars, pos)?));
+                if end_of_json_list(chars, pos, '}')? {
+                    return Ok(Json::Object(fields));
+                }
+            }
+        }
+        Some('[') => {
+            *pos += 1;
+            let mut items = Vec::new();
+            skip_json_whitespace(chars, pos);
+            if chars.get(*pos) == Some(&']') {
+                *pos += 1;
+                return Ok(Json::Array(items));
+            }
+            loop {
+                items.push(parse_json_value(chars, pos)?);
+                if end_of_json_list(chars, pos, ']')? {
+                    return Ok(Json::Array(items));
+                }
+            }
+        }
+        Some('"') => {
+            *pos += 1;
+            let mut s = String::new();
+            loop {
+                let c = *chars.get(*pos).ok_or("unterminated JSON string")?;
+                *pos += 1;
+                match c {
+                    '"' => return Ok(Json::String(s)),
+                    '\\' => {
+                        let esc = *chars.get(*pos).ok_or("unterminated JSON string")?;
+                        *pos += 1;
+                        s.push(match esc {
+                            'n' => '\n',
+                            't' => '\t',
+                            'r' => '\r',
+                            'b' => '\u{8}',
+                            'f' => '\u{c}',
+                            'u' => {
+                                let hex: String = chars.iter().skip(*pos).take(4).collect();
+                                *pos += 4;
+                                u32::from_str_radix(&hex, 16)
+                                    .ok()
+                                    .and_then(char::from_u32)
+                                    .ok_or_else(|| format!("bad \\u escape '{}'", hex))?
+                            }
+                            c => c,
+                        });
+                    }
+                    c => s.push(c),
+                }
+            }
+        }
+        Some(c) if *c == '-' || c.is_ascii_digit() => {
+            let start = *pos;
+            while chars
+                .get(*pos)
+                .is_some_and(|c| c.is_ascii_digit() || "+-.eE".contains(*c))
+            {
+                *pos += 1;
+            }
+            Ok(Json::Number(chars[start..*pos].iter().collect()))
+        }
+        Some(_) => {
+            for (word, value) in [
+                ("true", Json::Bool(true)),
+                ("false", Json::Bool(false)),
+                ("null", Json::Null),
+            ] {
+                if chars[*pos..].starts_with(&word.chars().collect::<Vec<_>>()) {
+                    *pos += word.len();
+                    return Ok(value);
+                }
+            }
+            Err(format!("unexpected '{}' in JSON", chars[*pos]))
+        }
+    }
+}
+
+/// After a list item: consumes `,` (more to come) or `close` (done).
+fn end_of_json_list(chars: &[char], pos: &mut usize, close: char) -> Result<bool, String> {
+    skip_json_whitespace(chars, pos);
+    match chars.get(*pos) {
+        Some(',') => {
+            *pos += 1;
+            Ok(false)
+        }
+        Some(c) if *c == close => {
+            *pos += 1;
+            Ok(true)
+        }
+        _ => Err(format!("expected ',' or '{}' in JSON", close)),
+    }
+}
+
+/*=====================================================================
+  Parameter Estimation
+=====================================================================*/
+
+fn estimate_parameters(metadata: &HashMap<String, String>, tensors: &[GgufTensorInfo]) -> u64 {
+    metadata
+        .get("general.parameter_count")
+        .and_then(|s| s.parse::<u64>().ok())
+        .or_else(|| {
+            metadata
+                .get("llama.block_count")
+                .and_then(|s| s.parse::<u64>().ok())
+                .map(|b| b * 110_000_000)
+        })
+        .unwrap_or_else(|| {
+            tensors
+                .iter()
+                .filter(|t| t.name.contains(".weight") || t.name.contains(".bias"))
+                .map(|t| t.dims.iter().product::<u64>())
+                .sum()
+        })
+}
+
+/*=====================================================================
+  Floats — half-precision formats widened to f32
+=====================================================================*/
+
+/// IEEE 754 binary16: 1 sign, 5 exponent (bias 15) and 10 fraction bits.
+/// Every f16 is exactly representable as an f32, subnormals included.
+fn f16_to_f32(bits: u16) -> f32 {
+    let exp = ((bits >> 10) & 0x1f) as u32;
+    let frac = (bits & 0x3ff) as u32;
+    let magnitude = match exp {
+        // Zero and subnormals: frac × 2^-24
+        0 => frac as f32 * 2f32.powi(-24),
+        // Infinity, or NaN with the payload kept in the top fraction bits
+        0x1f => f32::from_bits(0x7f80_0000 | frac << 13),
+        _ => f32::from_bits((exp + 127 - 15) << 23 | frac << 13),
+    };
+    if bits & 0x8000 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// bfloat16 is the top half of an f32, so widening is a shift.
+fn bf16_to_f32(bits: u16) -> f32 {
+    f32::from_bits((bits as u32) << 16)
+}
+
+/*=====================================================================
+  Quantization Types & Decoding
+=====================================================================*/
+
+fn gguf_type_name(kind: u32) -> &'static str {
+    match kind {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        6 => "Q5_0",
+        7 => "Q5_1",
+        8 => "Q8_0",
+        9 => "Q8_1",
+        10 => "Q2_K",
+        11 => "Q3_K",
+        12 => "Q4_K",
+        13 => "Q5_K",
+        14 => "Q6_K",
+        15 => "Q8_K",
+        16 => "IQ2_XXS",
+        17 => "IQ2_XS",
+        18 => "IQ3_XXS",
+        19 => "IQ1_S",
+        20 => "IQ4_NL",
+        21 => "IQ3_S",
+        22 => "IQ2_S",
+        23 => "IQ4_XS",
+        24 => "I8",
+        25 => "I16",
+        26 => "I32",
+        27 => "I64",
+        28 => "F64",
+        29 => "IQ1_M",
+        30 => "BF16",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Type name with its id, e.g. `Q4_K (12)`, so unknown types still say
+/// which id the file used.
+fn gguf_type_label(kind: u32) -> String {
+    format!("{} ({})", gguf_type_name(kind), kind)
+}
+
+/// (elements per block, bytes per block) for the ggml tensor types.
+/// Plain types are blocks of one element.
+fn type_size_bytes(kind: u32) -> Option<(u64, u64)> {
+    let size = match kind {
+        0 => (1, 4),      // F32
+        1 => (1, 2),      // F16
+        2 => (32, 18),    // Q4_0: f16 scale + 16 bytes of nibbles
+        3 => (32, 20),    // Q4_1
+        6 => (32, 22),    // Q5_0
+        7 => (32, 24),    // Q5_1
+        8 => (32, 34),    // Q8_0: f16 scale + 32 int8
+        9 => (32, 36),    // Q8_1
+        10 => (256, 84),  // Q2_K
+        11 => (256, 110), // Q3_K
+        12 => (256, 144), // Q4_K
+        13 => (256, 176), // Q5_K
+        14 => (256, 210), // Q6_K
+        15 => (256, 292), // Q8_K
+        16 => (256, 66),  // IQ2_XXS
+        17 => (256, 74),  // IQ2_XS
+        18 => (256, 98),  // IQ3_XXS
+        19 => (256, 50),  // IQ1_S
+        20 => (32, 18),   // IQ4_NL
+        21 => (256, 110), // IQ3_S
+        22 => (256, 82),  // IQ2_S
+        23 => (256, 136), // IQ4_XS
+        24 => (1, 1),     // I8
+        25 => (1, 2),     // I16
+        26 => (1, 4),     // I32
+        27 => (1, 8),     // I64
+        28 => (1, 8),     // F64
+        29 => (256, 56),  // IQ1_M
+        30 => (1, 2),     // BF16
+        _ => return None,
+    };
+    Some(size)
+}
+
+/// Bytes of tensor data, counted in whole blocks for the quantized types.
+/// None for an unknown type, or dimensions too large to have a size in
+/// 64 bits; `unsized_tensor` says which.
+fn tensor_byte_size(t: &GgufTensorInfo) -> Option<u64> {
+    let (block_elems, block_bytes) = type_size_bytes(t.kind)?;
+    let elems = t.dims.iter().try_fold(1u64, |n, &d| n.checked_mul(d))?;
+    elems.div_ceil(block_elems).checked_mul(block_bytes)
+}
+
+/// Why `tensor_byte_size` has nothing for `t`.
+fn unsized_tensor(t: &GgufTensorInfo) -> String {
+    if type_size_bytes(t.kind).is_none() {
+        format!("tensor '{}' has unknown type {}", t.name, t.kind)
+    } else {
+        format!(
+            "tensor '{}' is {}, more bytes than 64 bits can count",
+            t.name,
+            format_shape(&t.dims)
+        )
+    }
+}
+
+/// Where `t`'s data starts in the file. The offset comes from the file
+/// too, so one crafted to run past 2^64 is an error, not a wrapped seek.
+fn tensor_data_start(data_start: u64, t: &GgufTensorInfo) -> Result<u64, String> {
+    data_start
+        .checked_add(t.offset)
+        .ok_or_else(|| format!("tensor '{}' offset {} is past 2^64 bytes", t.name, t.offset))
+}
+
+type DecoderFn = fn(&[u8]) -> GgufValue;
+
+/// How to decode one unit of a tensor's data, and the unit's size in
+/// bytes: a single value for the float types, a whole block (an Array of
+/// its 32 values, the size from type_size_bytes) for Q8_0 and Q4_0. None
+/// for the types no decoder is written for yet.
+fn gguf_type_decoder(kind: u32) -> Option<(usize, DecoderFn)> {
+    let decoder: DecoderFn = match kind {
+        0 => |b| GgufValue::Float32(f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        1 => |b| GgufValue::Float32(f16_to_f32(u16::from_le_bytes([b[0], b[1]]))),
+        30 => |b| GgufValue::Float32(bf16_to_f32(u16::from_le_bytes([b[0], b[1]]))),
+        8 => dequantize_q8_0,
+        2 => dequantize_q4_0,
+        _ => return None,
+    };
+    let (_, unit) = type_size_bytes(kind)?;
+    Some((unit as usize, decoder))
+}
+
+/// A Q8_0 block: an f16 scale `d`, then 32 int8 quants; value i is d × q[i].
+fn dequantize_q8_0(block: &[u8]) -> GgufValue {
+    let d = f16_to_f32(u16::from_le_bytes([block[0], block[1]]));
+    let values = block[2..34]
+        .iter()
+        .map(|&q| GgufValue::Float32(d * q as i8 as f32))
+        .collect();
+    GgufValue::Array(values)
+}
+
+/// A Q4_0 block: an f16 scale `d`, then 16 bytes of 4-bit quants offset
+/// by 8. Byte j holds value j in its low nibble and value j + 16 in its
+/// high one, as ggml packs them.
+fn dequantize_q4_0(block: &[u8]) -> GgufValue {
+    let d = f16_to_f32(u16::from_le_bytes([block[0], block[1]]));
+    let qs = &block[2..18];
+    let nibble = |q: u8| GgufValue::Float32(d * (q as i32 - 8) as f32);
+    let low = qs.iter().map(|&q| nibble(q & 0x0f));
+    let high = qs.iter().map(|&q| nibble(q >> 4));
+    GgufValue::Array(low.chain(high).collect())
+}
+
+/*=====================================================================
+  Tests
+=====================================================================*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A GGUF file put together byte by byte, apart from `write_gguf`, so
+    /// the writers can be checked against it: the pairs in order, then each
+    /// tensor `(name, dims, type, data)` at the next multiple of 32 in the
+    /// data section. Version 1 has 32-bit counts, lengths and dimensions.
+    fn build_test_gguf(
+        version: u32,
+        kvs: &[(&str, GgufValue)],
+        tensors: &[(&str, &[u64], u32, &[u8])],
+    ) -> Vec<u8> {
+        // Laid out at general.alignment when it is a usable value
+        let alignment = kvs
+            .iter()
+            .find_map(|(key, value)| match (*key, value) {
+                ("general.alignment", &GgufValue::Uint32(a)) if a > 0 => Some(a as u64),
+                _ => None,
+            })
+            .unwrap_or(GGUF_DEFAULT_ALIGNMENT);
+        let mut b = Vec::new();
+        b.extend(GGUF_MAGIC.to_le_bytes());
+        b.extend(version.to_le_bytes());
+        put_len(&mut b, version, tensors.len() as u64);
+        put_len(&mut b, version, kvs.len() as u64);
+        for (key, value) in kvs {
+            put_str(&mut b, version, key);
+            b.extend(test_value_type(value).to_le_bytes());
+            put_value(&mut b, version, value);
+        }
+        let mut offset = 0;
+        for (name, dims, kind, data) in tensors {
+            put_str(&mut b, version, name);
+            b.extend((dims.len() as u32).to_le_bytes());
+            for &d in *dims {
+                put_len(&mut b, version, d);
+            }
+            b.extend(kind.to_le_bytes());
+            b.extend((offset as u64).to_le_bytes());
+            offset = align_up((offset + data.len()) as u64, alignment) as usize;
+        }
+        b.resize(align_up(b.len() as u64, alignment) as usize, 0);
+        let data_start = b.len();
+        let mut offset = 0;
+        for (_, _, _, data) in tensors {
+            b.resize(data_start + offset, 0);
+            b.extend(*data);
+            offset = align_up((offset + data.len()) as u64, alignment) as usize;
+        }
+        b
+    }
+
+    fn put_len(b: &mut Vec<u8>, version: u32, n: u64) {
+        if version == 1 {
+            b.extend((n as u32).to_le_bytes());
+        } else {
+            b.extend(n.to_le_bytes());
+        }
+    }
+
+    fn put_str(b: &mut Vec<u8>, version: u32, s: &str) {
+        put_len(b, version, s.len() as u64);
+        b.extend(s.as_bytes());
+    }
+
+    fn test_value_type(value: &GgufValue) -> u32 {
+        match value {
+            GgufValue::Uint8(_) => 0,
+            GgufValue::Int8(_) => 1,
+            GgufValue::Uint16(_) => 2,
+            GgufValue::Int16(_) => 3,
+            GgufValue::Uint32(_) => 4,
+            GgufValue::Int32(_) => 5,
+            GgufValue::Float32(_) => 6,
+            GgufValue::Bool(_) => 7,
+            GgufValue::String(_) => 8,
+            GgufValue::Array(_) => 9,
+            GgufValue::Uint64(_) => 10,
+            GgufValue::Int64(_) => 11,
+            GgufValue::Float64(_) => 12,
+        }
+    }
+
+    fn put_value(b: &mut Vec<u8>, version: u32, value: &GgufValue) {
+        match value {
+            GgufValue::Uint8(x) => b.extend(x.to_le_bytes()),
+            GgufValue::Int8(x) => b.extend(x.to_le_bytes()),
+            GgufValue::Uint16(x) => b.extend(x.to_le_bytes()),
+            GgufValue::Int16(x) => b.extend(x.to_le_bytes()),
+            GgufValue::Uint32(x) => b.extend(x.to_le_bytes()),
+            GgufValue::Int32(x) => b.extend(x.to_le_bytes()),
+            GgufValue::Uint64(x) => b.extend(x.to_le_bytes()),
+            GgufValue::Int64(x) => b.extend(x.to_le_bytes()),
+            GgufValue::Float32(x) => b.extend(x.to_le_bytes()),
+            GgufValue::Float64(x) => b.extend(x.to_le_bytes()),
+            GgufValue::Bool(x) => b.push(*x as u8),
+            GgufValue::String(s) => put_str(b, version, s),
+            GgufValue::Array(items) => {
+                // An empty array is written as one of strings
+                let ty = items.first().map_or(8, test_value_type);
+                b.extend(ty.to_le_bytes());
+                put_len(b, version, items.len() as u64);
+                for item in items {
+                    put_value(b, version, item);
+                }
+            }
+        }
+    }
+
+    fn string(s: &str) -> GgufValue {
+        GgufValue::String(s.to_string())
+    }
+
+    fn f32_bytes(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    /// A path in the temp directory for this test run only.
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir();
+        let file = format!("ternary-tools-{}-{}", std::process::id(), name);
+        dir.join(file).to_string_lossy().into_owned()
+    }
+
+    fn write_temp(name: &str, bytes: &[u8]) -> String {
+        let path = temp_path(name);
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    /// A small model: three pairs, an F32 and an F16 tensor.
+    fn tiny_model() -> Vec<u8> {
+        let half: Vec<u8> = [0x3c00u16, 0xc000]
+            .iter()
+            .flat_map(|h| h.to_le_bytes())
+            .collect();
+        build_test_gguf(
+            3,
+            &[
+                ("general.architecture", string("llama")),
+                ("llama.block_count", GgufValue::Uint32(2)),
+                (
+                    "tokenizer.ggml.tokens",
+                    GgufValue::Array(vec![string("a"), string("bb")]),
+                ),
+            ],
+            &[
+                (
+                    "a.weight",
+                    &[2, 3],
+                    0,
+                    &f32_bytes(&[1.0, -2.0, 0.0, 0.5, 3.0, -1.5]),
+                ),
+                ("b.weight", &[2], 1, &half),
+            ],
+        )
+    }
+
+    #[test]
+    fn builder_output_parses() {
+        let mut f = Cursor::new(tiny_model());
+        let header = parse_header(&mut f).unwrap();
+        let kvs = parse_metadata_kvs(&mut f, &header).unwrap();
+        let tensors = parse_tensors(&mut f, &header).unwrap();
+        assert_eq!((header.n_tensors, header.n_metadata_kv), (2, 3));
+        assert_eq!(kvs[1].key, "llama.block_count");
+        assert_eq!(tensors[1].offset, 32);
+        assert_eq!(check_tensor_layout(&tensors, 32, 64), Ok(()));
+    }
+
+    /// A rewrite that changes nothing gives back the input, byte for byte.
+    #[test]
+    fn golden_pass_through_rewrite() {
+        let golden = tiny_model();
+        let input = write_temp("golden-in.gguf", &golden);
+        let output = temp_path("golden-out.gguf");
+        rewrite_gguf(&input, &output, false, |_, _| Ok(()));
+        assert_eq!(std::fs::read(&output).unwrap(), golden);
+    }
+
+    /// Pairs, tensor infos and each tensor's decoded values.
+    fn load_values(bytes: &[u8]) -> (Vec<GgufKv>, Vec<GgufTensorInfo>, Vec<Vec<f64>>) {
+        let mut f = Cursor::new(bytes);
+        let header = parse_header(&mut f).unwrap();
+        let kvs = parse_metadata_kvs(&mut f, &header).unwrap();
+        let tensors = parse_tensors(&mut f, &header).unwrap();
+        let data_start = align_up(f.position(), gguf_alignment(&kvs));
+        let values = tensors
+            .iter()
+            .map(|t| {
+                let values = tensor_values(&mut f, data_start, t).unwrap();
+                values.collect::<Result<Vec<f64>, String>>().unwrap()
+            })
+            .collect();
+        (kvs, tensors, values)
+    }
+
+    fn kv<'a>(kvs: &'a [GgufKv], key: &str) -> Option<&'a GgufValue> {
+        kvs.iter().find(|kv| kv.key == key).map(|kv| &kv.value)
+    }
+
+    /// Runs `rewrite` from the tiny model to a new file and loads it.
+    fn rewritten(
+        name: &str,
+        rewrite: impl FnOnce(&str, &str),
+    ) -> (Vec<GgufKv>, Vec<GgufTensorInfo>, Vec<Vec<f64>>) {
+        let input = write_temp(&format!("{}-in.gguf", name), &tiny_model());
+        let output = temp_path(&format!("{}-out.gguf", name));
+        rewrite(&input, &output);
+        load_values(&std::fs::read(&output).unwrap())
+    }
+
+    const TINY_A: [f64; 6] = [1.0, -2.0, 0.0, 0.5, 3.0, -1.5];
+    const TINY_B: [f64; 2] = [1.0, -2.0];
+
+    #[test]
+    fn reads_versions_1_to_3_alike() {
+        let kvs = [
+            ("general.architecture", string("llama")),
+            ("counts", GgufValue::Array(vec![GgufValue::Uint32(7)])),
+        ];
+        let data = f32_bytes(&[1.5, -3.0]);
+        for version in 1..=3 {
+            let file = build_test_gguf(version, &kvs, &[("w", &[2, 1], 0, &data)]);
+            let (read, tensors, values) = load_values(&file);
+            let arch = kv(&read, "general.architecture").map(|v| v.to_string());
+            assert_eq!(arch.as_deref(), Some("llama"), "v{}", version);
+            assert_eq!(tensors[0].dims, [2, 1], "v{}", version);
+            assert_eq!(values[0], [1.5, -3.0], "v{}", version);
+        }
+        let mut future = build_test_gguf(3, &[], &[]);
+        future[4] = 4;
+        let e = parse_header(&mut Cursor::new(future)).unwrap_err();
+        assert!(matches!(e, GgufError::UnsupportedVersion(4)));
+    }
+
+    /// 537: a string set to a longer one reads back, and the data follows.
+    #[test]
+    fn set_round_trip() {
+        let (kvs, _, values) = rewritten("set", |input, output| {
+            gguf_set(
+                "general.architecture",
+                "a much longer name",
+                None,
+                input,
+                output,
+                false,
+            )
+        });
+        let arch = kv(&kvs, "general.architecture").map(|v| v.to_string());
+        assert_eq!(arch.as_deref(), Some("a much longer name"));
+        assert_eq!(values, [TINY_A.to_vec(), TINY_B.to_vec()]);
+    }
+
+    /// 568: every offset on the new alignment, recorded, and readable.
+    #[test]
+    fn align_round_trip() {
+        let (kvs, tensors, values) = rewritten("align", |input, output| {
+            gguf_align(input, output, 64, false)
+        });
+        assert!(matches!(
+            kv(&kvs, "general.alignment"),
+            Some(GgufValue::Uint32(64))
+        ));
+        assert!(tensors.iter().all(|t| t.offset % 64 == 0));
+        assert_eq!(check_tensor_layout(&tensors, 64, 128), Ok(()));
+        assert_eq!(values, [TINY_A.to_vec(), TINY_B.to_vec()]);
+    }
+
+    /// 573: a renamed tensor gives the file the builder makes with the
+    /// new name, longer tensor table and all.
+    #[test]
+    fn rename_matches_golden() {
+        let input = write_temp("rename-in.gguf", &tiny_model());
+        let output = temp_path("rename-out.gguf");
+        gguf_rename("a.weight", "blk.0.attn_q.weight", &input, &output, false);
+        let (kvs, _, _) = load_values(&tiny_model());
+        let pairs: Vec<(&str, GgufValue)> = kvs
+            .iter()
+            .map(|kv| (kv.key.as_str(), kv.value.clone()))
+            .collect();
+        let a = f32_bytes(&TINY_A.map(|x| x as f32));
+        let expected = build_test_gguf(
+            3,
+            &pairs,
+            &[
+                ("blk.0.attn_q.weight", &[2, 3], 0, &a),
+                ("b.weight", &[2], 1, &[0x00, 0x3c, 0x00, 0xc0]),
+            ],
+        );
+        assert_eq!(std::fs::read(&output).unwrap(), expected);
+    }
+
+    /// 596: the dropped key is gone and the rest still parses.
+    #[test]
+    fn strip_drops_a_key() {
+        let (kvs, _, values) = rewritten("strip", |input, output| {
+            let keys = ["meta:tokenizer.ggml.tokens".to_string()];
+            gguf_strip(input, output, &keys, false, false)
+        });
+        assert!(kv(&kvs, "tokenizer.ggml.tokens").is_none());
+        assert_eq!(kvs.len(), 2);
+        assert_eq!(values, [TINY_A.to_vec(), TINY_B.to_vec()]);
+    }
+
+    /// 598: reordered tensors take their data with them.
+    #[test]
+    fn reorder_moves_data() {
+        let small = f32_bytes(&[7.0]);
+        let big = f32_bytes(&[1.0, 2.0, 3.0, 4.0]);
+        let model = build_test_gguf(
+            3,
+            &[],
+            &[("z.small", &[1], 0, &small), ("a.big", &[4], 0, &big)],
+        );
+        let input = write_temp("reorder-in.gguf", &model);
+        let output = temp_path("reorder-out.gguf");
+        gguf_reorder(&input, &output, TensorSort::Name, false);
+        let (_, tensors, values) = load_values(&std::fs::read(&output).unwrap());
+        let names: Vec<&str> = tensors.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["a.big", "z.small"]);
+        assert_eq!(values, [vec![1.0, 2.0, 3.0, 4.0], vec![7.0]]);
+        assert_eq!(check_tensor_layout(&tensors, 32, 64), Ok(()));
+    }
+
+    /// 531: length fields far beyond the file are errors, not allocations.
+    #[test]
+    fn absurd_lengths_are_errors() {
+        let mut array = build_test_gguf(3, &[("a", GgufValue::Array(vec![string("x")]))], &[]);
+        // Header, key "a", value type and element type come before the count
+        let count_at = 24 + 8 + 1 + 4 + 4;
+        array[count_at..count_at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut f = Cursor::new(array);
+        let header = parse_header(&mut f).unwrap();
+        let e = parse_metadata_kvs(&mut f, &header).unwrap_err();
+        assert!(e.to_string().starts_with("metadata key 'a'"), "{}", e);
+
+        let mut string_len = build_test_gguf(3, &[("a", string("x"))], &[]);
+        string_len[24..32].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut f = Cursor::new(string_len);
+        let header = parse_header(&mut f).unwrap();
+        assert!(parse_metadata_kvs(&mut f, &header).is_err());
+
+        let mut f = Cursor::new(build_test_gguf(3, &[], &[]));
+        let mut header = parse_header(&mut f).unwrap();
+        header.n_tensors = u64::MAX;
+        assert!(parse_tensors(&mut f, &header).is_err());
+    }
+
+    /// 599: a file cut short anywhere, a metadata string or tensor name
+    /// included, reads as incomplete rather than as a bad file.
+    #[test]
+    fn cut_off_file_is_incomplete() {
+        let model = tiny_model();
+        let path = temp_path("watch.gguf");
+        for cut in 0..model.len() {
+            std::fs::write(&path, &model[..cut]).unwrap();
+            let status = watch_status(&path).unwrap_or_else(|e| panic!("cut at {}: {}", cut, e));
+            assert!(status.contains("incomplete"), "cut at {}: {}", cut, status);
+        }
+        std::fs::write(&path, &model).unwrap();
+        let status = watch_status(&path).unwrap();
+        assert!(status.ends_with("complete"), "{}", status);
+        assert!(!status.contains("incomplete"), "{}", status);
+    }
+
+    /// Calc reads its settings from statics, so every test that evaluates
+    /// takes this lock and leaves the defaults behind, even on failure.
+    static CALC_SETTINGS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    fn with_calc_settings<T>(set: impl FnOnce(), f: impl FnOnce() -> T) -> T {
+        struct Defaults;
+        impl Drop for Defaults {
+            fn drop(&mut self) {
+                CALC_IMPLICIT_MUL.store(false, Ordering::Relaxed);
+                CALC_INPUT_BASE.store(3, Ordering::Relaxed);
+                CALC_NATIVE_TERNARY.store(false, Ordering::Relaxed);
+            }
+        }
+        let _lock = CALC_SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+        let _defaults = Defaults;
+        set();
+        f()
+    }
+
+    fn ev(expr: &str) -> Result<i64, ParseError> {
+        eval_expr(expr, &mut HashMap::new(), DivMode::Trunc)
+    }
+
+    fn calc(expr: &str) -> Result<i64, ParseError> {
+        with_calc_settings(|| {}, || ev(expr))
+    }
+
+    /// 524
+    #[test]
+    fn trailing_operators_are_named() {
+        assert_eq!(calc("12 +"), Err(ParseError::TrailingOperator('+')));
+        assert_eq!(calc("12 *"), Err(ParseError::TrailingOperator('*')));
+        assert_eq!(calc("(12 +)"), Err(ParseError::TrailingOperator('+')));
+        assert_eq!(calc("(12 + 1) * 2"), Ok(12));
+    }
+
+    /// 529: chunk boundaries never change a digest.
+    #[test]
+    fn streaming_checksums_match_one_shot() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let algos = [
+            ChecksumAlgo::Ternary,
+            ChecksumAlgo::DigitSum,
+            ChecksumAlgo::Crc,
+            ChecksumAlgo::Fletcher,
+        ];
+        for algo in algos {
+            let mut one_shot = checksum_state(algo);
+            one_shot.update(&data);
+            for chunk in [1, 7, 4096, 65536] {
+                let mut streamed = checksum_state(algo);
+                data.chunks(chunk).for_each(|c| streamed.update(c));
+                assert_eq!(streamed.finalize(), one_shot.finalize());
+            }
+        }
+    }
+
+    /// 600
+    #[test]
+    fn implicit_multiplication() {
+        assert!(calc("2(11)").is_err());
+        assert!(calc("(1)(2)").is_err());
+        let on = || CALC_IMPLICIT_MUL.store(true, Ordering::Relaxed);
+        let results =
+            with_calc_settings(on, || [ev("2(11)"), ev("(1)(2)"), ev("(2)1"), ev("2 11")]);
+        assert_eq!(results[..3], [Ok(8), Ok(2), Ok(2)]);
+        assert!(results[3].is_err());
+    }
+
+    /// 605: decimal in, ternary out.
+    #[test]
+    fn decimal_input() {
+        let base = |b| move || CALC_INPUT_BASE.store(b, Ordering::Relaxed);
+        let result = with_calc_settings(base(10), || ev("12 * 3 + 6"));
+        assert_eq!(result.map(int_to_ternary).as_deref(), Ok("1120"));
+        assert_eq!(calc("12 * 10 + 20"), Ok(21));
+        let binary = with_calc_settings(base(2), || ev("102"));
+        assert_eq!(binary, Err(ParseError::InvalidDigit('2', 2)));
+    }
+
+    /// 607: trit-vector arithmetic agrees with i64, overflow included.
+    #[test]
+    fn native_ternary_matches_i64() {
+        assert_eq!(selftest_native_ternary(), Ok(()));
+        let mut rng = SplitMix64(81);
+        for _ in 0..200 {
+            let (a, b) = (
+                rng.below(1 << 40) as i64,
+                rng.below(1 << 20) as i64 - (1 << 19),
+            );
+            let expr = format!(
+                "{} * {} - {}",
+                int_to_ternary(a),
+                int_to_ternary(b),
+                int_to_ternary(b)
+            );
+            let native = || CALC_NATIVE_TERNARY.store(true, Ordering::Relaxed);
+            assert_eq!(
+                with_calc_settings(native, || ev(&expr)),
+                calc(&expr),
+                "{}",
+                expr
+            );
+        }
+        assert_eq!(native_ternary(i64::MAX, 2, balanced_mul), None);
+    }
+
+    /// 610
+    #[test]
+    fn clock_arithmetic() {
+        let eval = |s: &str| {
+            let (_, expr) = parse_statement(s).unwrap();
+            with_calc_settings(|| {}, || eval_mod(&expr, &HashMap::new(), 81))
+        };
+        assert_eq!(eval("2222 + 1"), Ok(0));
+        assert_eq!(eval("1 - 2"), Ok(80));
+        assert_eq!(eval("1 / 2"), Ok(41));
+        assert_eq!(eval("1 / 10"), Err(ParseError::NotInvertible(3, 81)));
+    }
+
+    /// 620
+    #[test]
+    fn calc_functions() {
+        assert_eq!(calc("fact(11)"), Ok(24));
+        assert_eq!(calc("fact(202)"), Ok(2432902008176640000));
+        assert_eq!(calc("fact(211)"), Err(ParseError::Overflow));
+        assert_eq!(calc("gcd(110, 11)"), Ok(4));
+        assert_eq!(calc("lcm(11, 20)"), Ok(12));
+        assert_eq!(calc("1 + fact(1 + 2) * 2"), Ok(13));
+        let e = calc("gcd(1)").unwrap_err();
+        assert_eq!(e.to_string(), "gcd takes at least 2 arguments, not 1");
+    }
+
+    /// Runs the test `test` again in a copy of this test binary, with
+    /// TERNARY_TOOLS_CASE set to `case`, for commands that print or exit.
+    fn run_in_child(test: &str, case: &str) -> std::process::Output {
+        std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", test, "--nocapture", "--test-threads=1"])
+            .env("TERNARY_TOOLS_CASE", case)
+            .output()
+            .unwrap()
+    }
+
+    /// The case a `run_in_child` child was started for, with its output
+    /// sent to the file named by `TERNARY_TOOLS_OUT` if the parent gave one.
+    fn child_case() -> Option<String> {
+        let case = std::env::var("TERNARY_TOOLS_CASE").ok()?;
+        if let Ok(path) = std::env::var("TERNARY_TOOLS_OUT") {
+            OUTPUT_FILE.set(File::create(path).unwrap()).unwrap();
+        }
+        Some(case)
+    }
+
+    /// Runs `case` of `test` in a child and returns its exit code and what
+    /// it printed through `outln!`, without the test harness's own lines.
+    fn child_output(test: &str, case: &str) -> (Option<i32>, String) {
+        let out = temp_path(&format!("{}.out", case.replace(['/', '\n'], "-")));
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", test, "--nocapture", "--test-threads=1"])
+            .env("TERNARY_TOOLS_CASE", case)
+            .env("TERNARY_TOOLS_OUT", &out)
+            .stderr(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        let text = std::fs::read_to_string(&out).unwrap();
+        std::fs::remove_file(&out).unwrap();
+        (status.code(), text)
+    }
+
+    /// `child_output`, parsed as JSON.
+    fn child_json(test: &str, case: &str) -> (Option<i32>, Json) {
+        let (code, text) = child_output(test, case);
+        let json = parse_json(&text).unwrap_or_else(|e| panic!("{}: {}\n{}", case, e, text));
+        (code, json)
+    }
+
+    /// `model` with the info of the tensor `name` pointing at `offset`.
+    fn set_tensor_offset(model: &mut [u8], name: &str, offset: u64) {
+        let mut f = Cursor::new(&*model);
+        let header = parse_header(&mut f).unwrap();
+        parse_metadata_kvs(&mut f, &header).unwrap();
+        let at = (0..header.n_tensors)
+            .find_map(|_| {
+                let t = parse_tensor_info(&mut f, header.version).unwrap();
+                (t.name == name).then(|| f.position() as usize - 8)
+            })
+            .unwrap();
+        model[at..at + 8].copy_from_slice(&offset.to_le_bytes());
+    }
+
+    /// 556: one failing command per exit code.
+    #[test]
+    fn exit_codes() {
+        if let Some(case) = child_case() {
+            match case.as_str() {
+                "usage" => run_convert("3", 3, 10, false, false),
+                "io" => gguf_summary(&temp_path("does-not-exist.gguf"), false),
+                "format" => gguf_summary(&write_temp("bad-magic.gguf", b"GGML and more"), false),
+                _ => {}
+            }
+            std::process::exit(0);
+        }
+        for (case, code) in [("usage", 2), ("io", 3), ("format", 4)] {
+            let status = run_in_child("tests::exit_codes", case).status;
+            assert_eq!(status.code(), Some(code), "{}", case);
+        }
+    }
+
+    /// 527: dimensions whose product overflows have no size, and say so.
+    #[test]
+    fn oversized_dimensions() {
+        let tensor = |kind, dims: &[u64]| GgufTensorInfo {
+            name: "w".to_string(),
+            dims: dims.to_vec(),
+            kind,
+            offset: 0,
+        };
+        assert_eq!(tensor_byte_size(&tensor(8, &[64, 2])), Some(2 * 2 * 34));
+        assert_eq!(tensor_byte_size(&tensor(0, &[1 << 32, 1 << 32])), None);
+        assert_eq!(tensor_byte_size(&tensor(0, &[1 << 62])), None);
+        let huge = [tensor(0, &[u64::MAX, 3])];
+        let e = check_tensor_layout(&huge, 32, 64).unwrap_err();
+        assert!(e.contains("more bytes than 64 bits can count"), "{}", e);
+        let mut two = [tensor(0, &[1 << 61]), tensor(0, &[1 << 61])];
+        assert!(pack_tensors(&mut two, &[0, 1], 32).is_err());
+    }
+
+    /// One Q8_0 block with scale 0.5 (f16 0x3800) and quants -16..16.
+    fn q8_0_block() -> Vec<u8> {
+        let mut block = 0x3800u16.to_le_bytes().to_vec();
+        block.extend((-16i8..16).map(|q| q as u8));
+        block
+    }
+
+    /// 615: Q8_0 and Q4_0 decode block by block, 32 values each, and
+    /// compare-data sees no drift where the quants are exact.
+    #[test]
+    fn dequantizes_q8_0_and_q4_0() {
+        let q8 = [q8_0_block(), q8_0_block()].concat();
+        // Q4_0 with scale 2: low nibbles 0..16 (values -8..8), high ones 8
+        let mut q4 = 0x4000u16.to_le_bytes().to_vec();
+        q4.extend((0u8..16).map(|q| q | 0x80));
+        let exact: Vec<f32> = (-16..16).map(|q| q as f32 * 0.5).collect();
+        let model = build_test_gguf(
+            3,
+            &[],
+            &[
+                ("q8", &[64], 8, &q8),
+                ("q4", &[32], 2, &q4),
+                (
+                    "f32",
+                    &[64],
+                    0,
+                    &f32_bytes(&[exact.clone(), exact.clone()].concat()),
+                ),
+            ],
+        );
+        let (_, _, values) = load_values(&model);
+        let expected: Vec<f64> = exact.iter().map(|&x| x as f64).collect();
+        assert_eq!(values[0], [expected.clone(), expected].concat());
+        let low = (-8..8).map(|q| q as f64 * 2.0);
+        assert_eq!(values[1], low.chain([0.0; 16]).collect::<Vec<f64>>());
+
+        let mut f = Cursor::new(&model);
+        let header = parse_header(&mut f).unwrap();
+        parse_metadata_kvs(&mut f, &header).unwrap();
+        let tensors = parse_tensors(&mut f, &header).unwrap();
+        let data_start = align_up(f.position(), 32);
+        let mut g = f.clone();
+        let a = tensor_values(&mut f, data_start, &tensors[2]).unwrap();
+        let b = tensor_values(&mut g, data_start, &tensors[0]).unwrap();
+        let drift = tensor_drift(a, b).unwrap();
+        assert_eq!((drift.count, drift.max_abs), (64, 0.0));
+
+        let q4_k = GgufTensorInfo {
+            kind: 12,
+            ..tensors[0].clone()
+        };
+        let e = tensor_values(&mut f, data_start, &q4_k).err().unwrap();
+        assert_eq!(e, "no value decoder for Q4_K (12) (tensor 'q8')");
+    }
+
+    /// 580: stats over a Q8_0 tensor count values, not bytes.
+    #[test]
+    fn stats_over_q8_0() {
+        let model = build_test_gguf(3, &[], &[("q8", &[32], 8, &q8_0_block())]);
+        let mut f = Cursor::new(&model);
+        let header = parse_header(&mut f).unwrap();
+        parse_metadata_kvs(&mut f, &header).unwrap();
+        let tensors = parse_tensors(&mut f, &header).unwrap();
+        let data_start = align_up(f.position(), 32);
+        let stats = tensor_stats(&mut f, data_start, &tensors[0]).unwrap();
+        assert_eq!((stats.count, stats.zeros), (32, 1));
+        assert_eq!((stats.min, stats.max, stats.sum), (-8.0, 7.5, -8.0));
+    }
+
+    /// 550: show's window counts values, and a block type starts it on a
+    /// block.
+    #[test]
+    fn show_windows_count_values() {
+        if let Ok(case) = std::env::var("TERNARY_TOOLS_CASE") {
+            let blocks = [q8_0_block(), q8_0_block()].concat();
+            let model = build_test_gguf(3, &[], &[("q8", &[64], 8, &blocks)]);
+            let path = write_temp("show.gguf", &model);
+            match case.as_str() {
+                "window" => gguf_show(&path, "q8", 3, 32, false, false, false),
+                "inside" => gguf_show(&path, "q8", 3, 5, false, false, false),
+                "past" => gguf_show(&path, "q8", 3, 64, false, false, false),
+                "wrapped" => {
+                    let mut model = model.clone();
+                    set_tensor_offset(&mut model, "q8", u64::MAX - 8);
+                    let path = write_temp("show-wrapped.gguf", &model);
+                    gguf_show(&path, "q8", 3, 32, false, false, false)
+                }
+                _ => {}
+            }
+            std::process::exit(0);
+        }
+        let window = run_in_child("tests::show_windows_count_values", "window");
+        let lines = String::from_utf8(window.stdout).unwrap();
+        let values: Vec<&str> = lines.lines().filter(|l| l.starts_with(" [")).collect();
+        let expected = [" [32] -8.000000", " [33] -7.500000", " [34] -7.000000"];
+        assert_eq!(values, expected, "{}", lines);
+        let inside = run_in_child("tests::show_windows_count_values", "inside");
+        assert_eq!(inside.status.code(), Some(2));
+        let e = String::from_utf8(inside.stderr).unwrap();
+        assert!(e.contains("offset 5 is inside a Q8_0 block"), "{}", e);
+        let past = run_in_child("tests::show_windows_count_values", "past");
+        assert_eq!(past.status.code(), Some(2));
+        let e = String::from_utf8(past.stderr).unwrap();
+        assert!(
+            e.contains("offset 64 is past the end of tensor 'q8'"),
+            "{}",
+            e
+        );
+        // An offset from the file that overflows is a damaged file
+        let wrapped = run_in_child("tests::show_windows_count_values", "wrapped");
+        assert_eq!(wrapped.status.code(), Some(4));
+        let e = String::from_utf8(wrapped.stderr).unwrap();
+        assert!(e.contains("past 2^64 bytes"), "{}", e);
+    }
+
+    /// 558: batch JSON names each side by its base, in either direction,
+    /// and a bad line is skipped but still fails the run.
+    #[test]
+    fn convert_batch_json() {
+        if let Some(case) = child_case() {
+            let input = write_temp("batch.txt", b"5\n\nx\n-4\n");
+            match case.as_str() {
+                "to" => run_convert_batch(&input, 10, 3, false, false, false, OutputFormat::Json),
+                "from" => {
+                    let input = write_temp("batch3.txt", b"12\n-11\n");
+                    run_convert_batch(&input, 3, 10, false, false, false, OutputFormat::Json)
+                }
+                _ => {}
+            }
+            std::process::exit(0);
+        }
+        let pair = |a: &str, x: &str, b: &str, y: &str| {
+            Json::Object(vec![
+                (a.to_string(), Json::from(x)),
+                (b.to_string(), Json::from(y)),
+            ])
+        };
+        let (code, json) = child_json("tests::convert_batch_json", "to");
+        assert_eq!(code, Some(2));
+        let expected = vec![
+            pair("decimal", "5", "ternary", "12"),
+            pair("decimal", "-4", "ternary", "-11"),
+        ];
+        assert_eq!(json, Json::Array(expected));
+        let (code, json) = child_json("tests::convert_batch_json", "from");
+        assert_eq!(code, Some(0));
+        let expected = vec![
+            pair("ternary", "12", "decimal", "5"),
+            pair("ternary", "-11", "decimal", "-4"),
+        ];
+        assert_eq!(json, Json::Array(expected));
+    }
+
+    /// 560: digit counts across powers of 3 and the i64 limits.
+    #[test]
+    fn ternary_digit_counts() {
+        assert_eq!(ternary_digit_count(0), 1);
+        let mut p = 1i64;
+        for k in 1..40 {
+            assert_eq!(ternary_digit_count(p), k, "3^{}", k - 1);
+            assert_eq!(ternary_digit_count(-p), k, "-3^{}", k - 1);
+            assert_eq!(
+                ternary_digit_count(p - 1),
+                (k - 1).max(1),
+                "3^{} - 1",
+                k - 1
+            );
+            p *= 3;
+        }
+        assert_eq!(ternary_digit_count(i64::MAX), 40);
+        assert_eq!(ternary_digit_count(i64::MIN), 40);
+        assert_eq!(ternary_digit_count(i64::MIN + 1), 40);
+        for n in [i64::MIN, -1, 0, 1, i64::MAX] {
+            let digits = int_to_ternary(n);
+            assert_eq!(ternary_digit_count(n), digits.trim_start_matches('-').len());
+        }
+    }
+
+    /// 564: balanced digits are right up to the i64 limits.
+    #[test]
+    fn balanced_ternary_limits() {
+        let value = |s: &str| {
+            let (sign, digits) = s.strip_prefix('-').map_or((1, s), |rest| (-1, rest));
+            let magnitude = digits.bytes().fold(0i128, |acc, b| {
+                acc * 3 + [-1, 0, 1][b"-0+".iter().position(|&d| d == b).unwrap()]
+            });
+            sign * magnitude
+        };
+        for n in [i64::MIN, i64::MIN + 1, -5, -1, 0, 1, 5, i64::MAX] {
+            assert_eq!(value(&int_to_balanced_ternary(n)), n as i128, "{}", n);
+        }
+        assert_eq!(int_to_balanced_ternary(5), "+--");
+        assert_eq!(int_to_balanced_ternary(-5), "-+--");
+    }
+
+    /// 564: with --show-both-ternary both forms are in the text and JSON.
+    #[test]
+    fn calc_shows_both_ternary() {
+        let format = CalcFormat {
+            decimal: false,
+            group: false,
+            both_ternary: true,
+            count_trits: false,
+        };
+        if let Some(case) = child_case() {
+            if case == "json" {
+                run_calc(
+                    Some("12".to_string()),
+                    None,
+                    format,
+                    OutputFormat::Json,
+                    DivMode::Trunc,
+                    false,
+                    false,
+                );
+            }
+            std::process::exit(0);
+        }
+        assert_eq!(format_calc_result(5, format), "unsigned=12 balanced=+--");
+        let (code, json) = child_json("tests::calc_shows_both_ternary", "json");
+        assert_eq!(code, Some(0));
+        let expected = json_object! {
+            "unsigned": "12",
+            "balanced": "+--",
+            "decimal": 5i64,
+        };
+        assert_eq!(json, Json::Array(vec![expected]));
+    }
+
+    /// 571: selftest passes, or with `selftest-inject-failure` catches the
+    /// wrong check value and exits 1.
+    #[test]
+    fn selftest_result() {
+        if child_case().is_some() {
+            run_selftest();
+            std::process::exit(0);
+        }
+        let out = run_in_child("tests::selftest_result", "run");
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        let total = SELFTESTS.len();
+        if cfg!(feature = "selftest-inject-failure") {
+            assert_eq!(out.status.code(), Some(1));
+            assert!(
+                stdout.contains("FAIL checksum check values: ternary"),
+                "{}",
+                stdout
+            );
+            assert!(stdout.contains(&format!("{} passed, 1 failed", total - 1)));
+        } else {
+            assert_eq!(out.status.code(), Some(0));
+            assert!(
+                stdout.contains(&format!("{} passed, 0 failed", total)),
+                "{}",
+                stdout
+            );
+        }
+    }
+
+    /// 574: --quiet leaves stderr empty where the REPL prompt and the
+    /// --verbose rewrite notes would go; results still reach stdout.
+    #[test]
+    fn quiet_and_verbose_stderr() {
+        if let Some(case) = child_case() {
+            let verbosity = match case.as_str() {
+                "quiet" => Verbosity::Quiet,
+                "verbose" => Verbosity::Verbose,
+                _ => Verbosity::Normal,
+            };
+            VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+            let input = write_temp("verbosity-in.gguf", &tiny_model());
+            let output = temp_path("verbosity-out.gguf");
+            gguf_set("general.name", "x", None, &input, &output, false);
+            let format = CalcFormat {
+                decimal: false,
+                group: false,
+                both_ternary: false,
+                count_trits: false,
+            };
+            let prompt = stderr_shows(Verbosity::Normal);
+            calc_repl(
+                Cursor::new("1 + 1\n"),
+                &mut Output,
+                format,
+                DivMode::Trunc,
+                prompt,
+            )
+            .unwrap();
+            std::process::exit(0);
+        }
+        let stderr = |case| {
+            let out = run_in_child("tests::quiet_and_verbose_stderr", case);
+            assert_eq!(out.status.code(), Some(0), "{}", case);
+            let stdout = String::from_utf8_lossy(&out.stdout);
+            assert!(
+                stdout.contains("general.name = x") && stdout.contains("\n2\n"),
+                "{}",
+                case
+            );
+            String::from_utf8_lossy(&out.stderr).into_owned()
+        };
+        assert_eq!(stderr("quiet"), "");
+        assert_eq!(stderr("normal"), "> > \n");
+        let verbose = stderr("verbose");
+        assert!(
+            verbose.contains("3 metadata pairs, 2 tensors"),
+            "{}",
+            verbose
+        );
+        assert!(verbose.contains("tensor data copied as is"), "{}", verbose);
+    }
+
+    /// Every string and object key anywhere in `json`.
+    fn json_strings(json: &Json) -> Vec<&str> {
+        match json {
+            Json::String(s) => vec![s.as_str()],
+            Json::Array(items) => items.iter().flat_map(json_strings).collect(),
+            Json::Object(fields) => fields
+                .iter()
+                .flat_map(|(k, v)| std::iter::once(k.as_str()).chain(json_strings(v)))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// 601: every command's JSON parses back, quotes and backslashes in
+    /// metadata and tensor names included.
+    #[test]
+    fn every_json_output_parses() {
+        const QUOTED: &str = "say \"hi\" \\ bye";
+        const TENSOR: &str = "w\"1";
+        if let Some(case) = child_case() {
+            let model = build_test_gguf(
+                3,
+                &[("general.name", string(QUOTED))],
+                &[(TENSOR, &[2], 0, &f32_bytes(&[1.0, 2.0]))],
+            );
+            let path = write_temp("json.gguf", &model);
+            let json = OutputFormat::Json;
+            match case.as_str() {
+                "tensors" => gguf_tensors(&path, TensorSort::File, None, json),
+                "types" => gguf_types(&path, json),
+                "checksum" => gguf_checksum(&path, ChecksumAlgo::Ternary, json),
+                "diff" => gguf_diff(&path, &write_temp("json-b.gguf", &tiny_model()), json),
+                "export-header" => gguf_export_header(&path, None),
+                "convert" => {
+                    let numbers = write_temp("json.txt", b"5\n-4\n");
+                    run_convert_batch(&numbers, 10, 3, false, false, false, json)
+                }
+                "calc" => {
+                    let format = CalcFormat {
+                        decimal: true,
+                        group: true,
+                        both_ternary: true,
+                        count_trits: true,
+                    };
+                    let expr = Some("1000".to_string());
+                    run_calc(expr, None, format, json, DivMode::Trunc, false, false)
+                }
+                _ => {}
+            }
+            std::process::exit(0);
+        }
+        let test = "tests::every_json_output_parses";
+        for case in ["tensors", "types", "checksum", "convert", "calc"] {
+            let (code, _) = child_json(test, case);
+            assert_eq!(code, Some(0), "{}", case);
+        }
+        for case in ["diff", "export-header"] {
+            let (_, json) = child_json(test, case);
+            let strings = json_strings(&json);
+            assert!(strings.contains(&QUOTED), "{}: {:?}", case, strings);
+            assert!(strings.contains(&TENSOR), "{}: {:?}", case, strings);
+        }
+        let (_, json) = child_json(test, "tensors");
+        assert!(json_strings(&json).contains(&TENSOR));
+    }
+
+    /// 602: `export-header --out` writes a SafeTensors-style header for
+    /// the tiny model to the file.
+    #[test]
+    fn export_header_out() {
+        let input = write_temp("export-in.gguf", &tiny_model());
+        let out = temp_path("export-header.json");
+        gguf_export_header(&input, Some(&out));
+        let json = parse_json(&std::fs::read_to_string(&out).unwrap()).unwrap();
+        let Json::Object(fields) = json else {
+            panic!("not an object: {:?}", json);
+        };
+        let names: Vec<&str> = fields.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(names, ["__metadata__", "a.weight", "b.weight"]);
+        let Json::Object(metadata) = &fields[0].1 else {
+            panic!("__metadata__ is {:?}", fields[0].1);
+        };
+        assert!(metadata.contains(&("general.architecture".to_string(), "llama".into())));
+        let a = json_object! {
+            "dtype": "F32",
+            "shape": Json::Array(vec![3u64.into(), 2u64.into()]),
+            "data_offsets": Json::Array(vec![0u64.into(), 24u64.into()]),
+        };
+        assert_eq!(fields[1].1, a);
+        let b = json_object! {
+            "dtype": "F16",
+            "shape": Json::Array(vec![2u64.into()]),
+            "data_offsets": Json::Array(vec![32u64.into(), 36u64.into()]),
+        };
+        assert_eq!(fields[2].1, b);
+    }
+
+    /// 537: the input is recognised however the output path spells it, and
+    /// a failed rewrite leaves no output behind.
+    #[test]
+    fn rewrite_protects_the_input() {
+        let input = write_temp("same-in.gguf", &tiny_model());
+        let path = std::path::Path::new(&input);
+        let dir = path.parent().unwrap();
+        let dotted = dir.join(".").join(path.file_name().unwrap());
+        assert!(same_file(&input, &dotted.to_string_lossy()));
+        let link = temp_path("same-link.gguf");
+        let _ = std::fs::remove_file(&link);
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&input, &link).unwrap();
+            assert!(same_file(&input, &link));
+            std::fs::remove_file(&link).unwrap();
+            std::fs::hard_link(&input, &link).unwrap();
+            assert!(same_file(&input, &link));
+            std::fs::remove_file(&link).unwrap();
+        }
+        assert!(!same_file(&input, &link));
+        let other = write_temp("same-other.gguf", &tiny_model());
+        assert!(!same_file(&input, &other));
+
+        // The child's temp paths would differ, so the case carries them
+        if let Some(case) = child_case() {
+            let args: Vec<&str> = case.split('\n').collect();
+            match args[..] {
+                ["over", input, output] => {
+                    gguf_set("general.name", "x", None, input, output, false)
+                }
+                ["cut", input, output] => gguf_align(input, output, 64, false),
+                _ => {}
+            }
+            std::process::exit(0);
+        }
+        let test = "tests::rewrite_protects_the_input";
+        let case = format!("over\n{}\n{}", input, dotted.to_string_lossy());
+        assert_eq!(run_in_child(test, &case).status.code(), Some(2));
+        assert_eq!(std::fs::read(&input).unwrap(), tiny_model());
+
+        // b.weight moves to 64 and its data is cut short, so the copy fails
+        let model = tiny_model();
+        let cut = write_temp("partial-in.gguf", &model[..model.len() - 2]);
+        let output = temp_path("partial-out.gguf");
+        let case = format!("cut\n{}\n{}", cut, output);
+        assert_eq!(run_in_child(test, &case).status.code(), Some(3));
+        let name = std::path::Path::new(&output).file_name().unwrap();
+        let left: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| {
+                e.file_name()
+                    .to_string_lossy()
+                    .starts_with(&*name.to_string_lossy())
+            })
+            .collect();
+        assert!(left.is_empty(), "{:?}", left);
+    }
+
+    /// 594: input nested or chained too deep is an error, not a crash.
+    #[test]
+    fn deep_expressions_are_refused() {
+        // A debug build's frames need more than the 2 MiB a test thread
+        // gets to reach the limit; give it what a main thread has
+        let check = move || {
+            let deep = || Err(ParseError::TooDeep(CALC_MAX_DEPTH));
+            let parens = |n| "(".repeat(n) + "1" + &")".repeat(n);
+            assert_eq!(calc(&parens(200_000)), deep());
+            assert_eq!(calc(&("-".repeat(200_000) + "1")), deep());
+            assert_eq!(calc(&("~".repeat(200_000) + "1")), deep());
+            assert_eq!(calc(&("fact(".repeat(200_000) + "1")), deep());
+            assert_eq!(calc(&("1+".repeat(200_000) + "1")), deep());
+            assert_eq!(calc(&parens(CALC_MAX_DEPTH)), Ok(1));
+            assert_eq!(calc(&("1+".repeat(CALC_MAX_DEPTH) + "1")), Ok(257));
+            // Parens, unary operators and chains mixed, about 100 levels
+            let mut nested = "1".to_string();
+            for _ in 0..20 {
+                nested = format!("-(~({}) * 1 + 0)", nested);
+            }
+            assert_eq!(calc(&nested), Ok(1));
+        };
+        let thread = std::thread::Builder::new().stack_size(8 << 20);
+        thread.spawn(check).unwrap().join().unwrap();
+    }
+
+    /// 594: an expression still running when --timeout-ms is up stops
+    /// with Timeout. The limit is set once per process, so in a child.
+    #[test]
+    fn slow_expression_times_out() {
+        if child_case().is_some() {
+            CALC_TIMEOUT.set(Duration::from_millis(1)).unwrap();
+            let slow = "gcd(2, 12, 21);".repeat(1_000_000);
+            let timeout = Err(ParseError::Timeout(Duration::from_millis(1)));
+            std::process::exit(if calc(&slow) == timeout { 0 } else { 1 });
+        }
+        let out = run_in_child("tests::slow_expression_times_out", "run");
+        assert_eq!(out.status.code(), Some(0));
+    }
+
+    /// 621: the dump of a known tensor starts with its bytes and says
+    /// where they are, and stops at the tensor's end.
+    #[test]
+    fn hexdump_of_a_tensor() {
+        if let Some(case) = child_case() {
+            let mut model = tiny_model();
+            if case == "wrapped" {
+                set_tensor_offset(&mut model, "b.weight", u64::MAX - 8);
+            }
+            let path = write_temp("hexdump.gguf", &model);
+            gguf_hexdump(&path, "b.weight", 64);
+            std::process::exit(0);
+        }
+        let (code, out) = child_output("tests::hexdump_of_a_tensor", "dump");
+        assert_eq!(code, Some(0));
+        let mut f = Cursor::new(tiny_model());
+        let header = parse_header(&mut f).unwrap();
+        parse_metadata_kvs(&mut f, &header).unwrap();
+        parse_tensors(&mut f, &header).unwrap();
+        let at = align_up(f.position(), 32) + 32;
+        let lines: Vec<&str> = out.lines().collect();
+        let header_line = format!(
+            "Tensor : b.weight | Type : F16 (1) | Data at byte {} of the file",
+            at
+        );
+        // 1.0 and -2.0 as little-endian halves; 0x3c is 2020 in ternary
+        let dump = "00000000  00 3c 00 c0              |.<..    |  000000 002020 000000 021010";
+        assert_eq!(lines, [header_line.as_str(), dump]);
+        let (code, _) = child_output("tests::hexdump_of_a_tensor", "wrapped");
+        assert_eq!(code, Some(4));
+    }
+
+    /// 587: per-tensor checksums cover exactly each tensor's bytes, so a
+    /// flipped byte changes that tensor's digest and the root only.
+    #[test]
+    fn checksums_per_tensor() {
+        let flipped = || {
+            let mut model = tiny_model();
+            let last = model.len() - 1;
+            model[last] ^= 1;
+            model
+        };
+        if let Some(case) = child_case() {
+            let model = match case.as_str() {
+                "flipped" => flipped(),
+                "wrapped" => {
+                    let mut model = tiny_model();
+                    set_tensor_offset(&mut model, "a.weight", u64::MAX - 8);
+                    model
+                }
+                _ => tiny_model(),
+            };
+            let path = write_temp("checksum.gguf", &model);
+            gguf_checksum(&path, ChecksumAlgo::Ternary, OutputFormat::Json);
+            std::process::exit(0);
+        }
+        let digest = |bytes: &[u8]| {
+            let mut checksum = checksum_state(ChecksumAlgo::Ternary);
+            checksum.update(bytes);
+            checksum.finalize()
+        };
+        let a = f32_bytes(&TINY_A.map(|x| x as f32));
+        let b = [0x00u8, 0x3c, 0x00, 0xc0];
+        let expected = |b: &[u8]| {
+            let (a, b) = (digest(&a), digest(b));
+            let root = digest(format!("{}\n{}\n", a, b).as_bytes());
+            json_object! {
+                "tensors": Json::Array(vec![
+                    json_object! { "name": "a.weight", "checksum": a },
+                    json_object! { "name": "b.weight", "checksum": b },
+                ]),
+                "root": root,
+            }
+        };
+        let test = "tests::checksums_per_tensor";
+        assert_eq!(child_json(test, "plain"), (Some(0), expected(&b)));
+        assert_eq!(
+            child_json(test, "flipped"),
+            (Some(0), expected(&[0x00, 0x3c, 0x00, 0xc1]))
+        );
+        assert_eq!(child_output(test, "wrapped").0, Some(4));
+    }
+
+    /// 622: every reader places the data at general.alignment, and one
+    /// that can't be used is a format error rather than a guess of 32.
+    #[test]
+    fn non_default_alignment() {
+        let model = |alignment: GgufValue| {
+            let a: Vec<u8> = f32_bytes(&[1.5; 3]);
+            let b: Vec<u8> = f32_bytes(&[-4.0, 0.25]);
+            build_test_gguf(
+                3,
+                &[("general.alignment", alignment)],
+                &[("a", &[3], 0, &a), ("b", &[2], 0, &b)],
+            )
+        };
+        if let Some(case) = child_case() {
+            let (alignment, command) = case.split_once(' ').unwrap();
+            let alignment = match alignment {
+                "64" => GgufValue::Uint32(64),
+                "0" => GgufValue::Uint32(0),
+                _ => GgufValue::Uint64(64),
+            };
+            let path = write_temp("aligned.gguf", &model(alignment));
+            match command {
+                "show" => gguf_show(&path, "b", 5, 0, false, false, false),
+                "stats" => gguf_show(&path, "b", 5, 0, false, false, true),
+                "hexdump" => gguf_hexdump(&path, "b", 8),
+                "checksum" => gguf_checksum(&path, ChecksumAlgo::Ternary, OutputFormat::Text),
+                "compare-data" => gguf_compare_data(&path, &path, "b"),
+                "export-header" => gguf_export_header(&path, None),
+                _ => {}
+            }
+            std::process::exit(0);
+        }
+        // With 64 the data starts later and b sits at 64, where 32 would
+        // find zero padding
+        let model_64 = model(GgufValue::Uint32(64));
+        assert_eq!(model_64.len() % 64, 8);
+        assert_eq!(load_values(&model_64).2[1], [-4.0, 0.25]);
+        let test = "tests::non_default_alignment";
+        let (code, out) = child_output(test, "64 show");
+        assert_eq!(code, Some(0));
+        assert!(out.contains(" [0] -4.000000\n [1] 0.250000\n"), "{}", out);
+        let (code, out) = child_output(test, "64 compare-data");
+        assert_eq!(code, Some(0));
+        assert!(out.contains("Max abs   : 0"), "{}", out);
+        let commands = [
+            "show",
+            "stats",
+            "hexdump",
+            "checksum",
+            "compare-data",
+            "export-header",
+        ];
+        for bad in ["0", "uint64"] {
+            for command in commands {
+                let case = format!("{} {}", bad, command);
+                assert_eq!(child_output(test, &case).0, Some(4), "{}", case);
+            }
+        }
+    }
+
+    /// The command line as clap reads it, after the program name.
+    fn parse_cli(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("ternary-tools").chain(args.iter().copied()))
+    }
+
+    /// 595: glyphs only come from `--glyphs=...`, so a bare flag leaves the
+    /// next token as the value.
+    #[test]
+    fn cli_glyphs_need_equals() {
+        let glyphs = |args: &[&str]| match parse_cli(args).ok().map(|cli| cli.command) {
+            Some(Commands::Convert {
+                value,
+                glyphs,
+                from_glyphs,
+                ..
+            }) => (value, glyphs, from_glyphs),
+            _ => panic!("{:?} is not a convert", args),
+        };
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(
+            glyphs(&["convert", "--glyphs", "5"]),
+            (some("5"), some("10T"), None)
+        );
+        assert_eq!(
+            glyphs(&["convert", "--glyphs", "-5"]),
+            (some("-5"), some("10T"), None)
+        );
+        assert_eq!(
+            glyphs(&["convert", "--glyphs=+0-", "5"]),
+            (some("5"), some("+0-"), None)
+        );
+        assert_eq!(
+            glyphs(&["convert", "--from-glyphs", "1TT"]),
+            (some("1TT"), None, some("10T"))
+        );
+        assert_eq!(
+            glyphs(&["convert", "--from-glyphs=+0-", "+--"]),
+            (some("+--"), None, some("+0-"))
+        );
+    }
+
+    #[test]
+    fn custom_glyphs_round_trip() {
+        let glyphs = parse_glyphs("+ 0 -").unwrap();
+        assert_eq!(glyphs, ['+', '0', '-']);
+        assert_eq!(to_glyphs(5, glyphs), "+--");
+        assert_eq!(to_glyphs(-5, glyphs), "-++");
+        assert_eq!(to_glyphs(0, glyphs), "0");
+        assert_eq!(from_glyphs("00+--", glyphs), Ok(5));
+        for n in -400..=400 {
+            assert_eq!(from_glyphs(&to_glyphs(n, glyphs), glyphs), Ok(n));
+        }
+        assert!(from_glyphs("+T", glyphs).is_err());
+        assert!(parse_glyphs("++-").is_err());
+        assert!(parse_glyphs("+0").is_err());
+
+        if let Some(case) = child_case() {
+            match case.as_str() {
+                "to" => run_glyphs("5", 10, 3, None, Some("+0-")),
+                "from" => run_glyphs("-++", 10, 10, Some("+0-"), None),
+                _ => run_glyphs("+--", 10, 3, Some("+0-"), Some("10T")),
+            }
+            std::process::exit(0);
+        }
+        let test = "tests::custom_glyphs_round_trip";
+        assert_eq!(child_output(test, "to"), (Some(0), "+--\n".to_string()));
+        assert_eq!(child_output(test, "from"), (Some(0), "-5\n".to_string()));
+        assert_eq!(
+            child_output(test, "translate"),
+            (Some(0), "1TT\n".to_string())
+        );
+    }
+
+    /// 619: help is rendered by clap and exits 0, and names what the user
+    /// can type rather than identifiers in this file.
+    #[test]
+    fn cli_help_lists_the_operations() {
+        use clap::CommandFactory;
+        let help = |args: &[&str]| match parse_cli(args) {
+            Err(e) => {
+                assert_eq!(e.kind(), clap::error::ErrorKind::DisplayHelp, "{:?}", args);
+                assert_eq!(e.exit_code(), 0);
+                e.to_string()
+            }
+            Ok(_) => panic!("{:?} did not print help", args),
+        };
+        let gguf = help(&["gguf", "--help"]);
+        let ops = [
+            "summary",
+            "info",
+            "show",
+            "hexdump",
+            "validate",
+            "checksum",
+            "tensors",
+            "types",
+            "vocab",
+            "diff",
+            "export-header",
+            "compare-data",
+            "set",
+            "merge",
+            "rename",
+            "reorder",
+            "strip",
+            "align",
+        ];
+        for op in ops {
+            assert!(
+                gguf.contains(&format!("  {} ", op)),
+                "{} missing from\n{}",
+                op,
+                gguf
+            );
+        }
+        assert!(Cli::command().render_help().to_string().contains("  calc "));
+        let pages = [
+            help(&["calc", "--help"]),
+            help(&["convert", "--help"]),
+            help(&["tfloat", "--help"]),
+            help(&["gguf", "validate", "--help"]),
+        ];
+        for page in pages {
+            for ident in [
+                "Calc for",
+                "CALC_CONSTANTS",
+                "TernarySci",
+                "ContinuedFraction",
+            ] {
+                assert!(!page.contains(ident), "{} in\n{}", ident, page);
+            }
+            assert!(!page.contains("TFloat") && !page.contains("REQUIRED_ARCH_KEYS"));
+        }
+    }
+
+    /// 614: aliases and unique prefixes pick their command; a prefix that
+    /// fits several is refused rather than guessed.
+    #[test]
+    fn cli_aliases_and_prefixes() {
+        let command = |args: &[&str]| parse_cli(args).ok().map(|cli| cli.command);
+        assert!(matches!(
+            command(&["c", "1"]),
+            Some(Commands::Calc { expr: Some(e), .. }) if e == "1"
+        ));
+        assert!(matches!(
+            command(&["conv", "5"]),
+            Some(Commands::Convert { value: Some(v), .. }) if v == "5"
+        ));
+        assert!(matches!(
+            command(&["g", "ren", "a", "b", "in.gguf", "out.gguf"]),
+            Some(Commands::Gguf {
+                op: GgufOp::Rename { old, new, .. },
+                ..
+            }) if old == "a" && new == "b"
+        ));
+        // summary, show, set and strip all start with `s`
+        match parse_cli(&["gguf", "s", "f"]) {
+            Err(e) => assert_eq!(e.kind(), clap::error::ErrorKind::InvalidSubcommand),
+            Ok(_) => panic!("an ambiguous prefix was accepted"),
+        }
+    }
+
+    /// 543: each tritwise operator against balanced trits worked by hand;
+    /// the literals are base 3, the results decimal.
+    #[test]
+    fn tritwise_operators() {
+        // 10 = +0 and 1 = 0+: the smaller of each pair is 00
+        assert_eq!(calc("10 & 1"), Ok(0));
+        // 11 = ++ and 2 = +-: the larger is ++, 11 again
+        assert_eq!(calc("11 | 2"), Ok(4));
+        assert_eq!(calc("~-10"), Ok(3));
+        assert_eq!(calc("~0"), Ok(0));
+        // ++ ^ +-: equal + gives -, + against - gives +, so -+
+        assert_eq!(calc("11 ^ 2"), Ok(-2));
+        assert_eq!(calc("1 ^ -1"), Ok(1));
+        assert_eq!(calc("-1 ^ -1"), Ok(-1));
+        assert_eq!(calc("0 ^ 12"), Ok(0));
+        for (a, b) in [(1, 1), (1, -1), (1, 0), (-1, 0)] {
+            let (ta, tb) = (Trit::try_from(a).unwrap(), Trit::try_from(b).unwrap());
+            assert_eq!(ta.xor(tb), tb.xor(ta));
+        }
+
+        // & before ^ before |, all after + and -
+        assert_eq!(calc("1 | 1 & -1"), Ok(1));
+        assert_eq!(calc("(1 | 1) & -1"), Ok(-1));
+        assert_eq!(calc("1 | 1 ^ 1"), Ok(1));
+        assert_eq!(calc("(1 | 1) ^ 1"), Ok(-1));
+        assert_eq!(calc("1 ^ 1 & 0"), Ok(0));
+        assert_eq!(calc("(1 ^ 1) & 0"), Ok(-1));
+        assert_eq!(calc("1 & 1 + 1"), Ok(-1));
+        assert_eq!(calc("(1 & 1) + 1"), Ok(2));
+        assert_eq!(calc("1 ^"), Err(ParseError::TrailingOperator('^')));
+
+        // Under --mod the operands are residues: 2222 is 80 = +000-
+        let eval = |s: &str| {
+            let (_, expr) = parse_statement(s).unwrap();
+            with_calc_settings(|| {}, || eval_mod(&expr, &HashMap::new(), 81))
+        };
+        assert_eq!(eval("2222 & 1"), Ok(80));
+        assert_eq!(eval("11 | 2"), Ok(4));
+        assert_eq!(eval("1 ^ 1"), Ok(80));
+        assert_eq!(eval("~1"), Ok(80));
+    }
+
+    /// 583
+    #[test]
+    fn info_only_extracts_one_field() {
+        if let Some(case) = child_case() {
+            let model = build_test_gguf(
+                3,
+                &[
+                    ("general.name", string("tiny")),
+                    ("llama.block_count", GgufValue::Uint32(5)),
+                ],
+                &[
+                    ("a", &[1], 0, &f32_bytes(&[1.0])),
+                    ("b", &[1], 0, &f32_bytes(&[2.0])),
+                ],
+            );
+            let path = write_temp("only.gguf", &model);
+            let (field, ternary) = match case.strip_suffix(" --ternary") {
+                Some(field) => (field, true),
+                None => (case.as_str(), false),
+            };
+            gguf_info(&path, ternary, Some(16), Some(field), false);
+            std::process::exit(0);
+        }
+        let test = "tests::info_only_extracts_one_field";
+        let only = |case: &str| child_output(test, case);
+        assert_eq!(only("n_tensors"), (Some(0), "2\n".to_string()));
+        assert_eq!(only("n_tensors --ternary"), (Some(0), "+-\n".to_string()));
+        assert_eq!(only("n_kv"), (Some(0), "2\n".to_string()));
+        assert_eq!(only("magic"), (Some(0), "GGUF\n".to_string()));
+        assert_eq!(only("meta:general.name"), (Some(0), "tiny\n".to_string()));
+        assert_eq!(only("meta:llama.block_count"), (Some(0), "5\n".to_string()));
+        assert_eq!(
+            only("meta:llama.block_count --ternary"),
+            (Some(0), "+--\n".to_string())
+        );
+        assert_eq!(only("n_layers"), (Some(2), String::new()));
+        assert_eq!(only("meta:general.author"), (Some(2), String::new()));
+    }
+
+    /// 583: `--only-ternary` is `--only` with `--ternary` switched on.
+    #[test]
+    fn cli_only_ternary() {
+        let info = |args: &[&str]| match parse_cli(args).ok().map(|cli| cli.command) {
+            Some(Commands::Gguf {
+                op: GgufOp::Info {
+                    only, only_ternary, ..
+                },
+                ..
+            }) => (only, only_ternary),
+            _ => panic!("{:?} is not an info", args),
+        };
+        let field = Some("n_tensors".to_string());
+        assert_eq!(
+            info(&["gguf", "info", "m.gguf", "--only-ternary", "n_tensors"]),
+            (None, field)
+        );
+        let both = [
+            "gguf",
+            "info",
+            "m",
+            "--only",
+            "n_kv",
+            "--only-ternary",
+            "n_kv",
+        ];
+        assert!(parse_cli(&both).is_err());
+    }
+
+    /// 565: a pipe parses like a file as long as every jump is forward.
+    #[test]
+    fn forward_only_stream() {
+        let model = tiny_model();
+        let (kvs, tensors, values) = load_values(&model);
+        let mut f = ForwardOnly {
+            inner: Cursor::new(&model[..]),
+            pos: 0,
+        };
+        let header = parse_header(&mut f).unwrap();
+        assert_eq!((header.n_tensors, header.n_metadata_kv), (2, 3));
+        let streamed_kvs = parse_metadata_kvs(&mut f, &header).unwrap();
+        let keys = |kvs: &[GgufKv]| kvs.iter().map(|kv| kv.key.clone()).collect::<Vec<_>>();
+        assert_eq!(keys(&streamed_kvs), keys(&kvs));
+        let streamed = parse_tensors(&mut f, &header).unwrap();
+        let layout = |ts: &[GgufTensorInfo]| {
+            ts.iter()
+                .map(|t| (t.name.clone(), t.dims.clone(), t.kind, t.offset))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(layout(&streamed), layout(&tensors));
+
+        // Tensor data in file order, past the padding before each
+        let data_start = align_up(f.stream_position().unwrap(), GGUF_DEFAULT_ALIGNMENT);
+        for (t, expected) in streamed.iter().zip(&values) {
+            let read = tensor_values(&mut f, data_start, t).unwrap();
+            assert_eq!(
+                &read.collect::<Result<Vec<f64>, String>>().unwrap(),
+                expected
+            );
+        }
+
+        // Back to the first tensor is the one jump a pipe can't make
+        let e = f.seek(SeekFrom::Start(data_start)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(e.to_string(), "cannot stream-seek backward");
+        assert!(f.seek(SeekFrom::Current(-1)).is_err());
+        let end = f.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(end, model.len() as u64);
+    }
+
+    /// 609: a file cut inside a tensor info keeps the infos before it.
+    #[test]
+    fn lenient_tensors_stop_at_the_cut() {
+        let data = f32_bytes(&[1.0, 2.0]);
+        let names = ["first.weight", "second.weight", "third.weight"];
+        let tensors: Vec<(&str, &[u64], u32, &[u8])> = names
+            .iter()
+            .map(|&name| (name, &[2][..], 0, &data[..]))
+            .collect();
+        let model = build_test_gguf(3, &[("general.name", string("cut"))], &tensors);
+        let mut f = Cursor::new(&model[..]);
+        let header = parse_header(&mut f).unwrap();
+        parse_metadata_kvs(&mut f, &header).unwrap();
+        parse_tensor_info(&mut f, header.version).unwrap();
+        parse_tensor_info(&mut f, header.version).unwrap();
+        // Past the third name's length and into the name itself
+        let cut = f.position() as usize + 8 + 3;
+
+        let mut f = Cursor::new(&model[..cut]);
+        let header = parse_header(&mut f).unwrap();
+        parse_metadata_kvs(&mut f, &header).unwrap();
+        let (tensors, error) = parse_tensors_lenient(&mut f, &header);
+        let read: Vec<&str> = tensors.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(read, names[..2]);
+        assert_eq!(tensors[1].offset, 32);
+        match error {
+            Some(GgufError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            e => panic!("expected a cut-off read, got {:?}", e),
+        }
+    }
+
+    /// 557: a long array shows its first `--max-values` entries and the
+    /// count, and all of them under `--full` or in JSON.
+    #[test]
+    fn long_arrays_are_truncated() {
+        let tokens: Vec<String> = (0..20).map(|i| format!("t{}", i)).collect();
+        let line = |n: usize, total: bool| {
+            let mut shown = tokens[..n].join(", ");
+            if total {
+                shown.push_str(", ... (20 total)");
+            }
+            format!(
+                "{:<40} = [{}] (ARRAY[STRING])\n",
+                "tokenizer.ggml.tokens", shown
+            )
+        };
+        if let Some(case) = child_case() {
+            let array = GgufValue::Array(tokens.iter().map(|t| string(t)).collect());
+            let model = build_test_gguf(3, &[("tokenizer.ggml.tokens", array)], &[]);
+            let path = write_temp("long-array.gguf", &model);
+            match case.as_str() {
+                "default" => gguf_info(&path, false, Some(16), None, false),
+                "max-values" => gguf_info(&path, false, Some(3), None, false),
+                "full" => gguf_info(&path, false, None, None, false),
+                _ => gguf_export_header(&path, None),
+            }
+            std::process::exit(0);
+        }
+        let test = "tests::long_arrays_are_truncated";
+        let (code, out) = child_output(test, "default");
+        assert_eq!(code, Some(0));
+        assert!(out.contains(&line(16, true)), "{}", out);
+        assert!(!out.contains("t16"), "{}", out);
+        assert!(child_output(test, "max-values").1.contains(&line(3, true)));
+        assert!(child_output(test, "full").1.contains(&line(20, false)));
+
+        let (code, json) = child_json(test, "json");
+        assert_eq!(code, Some(0));
+        let full = line(20, false);
+        let full = &full[full.find('[').unwrap()..full.rfind(" (").unwrap()];
+        let expected = json_object! { "tokenizer.ggml.tokens": full };
+        assert_eq!(json, json_object! { "__metadata__": expected });
+    }
+
+    /// 579: a seed gives the same lines every run, padded to `--digits`.
+    #[test]
+    fn random_is_reproducible() {
+        if let Some(case) = child_case() {
+            match case.as_str() {
+                "seed 7" => run_random(8, 20, Some(7), false, None),
+                "seed 8" => run_random(8, 20, Some(8), false, None),
+                _ => run_random(5, 50, Some(7), true, Some(4)),
+            }
+            std::process::exit(0);
+        }
+        let test = "tests::random_is_reproducible";
+        let (code, first) = child_output(test, "seed 7");
+        assert_eq!(code, Some(0));
+        assert_eq!(child_output(test, "seed 7").1, first);
+        assert_ne!(child_output(test, "seed 8").1, first);
+        let lines: Vec<&str> = first.lines().collect();
+        assert_eq!(lines.len(), 20);
+        assert!(lines
+            .iter()
+            .all(|l| l.len() == 8 && l.bytes().all(|b| (b'0'..=b'2').contains(&b))));
+
+        let (_, balanced) = child_output(test, "balanced");
+        assert_eq!(child_output(test, "balanced").1, balanced);
+        for line in balanced.lines() {
+            assert_eq!(line.len(), 5, "{}", line);
+            let n = from_glyphs(line, ['+', '0', '-']).unwrap();
+            assert!((-4..=4).contains(&n), "{} is {}", line, n);
+        }
+    }
+
+    /// 584
+    #[test]
+    fn hamming_distance() {
+        assert_eq!(ternary_hamming("1201", "1201"), Ok(0));
+        assert_eq!(ternary_hamming("1201", "1021"), Ok(2));
+        assert_eq!(ternary_hamming("2222", "0000"), Ok(4));
+        assert_eq!(ternary_mismatches("1201", "1021"), Ok(vec![1, 2]));
+        // The shorter side gets leading zeros, which change nothing
+        assert_eq!(ternary_hamming("12", "0012"), Ok(0));
+        assert_eq!(ternary_hamming("12", "2012"), Ok(1));
+        assert_eq!(ternary_mismatches("1", "210"), Ok(vec![0, 1, 2]));
+        assert_eq!(ternary_hamming("", "11"), Ok(2));
+        assert_eq!(
+            ternary_hamming("1231", "1201"),
+            Err(ParseError::InvalidDigit('3', 3))
+        );
+        assert_eq!(
+            ternary_hamming("12", "-12"),
+            Err(ParseError::InvalidDigit('-', 3))
+        );
+    }
+
+    /// 589
+    #[test]
+    fn canonical_ternary() {
+        for s in ["0", "1", "2", "10", "-1", "-1202", "2220"] {
+            assert_eq!(is_canonical_ternary(s, false), Ok(()), "{}", s);
+        }
+        assert_eq!(
+            is_canonical_ternary("0012", false),
+            Err("leading zero".to_string())
+        );
+        assert_eq!(
+            is_canonical_ternary("-012", false),
+            Err("leading zero".to_string())
+        );
+        assert_eq!(
+            is_canonical_ternary("00", false),
+            Err("leading zero".to_string())
+        );
+        assert_eq!(is_canonical_ternary("0012", true), Ok(()));
+        assert_eq!(is_canonical_ternary("00", true), Ok(()));
+        let digit = |c: char| Err(format!("'{}' is not a ternary digit (0, 1, 2)", c));
+        assert_eq!(is_canonical_ternary("1231", false), digit('3'));
+        assert_eq!(is_canonical_ternary("12 ", true), digit(' '));
+        assert_eq!(is_canonical_ternary("--1", false), digit('-'));
+        assert_eq!(
+            is_canonical_ternary("", false),
+            Err("no digits".to_string())
+        );
+        assert_eq!(
+            is_canonical_ternary("-", false),
+            Err("no digits".to_string())
+        );
+        assert_eq!(
+            is_canonical_ternary("-0", false),
+            Err("negative zero".to_string())
+        );
+        assert_eq!(
+            is_canonical_ternary("-00", true),
+            Err("negative zero".to_string())
+        );
+    }
+
+    /// 606
+    #[test]
+    fn vocab_lists_tokens() {
+        if let Some(case) = child_case() {
+            let tokens = ["<s>", "a", "b\n", "cc", "dd"];
+            let array = GgufValue::Array(tokens.iter().map(|&t| string(t)).collect());
+            let kvs = match case.as_str() {
+                "no tokens" => vec![("general.name", string("none"))],
+                "not an array" => vec![("tokenizer.ggml.tokens", string("a"))],
+                _ => vec![("tokenizer.ggml.tokens", array)],
+            };
+            let path = write_temp("vocab.gguf", &build_test_gguf(3, &kvs, &[]));
+            match case.as_str() {
+                "all" => gguf_vocab(&path, None),
+                "window" => gguf_vocab(&path, Some("1..3")),
+                "tail" => gguf_vocab(&path, Some("3..99")),
+                "past" => gguf_vocab(&path, Some("9..")),
+                _ => gguf_vocab(&path, None),
+            }
+            std::process::exit(0);
+        }
+        let test = "tests::vocab_lists_tokens";
+        let vocab = |case: &str| child_output(test, case);
+        let all = "0 (0)   <s>\n1 (+)   a\n2 (+-)  b\\n\n3 (+0)  cc\n4 (++)  dd\n";
+        assert_eq!(vocab("all"), (Some(0), all.to_string()));
+        let window = "1 (+)   a\n2 (+-)  b\\n\n";
+        assert_eq!(vocab("window"), (Some(0), window.to_string()));
+        assert_eq!(
+            vocab("tail"),
+            (Some(0), "3 (+0)  cc\n4 (++)  dd\n".to_string())
+        );
+        assert_eq!(vocab("past").0, Some(2));
+        assert_eq!(vocab("no tokens"), (Some(2), String::new()));
+        let stderr = run_in_child(test, "no tokens").stderr;
+        let stderr = String::from_utf8_lossy(&stderr);
+        assert!(
+            stderr.contains("no tokenizer.ggml.tokens metadata"),
+            "{}",
+            stderr
+        );
+        assert_eq!(vocab("not an array").0, Some(4));
+    }
+
+    /// 608: `--require-arch` names the one key a llama model is missing.
+    #[test]
+    fn require_arch_names_the_missing_key() {
+        if let Some(case) = child_case() {
+            let mut kvs = vec![
+                ("general.architecture", string("llama")),
+                ("llama.context_length", GgufValue::Uint32(4096)),
+                ("llama.embedding_length", GgufValue::Uint32(64)),
+                ("llama.feed_forward_length", GgufValue::Uint32(256)),
+                ("llama.block_count", GgufValue::Uint32(2)),
+                ("llama.attention.head_count", GgufValue::Uint32(4)),
+            ];
+            if case == "missing" {
+                kvs.retain(|(key, _)| *key != "llama.feed_forward_length");
+            }
+            let path = write_temp("require-arch.gguf", &build_test_gguf(3, &kvs, &[]));
+            gguf_validate(&path, false, true, &[]);
+            std::process::exit(0);
+        }
+        let test = "tests::require_arch_names_the_missing_key";
+        let complete = run_in_child(test, "complete");
+        assert_eq!(complete.status.code(), Some(0));
+        assert!(complete.stderr.is_empty());
+
+        let missing = run_in_child(test, "missing");
+        assert_eq!(missing.status.code(), Some(1));
+        let stderr = String::from_utf8_lossy(&missing.stderr);
+        assert!(stderr.contains("llama.feed_forward_length"), "{}", stderr);
+        assert!(
+            stderr.contains("1 required metadata keys missing"),
+            "{}",
+            stderr
+        );
+        for present in [
+            "context_length",
+            "embedding_length",
+            "block_count",
+            "head_count",
+        ] {
+            assert!(
+                !stderr.contains(present),
+                "{} reported in\n{}",
+                present,
+                stderr
+            );
+        }
+        assert!(!String::from_utf8_lossy(&missing.stdout).contains("validated successfully"));
+    }
+
+    /// 611
+    #[test]
+    fn closest_tensor_names() {
+        let names = [
+            "token_embd.weight",
+            "blk.0.attn_q.weight",
+            "blk.0.attn_k.weight",
+            "blk.1.attn_q.weight",
+            "output.weight",
+        ];
+        let closest = |wanted: &str| closest_names(wanted, names.iter().copied());
+        assert_eq!(closest("blk.0.attn_q"), ["blk.0.attn_q.weight"]);
+        // One edit away first, then the two-edit ones by name
+        assert_eq!(
+            closest("blk.0.atn_q.weight"),
+            [
+                "blk.0.attn_q.weight",
+                "blk.0.attn_k.weight",
+                "blk.1.attn_q.weight"
+            ]
+        );
+        assert_eq!(closest("output.weights"), ["output.weight"]);
+        assert!(closest("lm_head").is_empty());
+
+        let tensors: Vec<GgufTensorInfo> = names
+            .iter()
+            .map(|name| GgufTensorInfo {
+                name: name.to_string(),
+                dims: vec![1],
+                kind: 0,
+                offset: 0,
+            })
+            .collect();
+        assert_eq!(
+            did_you_mean("blk.0.attn_q", &tensors),
+            " (closest: blk.0.attn_q.weight)"
+        );
+        assert_eq!(did_you_mean("lm_head", &tensors), "");
+    }
+
+    /// 552: `$` is the previous line's result and `$n` the n-th.
+    #[test]
+    fn earlier_results() {
+        let lines = |input: &str| with_calc_settings(|| {}, || eval_lines(input, DivMode::Trunc));
+        assert_eq!(lines("12\n$ * 2"), Ok(vec![5, 10]));
+        // Within a line `$` is still the line before's
+        assert_eq!(lines("12\n2\n$1 - $2; $ + $1"), Ok(vec![5, 2, 7]));
+        assert_eq!(lines("1\n$ + $\n$ + $"), Ok(vec![1, 2, 4]));
+        let missing = lines("1\n2\n$3").unwrap_err();
+        assert_eq!(missing, ParseError::UndefinedVariable("$3".to_string()));
+        assert_eq!(missing.to_string(), "no result '$3' in the history yet");
+        let first = ParseError::UndefinedVariable("$".to_string());
+        assert_eq!(lines("$ + 1"), Err(first));
+    }
+
+    /// 590: a file goes on past bad lines, printing what did evaluate, but
+    /// still fails; `--strict` stops at the first bad line.
+    #[test]
+    fn calc_file_with_bad_lines() {
+        if let Some(case) = child_case() {
+            let (mode, path) = case.split_once('\n').unwrap();
+            let format = CalcFormat {
+                decimal: false,
+                group: false,
+                both_ternary: false,
+                count_trits: false,
+            };
+            let strict = mode == "strict";
+            let input = Some(path.to_string());
+            run_calc(
+                None,
+                input,
+                format,
+                OutputFormat::Text,
+                DivMode::Trunc,
+                false,
+                strict,
+            );
+            std::process::exit(0);
+        }
+        // 12 = 5, then 2 * 2 = 4 and $2 + 1 = 5: `$2` counts good lines only
+        let path = write_temp("calc-lines.txt", b"12\n1 +\n2 * 2\n3\n$2 + 1\n");
+        let test = "tests::calc_file_with_bad_lines";
+        let tolerant = format!("tolerant\n{}", path);
+        assert_eq!(
+            child_output(test, &tolerant),
+            (Some(2), "12\n11\n12\n".to_string())
+        );
+        let stderr = run_in_child(test, &tolerant).stderr;
+        let stderr = String::from_utf8_lossy(&stderr);
+        assert!(stderr.contains("line 2: calc: operator '+'"), "{}", stderr);
+        assert!(
+            stderr.contains("line 4: calc: '3' is not a ternary digit"),
+            "{}",
+            stderr
+        );
+
+        let strict = format!("strict\n{}", path);
+        assert_eq!(child_output(test, &strict), (Some(2), String::new()));
+        let stderr = run_in_child(test, &strict).stderr;
+        let stderr = String::from_utf8_lossy(&stderr);
+        assert!(stderr.contains("calc: operator '+'"), "{}", stderr);
+        assert!(!stderr.contains("'3'"), "{}", stderr);
+    }
+
+    /// 576: changing one value changes that key's checksum and the root,
+    /// and nothing else in the tree.
+    #[test]
+    fn checksum_tree_follows_one_change() {
+        if let Some(case) = child_case() {
+            let kvs = [
+                ("general.architecture", string("llama")),
+                ("general.name", string("tree")),
+                (
+                    "llama.block_count",
+                    GgufValue::Uint32(case.parse().unwrap()),
+                ),
+            ];
+            let tensors = [("a.weight", &[2][..], 0, &f32_bytes(&[1.0, 2.0])[..])];
+            let path = write_temp("tree.gguf", &build_test_gguf(3, &kvs, &tensors));
+            gguf_validate(&path, true, false, &[]);
+            std::process::exit(0);
+        }
+        let tree = |blocks: &str| {
+            let (code, out) = child_output("tests::checksum_tree_follows_one_change", blocks);
+            assert_eq!(code, Some(0));
+            out.lines()
+                .filter_map(|line| line.strip_prefix("  ")?.split_once(" : "))
+                .map(|(key, digest)| (key.trim_end().to_string(), digest.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let (two, three) = (tree("2"), tree("3"));
+        let keys: Vec<&str> = two.iter().map(|(k, _)| k.as_str()).collect();
+        let expected = [
+            "general.architecture",
+            "general.name",
+            "llama.block_count",
+            "root",
+        ];
+        assert_eq!(keys, expected);
+        let changed: Vec<&str> = two
+            .iter()
+            .zip(&three)
+            .filter(|((_, a), (_, b))| a != b)
+            .map(|((k, _), _)| k.as_str())
+            .collect();
+        assert_eq!(changed, ["llama.block_count", "root"]);
+        assert_eq!(tree("2"), two);
+    }
+
+    /// 526: the diff names each added, removed and changed key and each
+    /// tensor that appeared, went or moved.
+    #[test]
+    fn diff_reports_every_change() {
+        if let Some(case) = child_case() {
+            let data = f32_bytes(&[1.0, 2.0]);
+            let a = build_test_gguf(
+                3,
+                &[
+                    ("general.architecture", string("llama")),
+                    ("general.name", string("old")),
+                    ("llama.block_count", GgufValue::Uint32(2)),
+                ],
+                &[
+                    ("a.weight", &[2], 0, &data),
+                    ("b.weight", &[2], 0, &data),
+                    ("d.weight", &[2], 0, &data),
+                ],
+            );
+            // c.weight goes in ahead of b.weight, which moves up to 64
+            let b = build_test_gguf(
+                3,
+                &[
+                    ("general.architecture", string("llama")),
+                    ("general.author", string("me")),
+                    ("llama.block_count", GgufValue::Uint32(3)),
+                ],
+                &[
+                    ("a.weight", &[2], 0, &data),
+                    ("c.weight", &[2], 1, &data),
+                    ("b.weight", &[2], 0, &data),
+                ],
+            );
+            let (a, b) = (write_temp("diff-a.gguf", &a), write_temp("diff-b.gguf", &b));
+            let format = match case.as_str() {
+                "json" => OutputFormat::Json,
+                _ => OutputFormat::Text,
+            };
+            gguf_diff(&a, &b, format);
+            std::process::exit(0);
+        }
+        let test = "tests::diff_reports_every_change";
+        let (code, text) = child_output(test, "text");
+        assert_eq!(code, Some(0));
+        let lines: Vec<&str> = text.lines().skip(2).map(str::trim_end).collect();
+        let pad = |s: &str, width: usize| format!("{:<width$}", s, width = width);
+        assert_eq!(
+            lines,
+            [
+                "METADATA (3 differences)".to_string(),
+                format!("+ {} = me", pad("general.author", 40)),
+                format!("- {} = old", pad("general.name", 40)),
+                format!("~ {} = Uint32(2) → Uint32(3)", pad("llama.block_count", 40)),
+                "TENSORS (3 differences)".to_string(),
+                format!("~ {} offset 32 → 64", pad("b.weight", 48)),
+                format!(
+                    "+ {} {} {} offset=32",
+                    pad("c.weight", 48),
+                    pad("2", 20),
+                    pad("F16 (1)", 14)
+                ),
+                format!(
+                    "- {} {} {} offset=64",
+                    pad("d.weight", 48),
+                    pad("2", 20),
+                    pad("F32 (0)", 14)
+                ),
+            ]
+        );
+
+        let (code, json) = child_json(test, "json");
+        assert_eq!(code, Some(0));
+        let Json::Object(fields) = &json else {
+            panic!("not an object: {:?}", json);
+        };
+        let key =
+            |k: &str, a: Option<&str>, b: Option<&str>| json_object! { "key": k, "a": a, "b": b };
+        let metadata = Json::Array(vec![
+            key("general.author", None, Some("me")),
+            key("general.name", Some("old"), None),
+            key("llama.block_count", Some("Uint32(2)"), Some("Uint32(3)")),
+        ]);
+        assert_eq!(fields[0], ("metadata".to_string(), metadata));
+        let tensor = |kind: u32, offset: u64| {
+            Some(json_object! {
+                "shape": Json::Array(vec![2u64.into()]),
+                "type_id": kind,
+                "offset": offset,
+            })
+        };
+        let change = |name: &str, a: Option<Json>, b: Option<Json>| {
+            json_object! { "name": name, "a": a, "b": b }
+        };
+        let tensors = Json::Array(vec![
+            change("b.weight", tensor(0, 32), tensor(0, 64)),
+            change("c.weight", None, tensor(1, 32)),
+            change("d.weight", tensor(0, 64), None),
+        ]);
+        assert_eq!(fields[1], ("tensors".to_string(), tensors));
+    }
+
+    /// 559: a template sets typed keys, new and existing, and the tensors
+    /// come through untouched.
+    #[test]
+    fn merge_template_round_trip() {
+        let template = write_temp(
+            "merge.json",
+            br#"[
+                {"key": "llama.context_length", "type": "uint32", "value": 4096},
+                {"key": "general.name", "value": "merged"},
+                {"key": "llama.block_count", "value": "5"},
+                {"key": "llama.rope.freq_base", "type": "FLOAT32", "value": 10000.5},
+                {"key": "general.quantized", "type": "bool", "value": true},
+                {"key": "llama.offset", "type": "int64", "value": -3}
+            ]"#,
+        );
+        let (kvs, tensors, values) = rewritten("merge", |input, output| {
+            gguf_merge(&template, input, output, false)
+        });
+        assert!(matches!(
+            kv(&kvs, "llama.context_length"),
+            Some(GgufValue::Uint32(4096))
+        ));
+        assert!(matches!(kv(&kvs, "general.name"), Some(GgufValue::String(s)) if s == "merged"));
+        // An existing key keeps its type when the template gives none
+        assert!(matches!(
+            kv(&kvs, "llama.block_count"),
+            Some(GgufValue::Uint32(5))
+        ));
+        assert!(
+            matches!(kv(&kvs, "llama.rope.freq_base"), Some(&GgufValue::Float32(x)) if x == 10000.5)
+        );
+        assert!(matches!(
+            kv(&kvs, "general.quantized"),
+            Some(GgufValue::Bool(true))
+        ));
+        assert!(matches!(
+            kv(&kvs, "llama.offset"),
+            Some(GgufValue::Int64(-3))
+        ));
+        assert!(
+            matches!(kv(&kvs, "general.architecture"), Some(GgufValue::String(s)) if s == "llama")
+        );
+        assert_eq!(kvs.len(), 3 + 5);
+        let names: Vec<&str> = tensors.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["a.weight", "b.weight"]);
+        assert_eq!(values, [&TINY_A[..], &TINY_B[..]]);
+    }
+
+    /// 569: `--dry-run` writes nothing and prints the size the real run
+    /// writes, with a line per key added, changed or removed.
+    #[test]
+    fn dry_run_writes_nothing() {
+        if let Some(case) = child_case() {
+            let args: Vec<&str> = case.lines().collect();
+            let (input, output, dry_run) = (args[1], args[2], args[3] == "dry");
+            match args[0] {
+                "merge" => gguf_merge(args[4], input, output, dry_run),
+                _ => {
+                    let drop = ["meta:tokenizer.ggml.tokens".to_string()];
+                    gguf_strip(input, output, &drop, false, dry_run)
+                }
+            }
+            std::process::exit(0);
+        }
+        let test = "tests::dry_run_writes_nothing";
+        let input = write_temp("dry-run-in.gguf", &tiny_model());
+        let template = write_temp(
+            "dry-run.json",
+            br#"[{"key": "general.name", "value": "tiny"}, {"key": "llama.block_count", "value": 3}]"#,
+        );
+        let run = |op: &str, output: &str, mode: &str| {
+            let case = [op, &input, output, mode, &template].join("\n");
+            let (code, out) = child_output(test, &case);
+            assert_eq!(code, Some(0), "{}", out);
+            out
+        };
+        for (op, changes) in [
+            (
+                "merge",
+                // A changed key stays where it was, a new one goes last
+                [
+                    "~ llama.block_count (UINT32) = 3",
+                    "+ general.name (STRING) = tiny",
+                ]
+                .as_slice(),
+            ),
+            ("strip", ["- tokenizer.ggml.tokens"].as_slice()),
+        ] {
+            let dry = temp_path(&format!("dry-run-{}.gguf", op));
+            let out = run(op, &dry, "dry");
+            assert!(!std::path::Path::new(&dry).exists(), "{} wrote {}", op, dry);
+            let real = temp_path(&format!("dry-run-{}-real.gguf", op));
+            run(op, &real, "real");
+            let size = std::fs::metadata(&real).unwrap().len();
+            std::fs::remove_file(&real).unwrap();
+
+            let mut expected = vec![format!(
+                "dry run: {} would be {} bytes, 2 tensors (0 moved)",
+                dry, size
+            )];
+            expected.extend(changes.iter().map(|line| format!("  {}", line)));
+            assert_eq!(out.lines().collect::<Vec<_>>(), expected, "{}", op);
+        }
+        let leftovers = std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| {
+                e.file_name()
+                    .to_string_lossy()
+                    .contains("dry-run-merge.gguf.partial")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    /// 593
+    #[test]
+    fn ternary_scientific_notation() {
+        let sci =
+            |text: &str, base: u32| TernarySci::parse(text).and_then(|n| n.to_base_string(base));
+        let ok = |s: &str| Ok(s.to_string());
+        // 1.2 (base 3) × 3^2 moves the point two trits right
+        assert_eq!(sci("1.2e2", 3), ok("120"));
+        assert_eq!(sci("1.2e2", 10), ok("15"));
+        assert_eq!(sci("0.12e3", 3), ok("120"));
+        assert_eq!(sci("12E+1", 3), ok("120"));
+        assert_eq!(sci("2e-1", 3), ok("0.2"));
+        assert_eq!(sci("1.2e-2", 3), ok("0.012"));
+        assert_eq!(sci("-2e-1", 10), ok("-0.6666666666666666"));
+        assert_eq!(sci("1.10e-1", 3), ok("0.11"));
+        assert_eq!(sci("1e12", 10), ok("531441"));
+        assert_eq!(sci("-0.0e5", 3), ok("0"));
+        assert_eq!(
+            sci("2e-1", 16),
+            Err("fractions print in base 3 or 10, not 16".to_string())
+        );
+        assert!(sci("3e1", 3)
+            .unwrap_err()
+            .contains("'3' is not a ternary digit"));
+        assert!(sci("1e", 3).is_err());
+        assert!(sci("1e1.5", 3).is_err());
+        assert!(sci(".e2", 3).is_err());
+        assert_eq!(
+            sci("1e4097", 3),
+            Err("exponent 4097 is past ±4096".to_string())
+        );
+        assert_eq!(selftest_ternary_sci(), Ok(()));
     }
 }